@@ -0,0 +1,79 @@
+// Latency of a SOCKS5 handshake up to the request, for each address type
+// the request can carry:
+// cargo bench --bench handshake
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use rusocks::{
+    error::SocksError,
+    socks5::{Socks5, Socks5Handler},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Handshakes timed for each address type
+const HANDSHAKES: u32 = 1_000;
+
+struct Handler;
+
+#[async_trait]
+impl Socks5Handler for Handler {
+    type Error = SocksError;
+}
+
+#[tokio::main]
+async fn main() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    let ipv4 = [0x01, 127, 0, 0, 1];
+    let mut ipv6 = vec![0x04];
+    ipv6.extend([0; 15]);
+    ipv6.push(1);
+    let mut domain = vec![0x03, 11];
+    domain.extend(b"example.com");
+
+    for (name, addr) in [("IPv4", &ipv4[..]), ("IPv6", &ipv6), ("domain", &domain)] {
+        let mut greeting_and_request = vec![0x05, 0x01, 0x00, 0x05, 0x01, 0x00];
+        greeting_and_request.extend(addr);
+        greeting_and_request.extend(80u16.to_be_bytes());
+
+        let elapsed = bench(&listener, &greeting_and_request).await;
+        println!("{name}: {:?} per handshake", elapsed / HANDSHAKES);
+    }
+}
+
+/// Total time `Socks5::negotiate_only` takes to read `HANDSHAKES`
+/// requests, each sent in one write along with the greeting
+async fn bench(listener: &TcpListener, greeting_and_request: &[u8]) -> Duration {
+    let mut elapsed = Duration::ZERO;
+    for _ in 0..HANDSHAKES {
+        let (mut client, mut server) = tcp_pair(listener).await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            Handler,
+        )
+        .with_strict(true);
+
+        client.write_all(greeting_and_request).await.unwrap();
+        let started = Instant::now();
+        socks5.negotiate_only(&mut server).await.unwrap();
+        elapsed += started.elapsed();
+
+        let mut reply = [0; 2];
+        client.read_exact(&mut reply).await.unwrap();
+    }
+
+    elapsed
+}
+
+async fn tcp_pair(listener: &TcpListener) -> (TcpStream, TcpStream) {
+    let (client, server) = tokio::join!(
+        TcpStream::connect(listener.local_addr().unwrap()),
+        listener.accept()
+    );
+
+    (client.unwrap(), server.unwrap().0)
+}
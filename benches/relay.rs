@@ -0,0 +1,97 @@
+// Throughput of the copy loop of `relay` against `relay_tcp`, which uses
+// splice(2) with the `splice` feature on Linux:
+// cargo bench --bench relay --features splice
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use rusocks::relay::{relay, relay_tcp, Transfer};
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Bytes sent through the relay on each run
+const SIZE: usize = 256 * 1024 * 1024;
+const RUNS: u32 = 5;
+
+#[tokio::main]
+async fn main() {
+    let copy = bench(|mut client, mut remote| async move {
+        relay(&mut client, &mut remote, true, None, None, None).await
+    })
+    .await;
+    report("copy loop", copy);
+
+    let tcp = bench(|mut client, mut remote| async move {
+        relay_tcp(&mut client, &mut remote, true, None, None, None).await
+    })
+    .await;
+    if cfg!(all(feature = "splice", target_os = "linux")) {
+        report("splice", tcp);
+    } else {
+        report("relay_tcp (no splice)", tcp);
+    }
+}
+
+/// Median time `relay_fn` takes to move `SIZE` bytes from a client to a
+/// remote over loopback
+async fn bench<F, Fut>(relay_fn: F) -> Duration
+where
+    F: Fn(TcpStream, TcpStream) -> Fut,
+    Fut: Future<Output = io::Result<Transfer>> + Send + 'static,
+{
+    let mut runs = Vec::new();
+    for _ in 0..RUNS {
+        let (mut client, client_end) = tcp_pair().await;
+        let (remote_end, mut remote) = tcp_pair().await;
+        let session = tokio::spawn(relay_fn(client_end, remote_end));
+
+        let started = Instant::now();
+        let upload = async {
+            let chunk = vec![0; 64 * 1024];
+            for _ in 0..SIZE / chunk.len() {
+                client.write_all(&chunk).await.unwrap();
+            }
+            client.shutdown().await.unwrap();
+        };
+        let download = async {
+            let mut buf = vec![0; 64 * 1024];
+            let mut received = 0;
+            loop {
+                match remote.read(&mut buf).await.unwrap() {
+                    0 => break received,
+                    size => received += size,
+                }
+            }
+        };
+        let ((), received) = tokio::join!(upload, download);
+        runs.push(started.elapsed());
+        assert_eq!(received, SIZE);
+
+        drop(remote);
+        session.await.unwrap().unwrap();
+    }
+
+    runs.sort();
+    runs[runs.len() / 2]
+}
+
+fn report(name: &str, elapsed: Duration) {
+    let throughput = SIZE as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0 * 1024.0);
+    println!(
+        "{name}: {elapsed:?} per {} MiB, {throughput:.2} GiB/s",
+        SIZE >> 20
+    );
+}
+
+async fn tcp_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let (client, server) = tokio::join!(
+        TcpStream::connect(listener.local_addr().unwrap()),
+        listener.accept()
+    );
+
+    (client.unwrap(), server.unwrap().0)
+}
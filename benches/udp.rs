@@ -0,0 +1,119 @@
+// Heap allocations the UDP relay makes per datagram, in each direction:
+// cargo bench --bench udp
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    net::SocketAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use async_trait::async_trait;
+use rusocks::{
+    error::SocksError,
+    socks5::{udp::Socks5UdpHeader, Socks5, Socks5Handler},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+};
+
+/// Datagrams relayed in each direction
+const DATAGRAMS: usize = 10_000;
+
+/// Counts every allocation the process makes
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+struct Handler;
+
+#[async_trait]
+impl Socks5Handler for Handler {
+    type Error = SocksError;
+
+    fn udp_enabled(&self) -> bool {
+        true
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let (client, server) = tokio::join!(
+        TcpStream::connect(listener.local_addr().unwrap()),
+        listener.accept()
+    );
+    let (mut client, (mut server, _)) = (client.unwrap(), server.unwrap());
+    let mut socks5 = Socks5::new(
+        server.peer_addr().unwrap(),
+        server.local_addr().unwrap(),
+        Handler,
+    )
+    .with_strict(true);
+    let session = tokio::spawn(async move { socks5.negotiate(&mut server).await });
+
+    let client_udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    let mut request = vec![0x05, 0x01, 0x00, 0x05, 0x03, 0x00, 0x01, 127, 0, 0, 1];
+    request.extend(client_udp.local_addr().unwrap().port().to_be_bytes());
+    client.write_all(&request).await.unwrap();
+    let mut reply = [0; 2 + 10];
+    client.read_exact(&mut reply).await.unwrap();
+    let relay_addr = SocketAddr::from(([127, 0, 0, 1], u16::from_be_bytes([reply[10], reply[11]])));
+
+    let datagram = Socks5UdpHeader::new(target.local_addr().unwrap().into())
+        .encode(&[0; 512])
+        .unwrap();
+    let mut buf = vec![0; 2048];
+
+    // The first datagram in each direction sets the association up
+    client_udp.send_to(&datagram, relay_addr).await.unwrap();
+    target.recv(&mut buf).await.unwrap();
+    target.send_to(&[0; 512], relay_addr).await.unwrap();
+    client_udp.recv(&mut buf).await.unwrap();
+
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..DATAGRAMS {
+        client_udp.send_to(&datagram, relay_addr).await.unwrap();
+        target.recv(&mut buf).await.unwrap();
+    }
+    let upload = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    report("client to destination", upload);
+
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..DATAGRAMS {
+        target.send_to(&[0; 512], relay_addr).await.unwrap();
+        client_udp.recv(&mut buf).await.unwrap();
+    }
+    let download = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    report("destination to client", download);
+
+    drop(client);
+    session.await.unwrap().unwrap();
+}
+
+fn report(direction: &str, allocations: usize) {
+    println!(
+        "{direction}: {:.2} allocations per datagram",
+        allocations as f64 / DATAGRAMS as f64
+    );
+}
@@ -1,12 +1,9 @@
-use futures::future::join_all;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr};
 use std::ops::DerefMut;
 use std::sync::Arc;
-use std::time::Duration;
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::Mutex;
-use tokio::time;
 
 const SOCKS_VERSION: u8 = 4;
 const BIND_COMMAND: u8 = 2;
@@ -19,7 +16,7 @@ async fn main() -> io::Result<()> {
     println!("SOCKS4 Proxy Server started on port 1080");
 
     loop {
-        let (mut client_socket, client_addr) = listener.accept().await?;
+        let (client_socket, client_addr) = listener.accept().await?;
         println!("Connection received from {}", client_addr);
 
         tokio::spawn(async move {
@@ -69,7 +66,7 @@ async fn handle_socks4_bind(mut client_socket: TcpStream) -> io::Result<()> {
     Ok(())
 }
 
-async fn run<S>(mut client_socket: S, bind_listener: TcpListener) -> io::Result<()>
+async fn run<S>(client_socket: S, bind_listener: TcpListener) -> io::Result<()>
 where
     S: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static,
 {
@@ -107,51 +104,20 @@ where
 {
     let mut s = client_socket.lock().await;
     let s = s.deref_mut();
-    let (mut client_reader, mut client_writer) = s.split();
+    let (mut client_reader, mut client_writer) = io::split(s);
     let (mut remote_reader, mut remote_writer) = remote_socket.split();
 
     let client_to_remote = tokio::io::copy(&mut client_reader, &mut remote_writer);
     let remote_to_client = tokio::io::copy(&mut remote_reader, &mut client_writer);
 
     tokio::select! {
-        result = client_to_remote => {
+        _ = client_to_remote => {
                 println!("Error forwarding data from client to remote:");
         }
-        result = remote_to_client => {
+        _ = remote_to_client => {
                 println!("Error forwarding data from remote to client:");
         }
     }
 
-    // let t1 = tokio::spawn(async move {
-    //     transfer_data("write", client_reader, remote_writer)
-    //         .await
-    //         .unwrap();
-    // });
-    // let t2 = tokio::spawn(async move {
-    //     transfer_data("read", remote_reader, client_writer)
-    //         .await
-    //         .unwrap();
-    // });
-
-    // join_all([t1, t2]).await;
-
-    Ok(())
-}
-
-async fn transfer_data<R, W>(r: &str, mut reader: R, mut writer: W) -> std::io::Result<()>
-where
-    R: AsyncReadExt + Unpin,
-    W: AsyncWriteExt + Unpin,
-{
-    let mut buf = [0u8; 4096];
-    loop {
-        let n = reader.read(&mut buf).await?;
-        println!("{} {:?}", r, n);
-        if n == 0 {
-            time::sleep(Duration::from_secs(1)).await;
-            continue;
-        }
-        writer.write_all(&buf[..n]).await?;
-    }
     Ok(())
 }
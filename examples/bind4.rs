@@ -20,7 +20,6 @@ where
         }
         writer.write_all(&buf[..n]).await?;
     }
-    Ok(())
 }
 
 #[tokio::main]
@@ -1,8 +1,22 @@
 use std::net::{Ipv4Addr, SocketAddrV4};
 
+use rusocks::socks5::addr_type::Socks5AddrType;
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
+// 回复的长度取决于 ATYP，IPv4 为 10 字节，IPv6 为 22 字节，域名还要看长度字节
+async fn read_reply(stream: &mut TcpStream) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    // 回复至少有 10 字节，先读到 ATYP 和域名长度
+    let mut response = vec![0u8; 5];
+    stream.read_exact(&mut response).await?;
+
+    let addr_type = Socks5AddrType::try_from(response[3])?;
+    response.resize(addr_type.reply_len(Some(response[4])), 0);
+    stream.read_exact(&mut response[5..]).await?;
+
+    Ok(response)
+}
+
 async fn socks5_bind_client(
     socks5_addr: &str,
     bind_addr: SocketAddrV4,
@@ -41,20 +55,18 @@ async fn socks5_bind_client(
     // +----+-----+-------+------+----------+----------+
 
     // 5. 接收第一次响应
-    let mut response = [0u8; 10]; // 假设 IPv4 地址和端口长度固定
-    stream.read_exact(&mut response).await?;
+    let response = read_reply(&mut stream).await?;
     if response[1] != 0x00 {
         return Err("BIND request failed".into());
     }
 
     println!("response {:?}", response);
 
-    let bnd_port = ((response[8] as u16) << 8) | response[9] as u16;
+    let bnd_port = u16::from_be_bytes([response[response.len() - 2], response[response.len() - 1]]);
     println!("Server is listening on port: {}", bnd_port);
 
     // 6. 等待第二次响应以确认连接
-    let mut second_response = [0u8; 10];
-    stream.read_exact(&mut second_response).await?;
+    let second_response = read_reply(&mut stream).await?;
     if second_response[1] != 0x00 {
         return Err("BIND connection failed".into());
     }
@@ -0,0 +1,27 @@
+use rusocks::{addr::SocksAddr, socks5::client};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+#[tokio::main]
+async fn main() {
+    // Fetches http://example.com through the SOCKS5 server listening on
+    // 127.0.0.1:1080, such as the tcp example
+    let mut stream = TcpStream::connect("127.0.0.1:1080").await.unwrap();
+    let dest_addr = SocksAddr::new("example.com", 80);
+
+    let bind_addr = client::connect(&mut stream, &dest_addr, None)
+        .await
+        .unwrap();
+    println!("connected to {:?} from {:?}", dest_addr, bind_addr);
+
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    println!("{}", response);
+}
@@ -0,0 +1,121 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use rusocks::{
+    addr::SocksAddr,
+    error::SocksError,
+    relay::Transfer,
+    socks4::{command::Socks4Command, Socks4Handler},
+    socks5::Socks5Handler,
+    Socks,
+};
+use tokio::net::TcpListener;
+
+#[tokio::main]
+async fn main() {
+    // curl -x socks5h://127.0.0.1:1080 http://127.0.0.1:8080 is proxied
+    // curl -x socks5h://127.0.0.1:1080 http://127.0.0.1:25 is rejected with
+    // "connection not allowed by ruleset", as is any destination outside
+    // the allowlist. SOCKS4 requests are all rejected, and UDP ASSOCIATE is
+    // left disabled
+    let listener = TcpListener::bind("127.0.0.1:1080").await.unwrap();
+    // A single instance of the rules is shared by every connection
+    let handler = Arc::new(Firewall {
+        blocked_ports: vec![25],
+        allowed_ips: vec![IpAddr::from([127, 0, 0, 1])],
+    });
+
+    while let Ok((mut stream, _)) = listener.accept().await {
+        let handler = handler.clone();
+
+        tokio::spawn(async move {
+            // Health probes that connect and close without sending a byte
+            // end here with `SocksError::EmptyConnection`
+            let mut socks = match Socks::<Firewall>::from_stream(&mut stream, handler).await {
+                Ok(socks) => socks,
+                Err(e) => {
+                    println!("error: {}", e);
+                    return;
+                }
+            };
+
+            match socks.execute(&mut stream).await {
+                Ok(_) => {
+                    println!("success");
+                }
+                Err(e) => {
+                    println!("error: {}", e);
+                }
+            }
+        });
+    }
+}
+
+struct Firewall {
+    blocked_ports: Vec<u16>,
+    allowed_ips: Vec<IpAddr>,
+}
+
+#[async_trait]
+impl Socks4Handler for Firewall {
+    type Error = SocksError;
+
+    // SOCKS4 has no hooks to check the destination against the rules, so
+    // it is refused rather than let past them
+    async fn allow_command(&self, command: &Socks4Command) -> Result<bool, Self::Error> {
+        println!("deny socks4 {:?}", command);
+        Ok(false)
+    }
+}
+
+#[async_trait]
+impl Socks5Handler for Firewall {
+    type Error = SocksError;
+
+    async fn allow_peer(&self, peer_addr: &SocketAddr) -> Result<bool, Self::Error> {
+        println!("accept {}", peer_addr);
+        Ok(true)
+    }
+
+    // Checked first, against the port of the request
+    async fn allow_port(&self, port: u16) -> Result<bool, Self::Error> {
+        let is_allowed = !self.blocked_ports.contains(&port);
+        if !is_allowed {
+            println!("deny port {}", port);
+        }
+
+        Ok(is_allowed)
+    }
+
+    // Then against every address the destination resolves to, which also
+    // covers domains that point at blocked hosts
+    async fn allow_resolved_ip(&self, ip: &IpAddr) -> Result<bool, Self::Error> {
+        let is_allowed = self.allowed_ips.contains(ip);
+        if !is_allowed {
+            println!("deny ip {}", ip);
+        }
+
+        Ok(is_allowed)
+    }
+
+    // Left disabled, as it is by default: enabling UDP ASSOCIATE keeps the
+    // rules, since every datagram is checked with `allow_port` and
+    // `allow_resolved_ip` before it is forwarded
+    fn udp_enabled(&self) -> bool {
+        false
+    }
+
+    fn on_transfer_complete(&self, dest_addr: &SocksAddr, transfer: &Transfer) {
+        println!(
+            "{}:{} sent {} received {} in {:?}",
+            dest_addr.domain(),
+            dest_addr.port(),
+            transfer.sent,
+            transfer.received,
+            transfer.duration
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use async_trait::async_trait;
+use rusocks::{
+    error::SocksError,
+    socks4::Socks4Handler,
+    socks5::{method::Socks5Method, Socks5Handler},
+    stream::ClientStream,
+    Socks,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+/// Private method code used by this example
+const SHARED_SECRET_METHOD: u8 = 0x80;
+
+#[tokio::main]
+async fn main() {
+    // A shared-secret scheme in the private method range. After selecting
+    // X'80' the client sends the secret and the server answers with a status:
+    //
+    // +-----+--------+          +--------+
+    // | LEN | SECRET |          | STATUS |
+    // +-----+--------+          +--------+
+    // |  1  | 1-255  |          |   1    |
+    // +-----+--------+          +--------+
+    //
+    // STATUS X'00' means success, anything else is a failure.
+    let listener = TcpListener::bind("127.0.0.1:1080").await.unwrap();
+
+    while let Ok((mut stream, _)) = listener.accept().await {
+        tokio::spawn(async move {
+            let handler = Handler {
+                secret: b"secret".to_vec(),
+            };
+            let mut socks = Socks::from_stream(&mut stream, handler).await.unwrap();
+
+            match socks.execute(&mut stream).await {
+                Ok(_) => {
+                    println!("success");
+                }
+                Err(e) => {
+                    println!("error: {}", e);
+                }
+            }
+        });
+    }
+}
+
+struct Handler {
+    secret: Vec<u8>,
+}
+
+#[async_trait]
+impl Socks4Handler for Handler {
+    type Error = SocksError;
+}
+
+#[async_trait]
+impl Socks5Handler for Handler {
+    type Error = SocksError;
+
+    async fn negotiate_method(
+        &self,
+        methods: &[Socks5Method],
+    ) -> Result<Socks5Method, Self::Error> {
+        let method = Socks5Method::Private(SHARED_SECRET_METHOD);
+
+        if methods.contains(&method) {
+            Ok(method)
+        } else {
+            Err(SocksError::UnsupportedMethods(methods.to_vec()))
+        }
+    }
+
+    async fn private_auth(
+        &self,
+        method: u8,
+        stream: &mut ClientStream<'_>,
+    ) -> Result<bool, Self::Error> {
+        if method != SHARED_SECRET_METHOD {
+            return Ok(false);
+        }
+
+        let length = stream.read_u8().await?;
+        let mut secret = vec![0; length as usize];
+        stream.read_exact(&mut secret).await?;
+
+        let is_success = secret == self.secret;
+        stream
+            .write_u8(if is_success { 0x00 } else { 0x01 })
+            .await?;
+
+        Ok(is_success)
+    }
+}
@@ -3,7 +3,6 @@ use rusocks::{
     error::SocksError,
     socks4::Socks4Handler,
     socks5::{method::Socks5Method, Socks5Handler},
-    Socks,
 };
 use tokio::net::TcpListener;
 
@@ -13,21 +12,14 @@ async fn main() {
     // curl -x socks5://127.0.0.1:1080 http://127.0.0.1:8080
     let listener = TcpListener::bind("127.0.0.1:1080").await.unwrap();
 
-    while let Ok((mut stream, _)) = listener.accept().await {
-        tokio::spawn(async move {
-            let handler = Handler {};
-            let mut socks = Socks::from_stream(&mut stream, handler).await.unwrap();
-
-            match socks.execute(&mut stream).await {
-                Ok(_) => {
-                    println!("success");
-                }
-                Err(e) => {
-                    println!("error: {}", e);
-                }
-            }
-        });
-    }
+    rusocks::serve(
+        listener,
+        Handler {},
+        |peer_addr, e| println!("error from {}: {}", peer_addr, e),
+        std::future::pending(),
+    )
+    .await
+    .unwrap();
 }
 
 struct Handler {}
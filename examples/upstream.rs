@@ -0,0 +1,28 @@
+use rusocks::{socks5::upstream::UpstreamSocks5, Socks};
+use tokio::net::TcpListener;
+
+#[tokio::main]
+async fn main() {
+    // Chains every CONNECT to the SOCKS5 server listening on 127.0.0.1:1081
+    // curl -x socks5://127.0.0.1:1080 http://127.0.0.1:8080
+    let listener = TcpListener::bind("127.0.0.1:1080").await.unwrap();
+    let handler = UpstreamSocks5::new("127.0.0.1:1081".parse().unwrap())
+        .with_credentials("username", "password");
+
+    while let Ok((mut stream, _)) = listener.accept().await {
+        let handler = handler.clone();
+
+        tokio::spawn(async move {
+            let mut socks = Socks::from_stream(&mut stream, handler).await.unwrap();
+
+            match socks.execute(&mut stream).await {
+                Ok(_) => {
+                    println!("success");
+                }
+                Err(e) => {
+                    println!("error: {}", e);
+                }
+            }
+        });
+    }
+}
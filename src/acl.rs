@@ -0,0 +1,361 @@
+use std::{net::IpAddr, str::FromStr};
+
+use crate::{addr::SocksAddr, error::SocksError};
+
+/// Outcome of `Acl::check`
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum AclDecision {
+    Allow,
+    Deny,
+}
+
+/// Destinations a rule applies to
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum AclTarget {
+    /// Every destination, written `*`
+    Any,
+    /// IP addresses in a network, written `10.0.0.0/8` or `fd00::/8`. A
+    /// single address, such as `10.0.0.1`, is a network of one. A network
+    /// of IPv4-mapped IPv6 addresses, such as `::ffff:10.0.0.0/104`, is
+    /// parsed as the IPv4 network it holds.
+    Cidr(IpAddr, u8),
+    /// A domain, written `example.com`, matched regardless of ASCII case
+    Domain(String),
+    /// Subdomains of a domain but not the domain itself, written
+    /// `*.example.com`
+    DomainSuffix(String),
+}
+
+/// One line of an `Acl`, such as `deny 10.0.0.0/8` or
+/// `allow *.example.com`
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct AclRule {
+    pub decision: AclDecision,
+    pub target: AclTarget,
+}
+
+/// Allowlist and denylist of destinations, so handlers do not each write
+/// their own CIDR and domain matching.
+///
+/// Rules are checked in order and the first one matching decides, so a
+/// narrow exception goes before the broader rule it carves out of:
+///
+/// ```text
+/// allow 10.1.2.3
+/// deny 10.0.0.0/8
+/// deny *.internal.example.com
+/// ```
+///
+/// A destination no rule matches gets the default decision, `Allow` unless
+/// set with `with_default`. IP rules only match IP addresses, IPv4-mapped
+/// IPv6 addresses included, and domain rules only match domains, nothing
+/// being resolved. Call `check_ip` from `allow_resolved_ip` so IP rules also
+/// apply to the addresses domains resolve to, and `check` where the
+/// requested destination is at hand, such as `Socks5Handler::resolve`.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Acl {
+    rules: Vec<AclRule>,
+    default: AclDecision,
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Acl {
+    /// Empty list, allowing every destination
+    pub fn new() -> Self {
+        Self {
+            rules: Vec::new(),
+            default: AclDecision::Allow,
+        }
+    }
+
+    /// Appends `rule`, checked after the rules already in the list
+    pub fn with_rule(mut self, rule: AclRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Decision for destinations no rule matches
+    pub fn with_default(mut self, default: AclDecision) -> Self {
+        self.default = default;
+        self
+    }
+
+    /// Decision of the first rule matching `addr`, ignoring its port. A
+    /// domain that is an IP literal is matched as that IP.
+    pub fn check(&self, addr: &SocksAddr) -> AclDecision {
+        match addr {
+            SocksAddr::IPV4(addr) => self.check_ip(&IpAddr::V4(*addr.ip())),
+            SocksAddr::IPV6(addr) => self.check_ip(&IpAddr::V6(*addr.ip())),
+            SocksAddr::Domain(domain, _) => match domain.parse::<IpAddr>() {
+                Ok(ip) => self.check_ip(&ip),
+                Err(_) => self.decide(|target| target.matches_domain(domain)),
+            },
+        }
+    }
+
+    /// Decision of the first rule matching `ip`
+    pub fn check_ip(&self, ip: &IpAddr) -> AclDecision {
+        let ip = ip.to_canonical();
+        self.decide(|target| target.matches_ip(&ip))
+    }
+
+    fn decide(&self, matches: impl Fn(&AclTarget) -> bool) -> AclDecision {
+        self.rules
+            .iter()
+            .find(|rule| matches(&rule.target))
+            .map_or(self.default, |rule| rule.decision)
+    }
+}
+
+impl AclTarget {
+    /// `Cidr` of `ip`, IPv4-mapped networks taken as IPv4 since `check_ip`
+    /// matches the canonical form of an address
+    fn cidr(ip: IpAddr, prefix: u8) -> Self {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) if prefix >= 96 => Self::Cidr(IpAddr::V4(ip), prefix - 96),
+            _ => Self::Cidr(ip, prefix),
+        }
+    }
+
+    fn matches_ip(&self, ip: &IpAddr) -> bool {
+        match (self, ip) {
+            (Self::Any, _) => true,
+            (Self::Cidr(IpAddr::V4(network), prefix), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(*prefix)).unwrap_or(0);
+                u32::from(*network) & mask == u32::from(*ip) & mask
+            }
+            (Self::Cidr(IpAddr::V6(network), prefix), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(*prefix)).unwrap_or(0);
+                u128::from(*network) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+
+    fn matches_domain(&self, domain: &str) -> bool {
+        // A fully qualified name is the same domain
+        let domain = domain.strip_suffix('.').unwrap_or(domain);
+
+        match self {
+            Self::Any => true,
+            Self::Domain(target) => domain.eq_ignore_ascii_case(target),
+            Self::DomainSuffix(suffix) => {
+                let (domain, suffix) = (domain.as_bytes(), suffix.as_bytes());
+                let start = domain.len().saturating_sub(suffix.len());
+
+                // At least one label before the suffix
+                start > 1
+                    && domain[start - 1] == b'.'
+                    && domain[start..].eq_ignore_ascii_case(suffix)
+            }
+            Self::Cidr(..) => false,
+        }
+    }
+}
+
+/// Parses `*`, an IP address, a CIDR network, `*.domain` or a domain
+impl FromStr for AclTarget {
+    type Err = SocksError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || SocksError::InvalidAclRule(s.to_string());
+
+        if s == "*" {
+            return Ok(Self::Any);
+        }
+
+        if let Some((ip, prefix)) = s.split_once('/') {
+            let ip: IpAddr = ip.parse().map_err(|_| invalid())?;
+            let prefix: u8 = prefix.parse().map_err(|_| invalid())?;
+            let max_prefix = if ip.is_ipv4() { 32 } else { 128 };
+            if prefix > max_prefix {
+                return Err(invalid());
+            }
+
+            return Ok(Self::cidr(ip, prefix));
+        }
+
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            let prefix = if ip.is_ipv4() { 32 } else { 128 };
+            return Ok(Self::cidr(ip, prefix));
+        }
+
+        let (domain, is_suffix) = match s.strip_prefix("*.") {
+            Some(suffix) => (suffix, true),
+            None => (s, false),
+        };
+        let domain = domain.strip_suffix('.').unwrap_or(domain);
+        if domain.is_empty() || domain.contains(['*', '/', ':', '[', ']']) {
+            return Err(invalid());
+        }
+
+        if is_suffix {
+            Ok(Self::DomainSuffix(domain.to_string()))
+        } else {
+            Ok(Self::Domain(domain.to_string()))
+        }
+    }
+}
+
+/// Parses `allow <target>` or `deny <target>`
+impl FromStr for AclRule {
+    type Err = SocksError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || SocksError::InvalidAclRule(s.to_string());
+
+        let mut words = s.split_whitespace();
+        let decision = match words.next() {
+            Some("allow") => AclDecision::Allow,
+            Some("deny") => AclDecision::Deny,
+            _ => return Err(invalid()),
+        };
+        let target = words
+            .next()
+            .ok_or_else(invalid)?
+            .parse()
+            .map_err(|_| invalid())?;
+        if words.next().is_some() {
+            return Err(invalid());
+        }
+
+        Ok(Self { decision, target })
+    }
+}
+
+/// Parses one rule per line, skipping blank lines and comments starting
+/// with `#`
+impl FromStr for Acl {
+    type Err = SocksError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.lines()
+            .map(|line| line.split_once('#').map_or(line, |(rule, _)| rule).trim())
+            .filter(|line| !line.is_empty())
+            .try_fold(Self::new(), |acl, line| Ok(acl.with_rule(line.parse()?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    fn ip(ip: &str) -> SocksAddr {
+        SocketAddr::new(ip.parse().unwrap(), 80).into()
+    }
+
+    fn domain(domain: &str) -> SocksAddr {
+        SocksAddr::Domain(domain.to_string(), 80)
+    }
+
+    #[test]
+    fn rules_are_parsed_line_by_line() {
+        let acl: Acl = "
+            # Internal networks
+            allow 10.1.2.3
+            deny 10.0.0.0/8 # but not the one above
+
+            deny *.internal.example.com
+            allow *
+        "
+        .parse()
+        .unwrap();
+
+        assert_eq!(
+            acl.rules,
+            [
+                AclRule {
+                    decision: AclDecision::Allow,
+                    target: AclTarget::Cidr("10.1.2.3".parse().unwrap(), 32),
+                },
+                AclRule {
+                    decision: AclDecision::Deny,
+                    target: AclTarget::Cidr("10.0.0.0".parse().unwrap(), 8),
+                },
+                AclRule {
+                    decision: AclDecision::Deny,
+                    target: AclTarget::DomainSuffix("internal.example.com".to_string()),
+                },
+                AclRule {
+                    decision: AclDecision::Allow,
+                    target: AclTarget::Any,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        for rule in [
+            "deny 10.0.0.0/33",
+            "deny fd00::/129",
+            "deny 10.0.0.0/x",
+            "block 10.0.0.0/8",
+            "deny",
+            "deny 10.0.0.0/8 extra",
+            "deny *.",
+            "deny a*.example.com",
+        ] {
+            assert!(
+                matches!(rule.parse::<AclRule>(), Err(SocksError::InvalidAclRule(_))),
+                "{rule}"
+            );
+        }
+    }
+
+    #[test]
+    fn zero_prefix_matches_the_whole_family() {
+        let acl: Acl = "deny 0.0.0.0/0".parse().unwrap();
+
+        assert_eq!(acl.check(&ip("192.0.2.1")), AclDecision::Deny);
+        assert_eq!(acl.check(&ip("255.255.255.255")), AclDecision::Deny);
+        assert_eq!(acl.check(&ip("2001:db8::1")), AclDecision::Allow);
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let acl: Acl = "allow 10.1.2.3\ndeny 10.0.0.0/8".parse().unwrap();
+
+        assert_eq!(acl.check(&ip("10.1.2.3")), AclDecision::Allow);
+        assert_eq!(acl.check(&ip("10.1.2.4")), AclDecision::Deny);
+        assert_eq!(acl.check(&ip("192.0.2.1")), AclDecision::Allow);
+
+        let acl = acl.with_default(AclDecision::Deny);
+        assert_eq!(acl.check(&ip("192.0.2.1")), AclDecision::Deny);
+    }
+
+    #[test]
+    fn domain_suffix_matches_subdomains_only() {
+        let acl: Acl = "deny *.example.com".parse().unwrap();
+
+        assert_eq!(acl.check(&domain("a.example.com")), AclDecision::Deny);
+        assert_eq!(acl.check(&domain("a.b.EXAMPLE.com.")), AclDecision::Deny);
+        assert_eq!(acl.check(&domain("example.com")), AclDecision::Allow);
+        assert_eq!(acl.check(&domain("badexample.com")), AclDecision::Allow);
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_are_matched_as_ipv4() {
+        let acl: Acl = "deny 10.0.0.0/8".parse().unwrap();
+        assert_eq!(acl.check(&ip("::ffff:10.1.2.3")), AclDecision::Deny);
+        assert_eq!(acl.check(&domain("::ffff:10.1.2.3")), AclDecision::Deny);
+
+        let acl: Acl = "deny ::ffff:10.0.0.0/104\ndeny ::ffff:192.0.2.1"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            acl.rules[0].target,
+            AclTarget::Cidr("10.0.0.0".parse().unwrap(), 8)
+        );
+        assert_eq!(acl.check(&ip("10.1.2.3")), AclDecision::Deny);
+        assert_eq!(acl.check(&ip("::ffff:10.1.2.3")), AclDecision::Deny);
+        assert_eq!(acl.check(&ip("192.0.2.1")), AclDecision::Deny);
+    }
+}
@@ -1,4 +1,14 @@
-use std::net::{SocketAddrV4, SocketAddrV6};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    str::FromStr,
+};
+
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::lookup_host,
+};
+
+use crate::{error::SocksError, socks5::addr_type::Socks5AddrType};
 
 #[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub enum SocksAddr {
@@ -8,6 +18,17 @@ pub enum SocksAddr {
 }
 
 impl SocksAddr {
+    /// Address of `host` at `port`, `host` being an IPv4 address, an IPv6
+    /// address without brackets, or else a domain, as configurations with
+    /// separate host and port fields have it. Unlike `FromStr` it never
+    /// fails: anything that is not an IP address is taken as a domain.
+    pub fn new(host: &str, port: u16) -> Self {
+        match host.parse::<IpAddr>() {
+            Ok(ip) => SocketAddr::new(ip, port).into(),
+            Err(_) => Self::Domain(host.to_string(), port),
+        }
+    }
+
     pub fn domain(&self) -> String {
         match self {
             Self::IPV4(addr) => addr.ip().to_string(),
@@ -23,4 +44,491 @@ impl SocksAddr {
             Self::IPV6(addr) => addr.port(),
         }
     }
+
+    /// Same address at `port`, as when rewriting a destination to a fixed
+    /// port. The variant is kept, and so are the flow info and scope id of
+    /// an IPv6 address.
+    pub fn with_port(mut self, port: u16) -> Self {
+        match &mut self {
+            Self::IPV4(addr) => addr.set_port(port),
+            Self::Domain(_, domain_port) => *domain_port = port,
+            Self::IPV6(addr) => addr.set_port(port),
+        }
+
+        self
+    }
+
+    /// Address of `host` at the same port, `host` being read as `new` reads
+    /// it, so the variant follows the new host. Replacing an IPv6 address
+    /// with another keeps its flow info and scope id.
+    pub fn with_host(self, host: &str) -> Self {
+        match (Self::new(host, self.port()), self) {
+            (Self::IPV6(addr), Self::IPV6(old)) => Self::IPV6(SocketAddrV6::new(
+                *addr.ip(),
+                addr.port(),
+                old.flowinfo(),
+                old.scope_id(),
+            )),
+            (addr, _) => addr,
+        }
+    }
+
+    /// Whether both addresses denote the same endpoint, unlike `==` which
+    /// compares variants. A domain that is an IP literal, such as
+    /// `Domain("1.2.3.4", 80)`, matches that IP, IPv4-mapped IPv6 addresses
+    /// match their IPv4 form and other domains are compared ignoring ASCII
+    /// case. Nothing is resolved, so a domain never matches an IP it
+    /// resolves to. IPv6 flow info and scope ids are ignored.
+    pub fn same_endpoint(&self, other: &SocksAddr) -> bool {
+        if self.port() != other.port() {
+            return false;
+        }
+
+        match (self.ip(), other.ip()) {
+            (Some(ip), Some(other_ip)) => ip.to_canonical() == other_ip.to_canonical(),
+            (None, None) => self.domain().eq_ignore_ascii_case(&other.domain()),
+            _ => false,
+        }
+    }
+
+    /// Socket addresses the destination resolves to, ordered by `prefer`.
+    /// An IP address resolves to itself. A failed lookup is reported as
+    /// `io::ErrorKind::HostUnreachable`.
+    pub async fn resolved_sorted(&self, prefer: IpPref) -> io::Result<Vec<SocketAddr>> {
+        let addrs = match self {
+            Self::IPV4(addr) => vec![SocketAddr::V4(*addr)],
+            Self::Domain(domain, port) => lookup_host((domain.as_str(), *port))
+                .await
+                .map_err(|err| io::Error::new(io::ErrorKind::HostUnreachable, err))?
+                .collect(),
+            Self::IPV6(addr) => vec![SocketAddr::V6(*addr)],
+        };
+
+        Ok(prefer.sort(addrs))
+    }
+
+    /// IP of the address, also for a domain that is an IP literal
+    fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Self::IPV4(addr) => Some(IpAddr::V4(*addr.ip())),
+            Self::Domain(domain, _) => domain.parse().ok(),
+            Self::IPV6(addr) => Some(IpAddr::V6(*addr.ip())),
+        }
+    }
+
+    /// Decodes the `ADDR | PORT` part of a SOCKS5 frame for the given ATYP,
+    /// returning the address and the number of bytes consumed. A domain is
+    /// prefixed with its length octet.
+    pub(crate) fn from_bytes(
+        addr_type: &Socks5AddrType,
+        buf: &[u8],
+    ) -> Result<(Self, usize), SocksError> {
+        match addr_type {
+            Socks5AddrType::IPV4 => {
+                let buf = buf.get(..4 + 2).ok_or(SocksError::Incomplete)?;
+
+                let ip = Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]);
+                let port = u16::from_be_bytes([buf[4], buf[5]]);
+
+                Ok((Self::IPV4(SocketAddrV4::new(ip, port)), 4 + 2))
+            }
+            Socks5AddrType::Domain => {
+                let length = *buf.first().ok_or(SocksError::Incomplete)? as usize;
+                let buf = buf.get(1..1 + length + 2).ok_or(SocksError::Incomplete)?;
+
+                let domain = String::from_utf8(buf[..length].to_vec())
+                    .map_err(SocksError::Utf8BytesToStringError)?;
+                let port = u16::from_be_bytes([buf[length], buf[length + 1]]);
+
+                Ok((Self::Domain(domain, port), 1 + length + 2))
+            }
+            Socks5AddrType::IPV6 => {
+                let buf = buf.get(..16 + 2).ok_or(SocksError::Incomplete)?;
+
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&buf[..16]);
+                let ip = Ipv6Addr::from(octets);
+                let port = u16::from_be_bytes([buf[16], buf[17]]);
+
+                Ok((Self::IPV6(SocketAddrV6::new(ip, port, 0, 0)), 16 + 2))
+            }
+        }
+    }
+
+    pub(crate) fn addr_type(&self) -> Socks5AddrType {
+        match self {
+            Self::IPV4(_) => Socks5AddrType::IPV4,
+            Self::Domain(_, _) => Socks5AddrType::Domain,
+            Self::IPV6(_) => Socks5AddrType::IPV6,
+        }
+    }
+
+    /// Encodes the address as the `ADDR | PORT` part of a SOCKS5 frame, the
+    /// inverse of `from_bytes`. A domain longer than 255 bytes does not fit
+    /// in the length octet and is rejected rather than truncated.
+    pub(crate) fn to_bytes(&self) -> Result<Vec<u8>, SocksError> {
+        let mut buf = Vec::new();
+
+        match self {
+            Self::IPV4(addr) => buf.extend(addr.ip().octets()),
+            Self::Domain(domain, _) => {
+                let length = u8::try_from(domain.len())
+                    .map_err(|_| SocksError::DomainTooLong(domain.len()))?;
+                buf.push(length);
+                buf.extend(domain.as_bytes());
+            }
+            Self::IPV6(addr) => buf.extend(addr.ip().octets()),
+        }
+        buf.extend(self.port().to_be_bytes());
+
+        Ok(buf)
+    }
+}
+
+/// Order in which the addresses of a destination are dialed
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum IpPref {
+    /// The order of the system resolver
+    #[default]
+    System,
+    /// IPv6 and IPv4 addresses alternate, starting with IPv6, as RFC 8305
+    /// recommends for Happy Eyeballs
+    Ipv6First,
+    /// IPv4 and IPv6 addresses alternate, starting with IPv4
+    Ipv4First,
+}
+
+impl IpPref {
+    /// Orders `addrs` by this preference. Addresses of the same family keep
+    /// their relative order, and once a family runs out the rest of the
+    /// other one follows.
+    pub fn sort(&self, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let prefer_ipv6 = match self {
+            Self::System => return addrs,
+            Self::Ipv6First => true,
+            Self::Ipv4First => false,
+        };
+
+        let (preferred, other): (Vec<_>, Vec<_>) = addrs
+            .into_iter()
+            .partition(|addr| addr.is_ipv6() == prefer_ipv6);
+
+        let mut sorted = Vec::with_capacity(preferred.len() + other.len());
+        let mut preferred = preferred.into_iter();
+        let mut other = other.into_iter();
+        loop {
+            match (preferred.next(), other.next()) {
+                (None, None) => break,
+                (addr, other_addr) => sorted.extend(addr.into_iter().chain(other_addr)),
+            }
+        }
+
+        sorted
+    }
+}
+
+/// Reads the `ADDR | PORT` part of a SOCKS5 frame for the given ATYP, as
+/// found in requests, replies and UDP headers, consuming exactly its bytes.
+/// A domain is prefixed with its length octet.
+pub async fn read_socks_addr<S>(
+    stream: &mut S,
+    addr_type: Socks5AddrType,
+) -> Result<SocksAddr, SocksError>
+where
+    S: AsyncRead + Unpin,
+{
+    // The length octet of a domain is read on its own to size the rest
+    let (mut buf, start) = match addr_type {
+        Socks5AddrType::IPV4 => (vec![0; 4 + 2], 0),
+        Socks5AddrType::Domain => {
+            let length = stream.read_u8().await?;
+            let mut buf = vec![0; 1 + length as usize + 2];
+            buf[0] = length;
+            (buf, 1)
+        }
+        Socks5AddrType::IPV6 => (vec![0; 16 + 2], 0),
+    };
+    stream.read_exact(&mut buf[start..]).await?;
+
+    let (addr, _) = SocksAddr::from_bytes(&addr_type, &buf)?;
+
+    Ok(addr)
+}
+
+/// Writes `addr` as the `ATYP | ADDR | PORT` part of a SOCKS5 frame, the
+/// ATYP being chosen from the variant; `read_socks_addr` reads it back. A
+/// domain longer than 255 bytes is an `io::ErrorKind::InvalidInput` error
+/// and nothing is written then.
+pub async fn write_socks_addr<S>(stream: &mut S, addr: &SocksAddr) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut buf = vec![addr.addr_type().into()];
+    buf.extend(
+        addr.to_bytes()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?,
+    );
+
+    stream.write_all(&buf).await
+}
+
+impl From<SocketAddr> for SocksAddr {
+    fn from(addr: SocketAddr) -> Self {
+        match addr {
+            SocketAddr::V4(addr) => Self::IPV4(addr),
+            SocketAddr::V6(addr) => Self::IPV6(addr),
+        }
+    }
+}
+
+/// Parses `ipv4:port`, `[ipv6]:port` or `domain:port`. An IPv6 address may
+/// carry a numeric scope id, as in `[fe80::1%2]:80`; interface names are
+/// not resolved.
+impl FromStr for SocksAddr {
+    type Err = SocksError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(addr.into());
+        }
+
+        let invalid = || SocksError::InvalidAddress(s.to_string());
+
+        let (domain, port) = s.rsplit_once(':').ok_or_else(invalid)?;
+        let port = port.parse().map_err(|_| invalid())?;
+
+        // Anything bracketed or with a colon is a malformed IP, not a domain
+        if domain.is_empty() || domain.contains(['[', ']', ':']) {
+            return Err(invalid());
+        }
+
+        Ok(Self::Domain(domain.to_string(), port))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn domains_over_255_bytes_are_not_encoded() {
+        let dest_addr = SocksAddr::Domain("a".repeat(300), 80);
+
+        assert!(matches!(
+            dest_addr.to_bytes(),
+            Err(SocksError::DomainTooLong(300))
+        ));
+    }
+
+    #[test]
+    fn domains_of_255_bytes_are_encoded() {
+        let dest_addr = SocksAddr::Domain("a".repeat(255), 80);
+
+        let buf = dest_addr.to_bytes().unwrap();
+        assert_eq!(buf[0], 255);
+        assert_eq!(buf.len(), 1 + 255 + 2);
+    }
+
+    #[test]
+    fn with_port_keeps_the_host() {
+        let addr = SocksAddr::IPV6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 80, 0, 2));
+        assert_eq!(
+            addr.with_port(443),
+            SocksAddr::IPV6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 443, 0, 2))
+        );
+
+        let addr = SocksAddr::Domain("example.com".to_string(), 80);
+        assert_eq!(
+            addr.with_port(443),
+            SocksAddr::Domain("example.com".to_string(), 443)
+        );
+    }
+
+    #[test]
+    fn with_host_follows_the_new_host() {
+        let addr = SocksAddr::Domain("example.com".to_string(), 80);
+
+        assert_eq!(
+            addr.clone().with_host("192.0.2.1"),
+            SocksAddr::IPV4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 80))
+        );
+        assert_eq!(
+            addr.with_host("example.org"),
+            SocksAddr::Domain("example.org".to_string(), 80)
+        );
+    }
+
+    #[test]
+    fn with_host_keeps_the_scope_of_an_ipv6_address() {
+        let addr = SocksAddr::IPV6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 80, 7, 2));
+
+        assert_eq!(
+            addr.clone().with_host("fe80::1"),
+            SocksAddr::IPV6(SocketAddrV6::new("fe80::1".parse().unwrap(), 80, 7, 2))
+        );
+        assert_eq!(
+            addr.with_host("example.com"),
+            SocksAddr::Domain("example.com".to_string(), 80)
+        );
+    }
+
+    #[test]
+    fn addresses_parse_from_strings() {
+        assert_eq!(
+            "192.0.2.1:80".parse::<SocksAddr>().unwrap(),
+            SocksAddr::IPV4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 80))
+        );
+        assert_eq!(
+            "[fe80::1%2]:80".parse::<SocksAddr>().unwrap(),
+            SocksAddr::IPV6(SocketAddrV6::new("fe80::1".parse().unwrap(), 80, 0, 2))
+        );
+        assert_eq!(
+            "example.com:443".parse::<SocksAddr>().unwrap(),
+            SocksAddr::Domain("example.com".to_string(), 443)
+        );
+    }
+
+    #[test]
+    fn malformed_addresses_are_invalid() {
+        for s in [
+            "example.com",
+            "example.com:http",
+            ":80",
+            "[::1:80",
+            "::1:80",
+            "[fe80::1%eth0]:80",
+        ] {
+            assert!(
+                matches!(s.parse::<SocksAddr>(), Err(SocksError::InvalidAddress(_))),
+                "{}",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn same_endpoint_matches_across_variants() {
+        let ipv4 = SocksAddr::IPV4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 80));
+        let mapped = SocksAddr::IPV6(SocketAddrV6::new(
+            Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped(),
+            80,
+            0,
+            0,
+        ));
+
+        assert!(ipv4.same_endpoint(&SocksAddr::Domain("192.0.2.1".to_string(), 80)));
+        assert!(ipv4.same_endpoint(&mapped));
+        assert!(!ipv4.same_endpoint(&ipv4.clone().with_port(443)));
+        assert!(SocksAddr::Domain("Example.COM".to_string(), 80)
+            .same_endpoint(&SocksAddr::Domain("example.com".to_string(), 80)));
+    }
+
+    #[test]
+    fn domains_never_match_ips() {
+        let localhost = SocksAddr::Domain("localhost".to_string(), 80);
+        let ip = SocksAddr::IPV4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 80));
+
+        assert!(!localhost.same_endpoint(&ip));
+    }
+
+    #[tokio::test]
+    async fn read_socks_addr_consumes_exactly_the_address() {
+        let cases: [(Socks5AddrType, &[u8], SocksAddr); 3] = [
+            (
+                Socks5AddrType::IPV4,
+                &[192, 0, 2, 1, 0, 80],
+                SocksAddr::IPV4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 80)),
+            ),
+            (
+                Socks5AddrType::Domain,
+                &[3, b'a', b'.', b'b', 0, 80],
+                SocksAddr::Domain("a.b".to_string(), 80),
+            ),
+            (
+                Socks5AddrType::IPV6,
+                &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 80],
+                SocksAddr::IPV6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 80, 0, 0)),
+            ),
+        ];
+
+        for (addr_type, bytes, addr) in cases {
+            let buf = [bytes, b"rest"].concat();
+            let mut stream = &buf[..];
+
+            assert_eq!(read_socks_addr(&mut stream, addr_type).await.unwrap(), addr);
+            assert_eq!(stream, b"rest");
+        }
+    }
+
+    #[tokio::test]
+    async fn written_addresses_read_back() {
+        let addrs = [
+            SocksAddr::IPV4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 80)),
+            SocksAddr::Domain("example.com".to_string(), 443),
+            SocksAddr::IPV6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 8080, 0, 0)),
+        ];
+
+        for addr in addrs {
+            let mut buf = Vec::new();
+            write_socks_addr(&mut buf, &addr).await.unwrap();
+
+            let addr_type = buf[0].try_into().unwrap();
+            let mut stream = &buf[1..];
+            assert_eq!(read_socks_addr(&mut stream, addr_type).await.unwrap(), addr);
+            assert!(stream.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn overlong_domains_write_nothing() {
+        let mut buf = Vec::new();
+        let addr = SocksAddr::Domain("a".repeat(256), 80);
+
+        let err = write_socks_addr(&mut buf, &addr).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn families_alternate_in_the_preferred_order() {
+        let v4 = |last| SocketAddr::from(([192, 0, 2, last], 80));
+        let v6 = |last| SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, last], 80));
+        let addrs = vec![v4(1), v4(2), v4(3), v6(1)];
+
+        assert_eq!(IpPref::System.sort(addrs.clone()), addrs);
+        assert_eq!(
+            IpPref::Ipv6First.sort(addrs.clone()),
+            [v6(1), v4(1), v4(2), v4(3)]
+        );
+        assert_eq!(IpPref::Ipv4First.sort(addrs), [v4(1), v6(1), v4(2), v4(3)]);
+    }
+
+    #[tokio::test]
+    async fn ips_resolve_to_themselves() {
+        let addr = SocksAddr::IPV4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 80));
+
+        let addrs = addr.resolved_sorted(IpPref::Ipv6First).await.unwrap();
+        assert_eq!(addrs, [SocketAddr::from(([192, 0, 2, 1], 80))]);
+    }
+
+    #[test]
+    fn new_reads_ips_and_domains() {
+        assert_eq!(
+            SocksAddr::new("192.0.2.1", 80),
+            SocksAddr::IPV4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 80))
+        );
+        assert_eq!(
+            SocksAddr::new("::1", 80),
+            SocksAddr::IPV6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 80, 0, 0))
+        );
+        assert_eq!(
+            SocksAddr::new("example.com", 80),
+            SocksAddr::Domain("example.com".to_string(), 80)
+        );
+        // Brackets are not stripped, so this is not an IPv6 address
+        assert_eq!(
+            SocksAddr::new("[::1]", 80),
+            SocksAddr::Domain("[::1]".to_string(), 80)
+        );
+    }
 }
@@ -0,0 +1,167 @@
+use std::{net::SocketAddr, time::Duration};
+
+use tokio::{
+    io,
+    net::{TcpSocket, TcpStream},
+};
+#[cfg(feature = "happy-eyeballs")]
+use tokio::{select, task::JoinSet, time};
+
+#[cfg(feature = "happy-eyeballs")]
+use crate::addr::IpPref;
+
+/// Delay before the next address is dialed while the previous attempts are
+/// still pending, the Connection Attempt Delay of RFC 8305
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Opens a connection to one of `addrs`, which the default `connect` of both
+/// handlers use.
+///
+/// With the `happy-eyeballs` feature, the addresses are dialed as RFC 8305
+/// describes: IPv6 and IPv4 alternate, starting with IPv6, and each attempt
+/// starts `CONNECTION_ATTEMPT_DELAY` after the previous one, or as soon as
+/// it fails. The first connection established wins and the other attempts
+/// are cancelled, so a broken IPv6 route does not hold up the IPv4 one.
+///
+/// Without the feature, the addresses are dialed one after the other, in
+/// order. Either way the error of the last attempt is returned when none
+/// succeeds.
+pub async fn dial(addrs: &[SocketAddr]) -> io::Result<TcpStream> {
+    dial_from(addrs, None).await
+}
+
+/// Same as `dial`, binding each outbound socket to `bind_addr` first when
+/// given, to pick the source address and so the egress interface. An
+/// address of the other family cannot be reached from `bind_addr`, and
+/// the attempt to dial it fails.
+pub async fn dial_from(
+    addrs: &[SocketAddr],
+    bind_addr: Option<SocketAddr>,
+) -> io::Result<TcpStream> {
+    #[cfg(feature = "happy-eyeballs")]
+    return happy_eyeballs(addrs, bind_addr).await;
+
+    #[cfg(not(feature = "happy-eyeballs"))]
+    {
+        let mut last_err = None;
+        for &addr in addrs {
+            match connect(addr, bind_addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = Some(err),
+            }
+        }
+
+        Err(last_err.unwrap_or_else(no_address))
+    }
+}
+
+async fn connect(addr: SocketAddr, bind_addr: Option<SocketAddr>) -> io::Result<TcpStream> {
+    let Some(bind_addr) = bind_addr else {
+        return TcpStream::connect(addr).await;
+    };
+
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.bind(bind_addr)?;
+    socket.connect(addr).await
+}
+
+fn no_address() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "could not resolve to any address",
+    )
+}
+
+#[cfg(feature = "happy-eyeballs")]
+async fn happy_eyeballs(
+    addrs: &[SocketAddr],
+    bind_addr: Option<SocketAddr>,
+) -> io::Result<TcpStream> {
+    let mut pending = IpPref::Ipv6First.sort(addrs.to_vec()).into_iter();
+    let mut attempts = JoinSet::new();
+    let mut last_err = None;
+
+    if let Some(addr) = pending.next() {
+        attempts.spawn(connect(addr, bind_addr));
+    }
+
+    while !attempts.is_empty() {
+        select! {
+            Some(result) = attempts.join_next() => {
+                match result {
+                    // Dropping the set aborts the attempts still running
+                    Ok(Ok(stream)) => return Ok(stream),
+                    Ok(Err(err)) => last_err = Some(err),
+                    Err(err) => last_err = Some(io::Error::other(err)),
+                }
+
+                // A failed attempt does not wait for the delay to elapse
+                if let Some(addr) = pending.next() {
+                    attempts.spawn(connect(addr, bind_addr));
+                }
+            }
+            _ = time::sleep(CONNECTION_ATTEMPT_DELAY), if !pending.as_slice().is_empty() => {
+                if let Some(addr) = pending.next() {
+                    attempts.spawn(connect(addr, bind_addr));
+                }
+            }
+        }
+    }
+
+    Err(last_err.unwrap_or_else(no_address))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn outbound_sockets_are_bound_to_the_address_given() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bind_addr = SocketAddr::from(([127, 0, 0, 2], 0));
+
+        let stream = dial_from(&[listener.local_addr().unwrap()], Some(bind_addr))
+            .await
+            .unwrap();
+        let (_, peer_addr) = listener.accept().await.unwrap();
+        assert_eq!(peer_addr, stream.local_addr().unwrap());
+        assert_eq!(peer_addr.ip(), bind_addr.ip());
+    }
+
+    #[tokio::test]
+    async fn addresses_of_the_other_family_fail() {
+        let addr = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], 80));
+        let bind_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+
+        assert!(dial_from(&[addr], Some(bind_addr)).await.is_err());
+    }
+
+    #[cfg(feature = "happy-eyeballs")]
+    #[tokio::test]
+    async fn next_address_wins_when_the_first_hangs() {
+        use std::time::Instant;
+
+        use crate::test_util::unresponsive_listener;
+
+        let (unresponsive, _streams) = unresponsive_listener().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addrs = [
+            unresponsive.local_addr().unwrap(),
+            listener.local_addr().unwrap(),
+        ];
+
+        let started = Instant::now();
+        let stream = dial(&addrs).await.unwrap();
+        let elapsed = started.elapsed();
+
+        assert_eq!(stream.peer_addr().unwrap(), addrs[1]);
+        assert!(elapsed >= CONNECTION_ATTEMPT_DELAY, "{elapsed:?}");
+        assert!(elapsed < CONNECTION_ATTEMPT_DELAY * 4, "{elapsed:?}");
+    }
+}
@@ -0,0 +1,190 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tokio::io;
+
+use crate::addr::{IpPref, SocksAddr};
+
+/// Cache of name resolution the default SOCKS5 `connect` consults when
+/// `Socks5Handler::dns_cache` returns one, so repeated destinations skip
+/// the resolver.
+///
+/// Addresses are kept for `with_ttl` and failed lookups for
+/// `with_negative_ttl`, keyed by domain regardless of ASCII case and port.
+/// The system resolver does not report the TTL of the records, so the
+/// cache applies its own. Once `with_max_entries` domains are cached,
+/// expired entries are dropped, and then the entry closest to expiring,
+/// so memory stays bounded.
+#[derive(Debug)]
+pub struct DnsCache {
+    entries: Mutex<HashMap<String, Entry>>,
+    ttl: Duration,
+    negative_ttl: Duration,
+    max_entries: usize,
+}
+
+#[derive(Debug)]
+struct Entry {
+    // `None` for a failed lookup
+    ips: Option<Vec<IpAddr>>,
+    expires_at: Instant,
+}
+
+impl Default for DnsCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DnsCache {
+    /// Cache of up to 1024 domains, keeping addresses for 60 seconds and
+    /// failed lookups for 5 seconds
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            ttl: Duration::from_secs(60),
+            negative_ttl: Duration::from_secs(5),
+            max_entries: 1024,
+        }
+    }
+
+    /// How long the addresses a domain resolved to are reused
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// How long a failed lookup keeps failing without asking the resolver
+    /// again, `Duration::ZERO` to not cache failures
+    pub fn with_negative_ttl(mut self, negative_ttl: Duration) -> Self {
+        self.negative_ttl = negative_ttl;
+        self
+    }
+
+    /// Most domains cached at once
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// Same as `SocksAddr::resolved_sorted` with `IpPref::System`, answered
+    /// from the cache while the previous lookup of the domain is fresh. A
+    /// failed lookup, cached or not, is `io::ErrorKind::HostUnreachable`.
+    pub async fn resolve(&self, dest_addr: &SocksAddr) -> io::Result<Vec<SocketAddr>> {
+        let SocksAddr::Domain(domain, port) = dest_addr else {
+            return dest_addr.resolved_sorted(IpPref::System).await;
+        };
+        let key = domain.to_ascii_lowercase();
+
+        if let Some(entry) = self.lock().get(&key) {
+            if entry.expires_at > Instant::now() {
+                return match &entry.ips {
+                    Some(ips) => Ok(ips.iter().map(|ip| SocketAddr::new(*ip, *port)).collect()),
+                    None => Err(io::Error::new(
+                        io::ErrorKind::HostUnreachable,
+                        format!("lookup of {} failed recently", domain),
+                    )),
+                };
+            }
+        }
+
+        let result = dest_addr.resolved_sorted(IpPref::System).await;
+
+        let (ips, ttl) = match &result {
+            Ok(addrs) => (Some(addrs.iter().map(SocketAddr::ip).collect()), self.ttl),
+            Err(_) => (None, self.negative_ttl),
+        };
+        if !ttl.is_zero() && self.max_entries > 0 {
+            self.insert(
+                key,
+                Entry {
+                    ips,
+                    expires_at: Instant::now() + ttl,
+                },
+            );
+        }
+
+        result
+    }
+
+    fn insert(&self, key: String, entry: Entry) {
+        let mut entries = self.lock();
+
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            let now = Instant::now();
+            entries.retain(|_, entry| entry.expires_at > now);
+        }
+
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            let closest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires_at)
+                .map(|(key, _)| key.clone());
+            if let Some(closest) = closest {
+                entries.remove(&closest);
+            }
+        }
+
+        entries.insert(key, entry);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Entry>> {
+        // The map stays consistent even if a holder of the lock panicked
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Caches `ips` for `domain` as if a lookup just returned them
+    fn seed(cache: &DnsCache, domain: &str, ips: Vec<IpAddr>) {
+        let entry = Entry {
+            ips: Some(ips),
+            expires_at: Instant::now() + cache.ttl,
+        };
+        cache.insert(domain.to_string(), entry);
+    }
+
+    #[tokio::test]
+    async fn lookups_are_answered_from_the_cache() {
+        let cache = DnsCache::new();
+        // Does not resolve, so only the cache can answer it
+        seed(&cache, "cache.invalid", vec![IpAddr::from([192, 0, 2, 1])]);
+
+        let dest_addr = SocksAddr::Domain("Cache.Invalid".to_string(), 443);
+        let addrs = cache.resolve(&dest_addr).await.unwrap();
+        assert_eq!(addrs, [SocketAddr::from(([192, 0, 2, 1], 443))]);
+    }
+
+    #[tokio::test]
+    async fn failed_lookups_are_cached() {
+        let cache = DnsCache::new();
+        let dest_addr = SocksAddr::Domain("cache.invalid".to_string(), 443);
+
+        assert!(cache.resolve(&dest_addr).await.is_err());
+        assert!(cache.lock()["cache.invalid"].ips.is_none());
+
+        let err = cache.resolve(&dest_addr).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::HostUnreachable);
+    }
+
+    #[test]
+    fn entry_closest_to_expiring_is_evicted() {
+        let cache = DnsCache::new().with_max_entries(2);
+        seed(&cache, "a.invalid", vec![IpAddr::from([192, 0, 2, 1])]);
+        seed(&cache, "b.invalid", vec![IpAddr::from([192, 0, 2, 2])]);
+        seed(&cache, "c.invalid", vec![IpAddr::from([192, 0, 2, 3])]);
+
+        let entries = cache.lock();
+        assert_eq!(entries.len(), 2);
+        assert!(!entries.contains_key("a.invalid"));
+    }
+}
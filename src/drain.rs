@@ -0,0 +1,122 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// Flag shared by a handler and `serve` to drain the server gracefully:
+/// once `start` is called, clients still negotiating get a clean refusal
+/// to their request, `NotAllowed` in SOCKS5 and `Rejected` in SOCKS4, and
+/// tunnels already relaying carry on until they close.
+///
+/// Hand it out through the handlers' `drain_state`, and `serve` starts it
+/// as soon as its shutdown completes, then waits for the connections left.
+/// Unlike hard cancellation, which aborts the tasks serving clients and cuts
+/// handshakes and tunnels mid-stream, every client gets an answer and no
+/// tunnel loses data, so a rolling restart looks like a refusal to clients
+/// caught in the middle. Clones share the same flag.
+#[derive(Clone, Debug, Default)]
+pub struct DrainState {
+    draining: Arc<AtomicBool>,
+}
+
+impl DrainState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Refuses the requests read from now on
+    pub fn start(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether `start` was called, for handlers to also stop work of their
+    /// own
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::{
+        error::SocksError,
+        socks4::{Socks4, Socks4Handler},
+        socks5::{Socks5, Socks5Handler},
+        test_util::tcp_pair,
+    };
+
+    /// Hands out a drain state that already started
+    struct DrainingHandler(DrainState);
+
+    impl DrainingHandler {
+        fn new() -> Self {
+            let drain_state = DrainState::new();
+            drain_state.start();
+            Self(drain_state)
+        }
+    }
+
+    #[async_trait]
+    impl Socks4Handler for DrainingHandler {
+        type Error = SocksError;
+
+        fn drain_state(&self) -> Option<&DrainState> {
+            Some(&self.0)
+        }
+    }
+
+    #[async_trait]
+    impl Socks5Handler for DrainingHandler {
+        type Error = SocksError;
+
+        fn drain_state(&self) -> Option<&DrainState> {
+            Some(&self.0)
+        }
+    }
+
+    #[tokio::test]
+    async fn socks5_requests_are_not_allowed_while_draining() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            DrainingHandler::new(),
+        )
+        .with_strict(true);
+
+        let request = [0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0, 80];
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        client.write_all(&request).await.unwrap();
+        let result = socks5.negotiate(&mut server).await;
+        assert!(matches!(result, Err(SocksError::Draining)));
+
+        let mut reply = [0; 2 + 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..4], [0x05, 0x00, 0x05, 0x02]);
+    }
+
+    #[tokio::test]
+    async fn socks4_requests_are_rejected_while_draining() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks4 = Socks4::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            DrainingHandler::new(),
+        );
+
+        client
+            .write_all(&[0x01, 0, 80, 127, 0, 0, 1, 0])
+            .await
+            .unwrap();
+        let result = socks4.negotiate(&mut server).await;
+        assert!(matches!(result, Err(SocksError::Draining)));
+
+        let mut reply = [0; 8];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [0x00, 91]);
+    }
+}
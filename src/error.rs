@@ -1,4 +1,10 @@
-use crate::socks5::{addr_type::Socks5AddrType, method::Socks5Method};
+use std::{error::Error, fmt, io, net::SocketAddr};
+
+use crate::{
+    addr::SocksAddr,
+    socks4::reply::Socks4Reply,
+    socks5::{addr_type::Socks5AddrType, method::Socks5Method, reply::Socks5Reply},
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum SocksError {
@@ -8,25 +14,190 @@ pub enum SocksError {
     #[error("Unsupported SOCKS version {0}")]
     UnsupportedVersion(u8),
 
-    #[error("Unsupported methods {:?}", self)]
+    #[error("Unsupported methods {0:?}")]
     UnsupportedMethods(Vec<Socks5Method>),
 
+    #[error("Peer {0} is not allowed")]
+    PeerNotAllowed(SocketAddr),
+    #[error("Destination {0:?} is not allowed")]
+    DestinationNotAllowed(SocksAddr),
+
     #[error("Authentication failed")]
     AuthFailed,
 
+    /// The client closed the connection without sending a single byte, as
+    /// health checks of load balancers do
+    #[error("Connection closed before any data was sent")]
+    EmptyConnection,
+
+    #[error("Client disconnected")]
+    ClientDisconnected,
+
+    /// The client sent more than `max_handshake_size` bytes before its
+    /// request was fully read
+    #[error("Handshake exceeds the size limit")]
+    HandshakeTooLarge,
+
+    /// A request read once the server started draining, see
+    /// `drain::DrainState`
+    #[error("Server is draining")]
+    Draining,
+
+    /// A deadline elapsed, `phase` telling which one. SOCKS5 replies
+    /// `TTLExpired` to it by default.
+    #[error("Timed out during {phase}")]
+    Timeout { phase: TimeoutPhase },
+
+    #[error("Cannot connect to identd on the client")]
+    IdentdUnreachable,
+    #[error("Identd reports a different user id {0:?}")]
+    IdentdUserIdMismatch(String),
+
     #[error("Invalid command {0}")]
     InvalidCommand(u8),
-    #[error("Unsupported command {:?}", self)]
+    #[error("Unsupported command {0}")]
     UnsupportedCommand(u8),
 
     #[error("Invalid address type {0}")]
     InvalidAddressType(u8),
-    #[error("Unsupported address {:?}", self)]
+    /// A RSV field that is not zero, such as the one of a UDP request header
+    #[error("Invalid reserved field {0:#x}")]
+    InvalidReserved(u16),
+    #[error("Unsupported address type {0:?}")]
     UnsupportedAddressType(Socks5AddrType),
 
+    #[error("Incomplete data")]
+    Incomplete,
+
+    #[error("Domain name of {0} bytes exceeds the 255 bytes limit")]
+    DomainTooLong(usize),
+
+    #[error("Invalid address {0:?}")]
+    InvalidAddress(String),
+
+    #[error("Invalid ACL rule {0:?}")]
+    InvalidAclRule(String),
+
+    #[error("Invalid method {0:?}")]
+    InvalidMethod(String),
+
+    #[error("Request failed with reply {0:?}")]
+    RequestFailed(Socks5Reply),
+    /// Returned by a SOCKS5 handler to fail the request with this exact
+    /// reply, such as a vendor-specific `Socks5Reply::Unassigned` code,
+    /// rather than the one its error maps to. `Succeeded` replies `Failure`.
+    #[error("Request refused with reply {0:?}")]
+    Reply(Socks5Reply),
+    #[error("SOCKS4 request failed with reply {0:?}")]
+    Socks4RequestFailed(Socks4Reply),
+
     #[error("Converting a UTF-8 bytes to string error. {0}")]
     Utf8BytesToStringError(#[from] std::string::FromUtf8Error),
 
     #[error("Execute error {0}")]
     ExecuteError(String),
 }
+
+/// Part of a session a `SocksError::Timeout` is raised from
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum TimeoutPhase {
+    /// Negotiating the method, authenticating or reading the request
+    Handshake,
+    /// Resolving and dialing the destination of a CONNECT
+    Connect,
+    /// Waiting on a tunnel no data went through
+    Idle,
+    /// Relaying for longer than the session is allowed to last
+    Session,
+    /// Waiting on a single read from one side of a tunnel
+    Read,
+    /// Waiting for one side of a tunnel to accept a single write
+    Write,
+    /// Waiting for the inbound connection of a BIND
+    BindAccept,
+}
+
+impl fmt::Display for TimeoutPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let phase = match self {
+            TimeoutPhase::Handshake => "handshake",
+            TimeoutPhase::Connect => "connect",
+            TimeoutPhase::Idle => "idle",
+            TimeoutPhase::Session => "session",
+            TimeoutPhase::Read => "read",
+            TimeoutPhase::Write => "write",
+            TimeoutPhase::BindAccept => "bind accept",
+        };
+
+        f.write_str(phase)
+    }
+}
+
+impl SocksError {
+    /// Maps an error from writing a reply, telling a client that went away
+    /// apart from genuine I/O failures.
+    pub(crate) fn from_reply_error(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected => Self::ClientDisconnected,
+            _ => Self::StdIoError(err),
+        }
+    }
+
+    /// Whether `err`, or one of its sources, is `ClientDisconnected`
+    pub(crate) fn is_client_disconnected(err: &(dyn Error + 'static)) -> bool {
+        let mut source = Some(err);
+
+        while let Some(err) = source {
+            if let Some(Self::ClientDisconnected) = err.downcast_ref::<Self>() {
+                return true;
+            }
+
+            source = err.source();
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reply_errors_of_a_gone_client_are_disconnections() {
+        let kinds = [
+            io::ErrorKind::BrokenPipe,
+            io::ErrorKind::ConnectionReset,
+            io::ErrorKind::ConnectionAborted,
+            io::ErrorKind::NotConnected,
+        ];
+        for kind in kinds {
+            let err = SocksError::from_reply_error(kind.into());
+            assert!(matches!(err, SocksError::ClientDisconnected), "{:?}", kind);
+            assert!(SocksError::is_client_disconnected(&err));
+        }
+
+        let err = SocksError::from_reply_error(io::ErrorKind::PermissionDenied.into());
+        assert!(matches!(err, SocksError::StdIoError(_)));
+        assert!(!SocksError::is_client_disconnected(&err));
+    }
+
+    #[test]
+    fn unsupported_errors_print_the_offending_value() {
+        let err =
+            SocksError::UnsupportedMethods(vec![Socks5Method::GssApi, Socks5Method::Private(0x80)]);
+        assert_eq!(
+            err.to_string(),
+            "Unsupported methods [GssApi, Private(128)]"
+        );
+
+        let err = SocksError::UnsupportedCommand(0x03);
+        assert_eq!(err.to_string(), "Unsupported command 3");
+
+        let err = SocksError::UnsupportedAddressType(Socks5AddrType::IPV6);
+        assert_eq!(err.to_string(), "Unsupported address type IPV6");
+    }
+}
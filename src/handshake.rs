@@ -0,0 +1,49 @@
+use crate::error::SocksError;
+
+/// Bytes a client may send during the handshake by default, see
+/// `max_handshake_size` on both handlers. Well above what a regular
+/// handshake takes: the largest SOCKS5 one, with 255 methods, 255 byte
+/// credentials and a 255 byte domain, stays just under 1100 bytes.
+pub const DEFAULT_MAX_HANDSHAKE_SIZE: usize = 4096;
+
+/// Bytes left for the client to send before the handshake is too large,
+/// charged after each read of the negotiation
+pub(crate) struct HandshakeBudget {
+    remaining: usize,
+}
+
+impl HandshakeBudget {
+    pub(crate) fn new(max_size: usize) -> Self {
+        Self {
+            remaining: max_size,
+        }
+    }
+
+    /// Fails with `SocksError::HandshakeTooLarge` once more than the budget
+    /// was read
+    pub(crate) fn consume(&mut self, size: usize) -> Result<(), SocksError> {
+        self.remaining = self
+            .remaining
+            .checked_sub(size)
+            .ok_or(SocksError::HandshakeTooLarge)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budget_fails_once_exceeded() {
+        let mut budget = HandshakeBudget::new(10);
+
+        assert!(budget.consume(4).is_ok());
+        assert!(budget.consume(6).is_ok());
+        assert!(matches!(
+            budget.consume(1),
+            Err(SocksError::HandshakeTooLarge)
+        ));
+    }
+}
@@ -1,11 +1,39 @@
+pub mod acl;
 pub mod addr;
+pub mod dial;
+#[cfg(feature = "dns-cache")]
+pub mod dns_cache;
+pub mod drain;
 pub mod error;
+pub mod handshake;
+pub mod listener;
+pub mod permit;
+pub mod pool;
+#[cfg(feature = "proxy-protocol")]
+pub mod proxy_protocol;
+pub mod relay;
+pub mod reply;
+#[cfg(feature = "tower")]
+pub mod service;
 pub mod socks4;
 pub mod socks5;
+pub mod stream;
+#[cfg(feature = "tap")]
+pub mod tap;
+#[cfg(test)]
+mod test_util;
+#[cfg(target_os = "linux")]
+pub mod transparent;
+#[cfg(all(unix, feature = "unix-socket"))]
+pub mod unix_socket;
+
+use std::{future::Future, net::SocketAddr, sync::Arc};
 
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    select,
+    task::JoinSet,
 };
 
 use error::SocksError;
@@ -18,18 +46,91 @@ pub enum Socks<H: Socks4Handler + Socks5Handler + Send + Sync> {
 }
 
 impl<H: Socks4Handler + Socks5Handler + Send + Sync> Socks<H> {
-    pub async fn from_stream(stream: &mut TcpStream, handler: H) -> Result<Self, SocksError> {
-        let version = stream.read_u8().await?;
+    /// Reads the version byte of a new client and picks the protocol. A
+    /// client closing before sending it, such as a health check probe,
+    /// yields `SocksError::EmptyConnection` rather than an I/O error.
+    ///
+    /// An `Arc` handler is shared across connections without being cloned,
+    /// in which case the handler type is named as in
+    /// `Socks::<MyHandler>::from_stream`, since it cannot be inferred from
+    /// the `Arc` alone.
+    pub async fn from_stream(
+        stream: &mut TcpStream,
+        handler: impl Into<Arc<H>>,
+    ) -> Result<Self, SocksError> {
+        Self::detect(stream, handler, |_| {}).await
+    }
+
+    /// Same as `from_stream`, copying every byte read from or written to
+    /// the client into `tap` from the version byte on, to record a session
+    /// when debugging a client: the handshake, the replies and the tunnel.
+    ///
+    /// The default handlers relay a tapped session through the stream
+    /// rather than with splice(2), which would bypass the tap. An
+    /// `on_relay` of its own must do the same to record the tunnel, see
+    /// `ClientStream::is_tapped`.
+    #[cfg(feature = "tap")]
+    pub async fn with_tap<T>(
+        stream: &mut TcpStream,
+        handler: impl Into<Arc<H>>,
+        mut tap: T,
+    ) -> Result<Self, SocksError>
+    where
+        T: tap::Tap + Send + Sync + 'static,
+    {
+        let socks = Self::detect(stream, handler, |data| tap.on_read(data)).await?;
+
+        Ok(match socks {
+            Socks::V4(socks4) => Socks::V4(socks4.with_tap(tap)),
+            Socks::V5(socks5) => Socks::V5(socks5.with_tap(tap)),
+        })
+    }
+
+    /// Reads the version byte and picks the protocol, passing the bytes
+    /// read to `on_read`
+    async fn detect(
+        stream: &mut TcpStream,
+        handler: impl Into<Arc<H>>,
+        on_read: impl FnOnce(&[u8]),
+    ) -> Result<Self, SocksError> {
+        let version = match stream.read_u8().await {
+            Ok(version) => version,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                on_read(&[]);
+                return Err(SocksError::EmptyConnection);
+            }
+            Err(err) => return Err(err.into()),
+        };
+        on_read(&[version]);
         let peer_addr = stream.peer_addr()?;
         let local_addr = stream.local_addr()?;
 
+        match Self::from_version(version, peer_addr, local_addr, handler) {
+            Ok(socks) => Ok(socks),
+            Err(err) => {
+                stream.shutdown().await?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Picks the protocol from a version byte the caller already read, for
+    /// servers that peek at the first byte to run SOCKS next to other
+    /// protocols on one listener. `execute` then continues right after the
+    /// version byte, which must have been consumed from the stream.
+    ///
+    /// A version other than 4 or 5 yields `SocksError::UnsupportedVersion`,
+    /// leaving the connection to the caller.
+    pub fn from_version(
+        version: u8,
+        peer_addr: SocketAddr,
+        local_addr: SocketAddr,
+        handler: impl Into<Arc<H>>,
+    ) -> Result<Self, SocksError> {
         match version {
             0x04 => Ok(Socks::V4(Socks4::new(peer_addr, local_addr, handler))),
             0x05 => Ok(Socks::V5(Socks5::new(peer_addr, local_addr, handler))),
-            v => {
-                stream.shutdown().await?;
-                Err(SocksError::UnsupportedVersion(v))
-            }
+            v => Err(SocksError::UnsupportedVersion(v)),
         }
     }
 
@@ -40,3 +141,227 @@ impl<H: Socks4Handler + Socks5Handler + Send + Sync> Socks<H> {
         }
     }
 }
+
+/// Copies the first bytes the client sent into `buf` without consuming them,
+/// so a server multiplexing protocols on one listener can classify the
+/// connection, such as by a SOCKS version byte of 4 or 5, before handing it
+/// to `Socks::from_stream`, which then reads those bytes again.
+///
+/// Waits until at least one byte arrived and returns how many were copied,
+/// which may be fewer than `buf` holds when the client sent less so far,
+/// and 0 once the client closed without sending anything. Calling it again
+/// returns the same bytes, and more of them if more arrived.
+///
+/// Only works on a `TcpStream`: peeking is not part of `AsyncRead`, so
+/// streams wrapped by TLS or anything else must be classified by reading,
+/// then passing the version byte to `Socks::from_version`.
+pub async fn peek(stream: &TcpStream, buf: &mut [u8]) -> io::Result<usize> {
+    stream.peek(buf).await
+}
+
+/// Accepts clients from `listener` until `shutdown` completes, serving each
+/// one on its own task with the shared `handler`. `Socks::from_stream` and
+/// `Socks::execute` remain available for custom accept loops.
+///
+/// The error a connection ends with is passed to `on_error` along with the
+/// address of its client. That includes `SocksError::EmptyConnection`,
+/// which callers usually leave out of their logs.
+///
+/// Once `shutdown` completes, be it a signal, a `Notify` or a cancellation
+/// token, no new client is accepted and `serve` returns after the
+/// connections in flight are done. The `drain::DrainState` of the handler,
+/// if any, is started then, so those still negotiating are refused instead
+/// of starting new tunnels. Pass `std::future::pending()` to serve forever.
+///
+/// An error accepting a client stops the loop and is returned, leaving the
+/// connections in flight running in the background.
+pub async fn serve<H, E, F>(
+    listener: TcpListener,
+    handler: impl Into<Arc<H>>,
+    on_error: E,
+    shutdown: F,
+) -> io::Result<()>
+where
+    H: Socks4Handler + Socks5Handler + Send + Sync + 'static,
+    <H as Socks4Handler>::Error: Send,
+    <H as Socks5Handler>::Error: Send,
+    E: Fn(SocketAddr, SocksError) + Send + Sync + 'static,
+    F: Future<Output = ()>,
+{
+    let handler = handler.into();
+    let on_error = Arc::new(on_error);
+    let mut tasks = JoinSet::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        select! {
+            _ = &mut shutdown => {
+                if let Some(drain_state) = Socks4Handler::drain_state(&*handler) {
+                    drain_state.start();
+                }
+                if let Some(drain_state) = Socks5Handler::drain_state(&*handler) {
+                    drain_state.start();
+                }
+                break;
+            }
+            // Reap finished connections so the set does not keep growing
+            Some(_) = tasks.join_next(), if !tasks.is_empty() => {}
+            result = listener.accept() => {
+                let (mut stream, peer_addr) = match result {
+                    Ok(val) => val,
+                    Err(err) => {
+                        tasks.detach_all();
+                        return Err(err);
+                    }
+                };
+
+                let handler = handler.clone();
+                let on_error = on_error.clone();
+                tasks.spawn(async move {
+                    let result = match Socks::<H>::from_stream(&mut stream, handler).await {
+                        Ok(mut socks) => socks.execute(&mut stream).await,
+                        Err(err) => Err(err),
+                    };
+
+                    if let Err(err) = result {
+                        on_error(peer_addr, err);
+                    }
+                });
+            }
+        }
+    }
+
+    drop(listener);
+    while tasks.join_next().await.is_some() {}
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "tap")]
+    use std::sync::Mutex;
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::test_util::tcp_pair;
+
+    struct Handler;
+
+    #[async_trait]
+    impl Socks4Handler for Handler {
+        type Error = SocksError;
+    }
+
+    #[async_trait]
+    impl Socks5Handler for Handler {
+        type Error = SocksError;
+    }
+
+    /// Hands out the drain state `serve` starts on shutdown
+    struct DrainHandler(drain::DrainState);
+
+    #[async_trait]
+    impl Socks4Handler for DrainHandler {
+        type Error = SocksError;
+
+        fn drain_state(&self) -> Option<&drain::DrainState> {
+            Some(&self.0)
+        }
+    }
+
+    #[async_trait]
+    impl Socks5Handler for DrainHandler {
+        type Error = SocksError;
+
+        fn drain_state(&self) -> Option<&drain::DrainState> {
+            Some(&self.0)
+        }
+    }
+
+    #[cfg(feature = "tap")]
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<(Vec<u8>, Vec<u8>)>>);
+
+    #[cfg(feature = "tap")]
+    impl tap::Tap for Recorder {
+        fn on_read(&mut self, data: &[u8]) {
+            self.0.lock().unwrap().0.extend(data);
+        }
+
+        fn on_write(&mut self, data: &[u8]) {
+            self.0.lock().unwrap().1.extend(data);
+        }
+    }
+
+    #[tokio::test]
+    async fn closing_before_the_version_is_an_empty_connection() {
+        let (client, mut server) = tcp_pair().await;
+        drop(client);
+
+        let result = Socks::<Handler>::from_stream(&mut server, Handler).await;
+        assert!(matches!(result, Err(SocksError::EmptyConnection)));
+    }
+
+    #[cfg(feature = "tap")]
+    #[tokio::test]
+    async fn tap_records_the_handshake() {
+        let (mut client, mut server) = tcp_pair().await;
+        let recorder = Recorder::default();
+
+        // UDP ASSOCIATE, which the default handler refuses
+        let sent = [5, 1, 0, 5, 3, 0, 1, 0, 0, 0, 0, 0, 0];
+        client.write_all(&sent).await.unwrap();
+        let mut socks = Socks::with_tap(&mut server, Handler, recorder.clone())
+            .await
+            .unwrap();
+        assert!(socks.execute(&mut server).await.is_err());
+        drop(server);
+
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        let (read, written) = recorder.0.lock().unwrap().clone();
+        assert_eq!(read, sent);
+        assert_eq!(written, received);
+        assert_eq!(received[..4], [5, 0, 5, 7]);
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_the_connections_in_flight() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let drain_state = drain::DrainState::new();
+        let (shutdown, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(
+            listener,
+            DrainHandler(drain_state.clone()),
+            |_, _| {},
+            async move {
+                let _ = shutdown_rx.await;
+            },
+        ));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut reply = [0; 2];
+        client.read_exact(&mut reply).await.unwrap();
+
+        shutdown.send(()).unwrap();
+        while !drain_state.is_draining() {
+            tokio::task::yield_now().await;
+        }
+        assert!(!server.is_finished());
+
+        // The connection still negotiating is refused, and serve returns
+        // once it is done
+        let request = [0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0, 80];
+        client.write_all(&request).await.unwrap();
+        let mut reply = [0; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [0x05, 0x02]);
+
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,38 @@
+use tokio::{
+    io,
+    net::{lookup_host, TcpListener, TcpSocket, ToSocketAddrs},
+};
+
+/// Backlog `TcpListener::bind` uses, and the default `bind_backlog`
+pub const DEFAULT_BACKLOG: u32 = 1024;
+
+/// Same as `TcpListener::bind`, with the length of the queue of pending
+/// connections given by `backlog`. Each resolved address is tried in turn
+/// until one binds.
+pub async fn bind_listener<A: ToSocketAddrs>(addr: A, backlog: u32) -> io::Result<TcpListener> {
+    let mut last_err = None;
+
+    for addr in lookup_host(addr).await? {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+
+        // Matches TcpListener::bind, so a restarted server can rebind
+        #[cfg(not(windows))]
+        socket.set_reuseaddr(true)?;
+
+        match socket.bind(addr).and_then(|_| socket.listen(backlog)) {
+            Ok(listener) => return Ok(listener),
+            Err(err) => last_err = Some(err),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any address",
+        )
+    }))
+}
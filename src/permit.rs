@@ -0,0 +1,17 @@
+/// Guard returned by the handlers' `acquire_permit`, held for as long as
+/// the command it was acquired for is being served and dropped afterwards.
+///
+/// It wraps whatever the handler uses to bound concurrency, such as a
+/// semaphore permit, so releasing happens when the guard drops.
+#[derive(Default)]
+pub struct Permit {
+    _guard: Option<Box<dyn Send>>,
+}
+
+impl Permit {
+    pub fn new<G: Send + 'static>(guard: G) -> Self {
+        Self {
+            _guard: Some(Box::new(guard)),
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tokio::{io, net::TcpStream, time};
+
+use crate::{
+    addr::{IpPref, SocksAddr},
+    dial::dial,
+};
+
+/// Outbound connections kept ready per destination, for proxies fronting a
+/// small set of backends. Opt-in: a handler holds one and uses it from its
+/// own `connect`, or returns `resolve` from `Socks5Handler::resolve` to only
+/// cache name resolution in the default `connect`.
+///
+/// A CONNECT tunnel owns its outbound connection from start to end. The
+/// destination sees one client per connection, and whatever state the
+/// tunnel left in the byte stream cannot be reset, so connections are
+/// never taken back once a tunnel is done with them. What the pool saves is
+/// the dialing: connections opened ahead of time with `prewarm` wait idle
+/// until a request for their destination takes one, and resolved addresses
+/// are kept for `with_dns_ttl`. Once `with_max_dns_entries` domains are
+/// cached, expired resolutions are dropped, and then the oldest one, so
+/// memory stays bounded. Destinations that drop idle connections
+/// after a timeout of their own need `with_idle_timeout` under it, and
+/// protocols where the server speaks first get its greeting once the
+/// client takes over, as it arrived while the connection was idle.
+#[derive(Debug)]
+pub struct ConnectPool {
+    entries: Mutex<HashMap<SocksAddr, Entry>>,
+    dns_ttl: Duration,
+    max_dns_entries: usize,
+    max_idle: usize,
+    idle_timeout: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Entry {
+    addrs: Option<(Vec<SocketAddr>, Instant)>,
+    idle: VecDeque<(TcpStream, Instant)>,
+}
+
+impl Default for ConnectPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConnectPool {
+    /// Pool keeping resolutions of up to 1024 domains for 60 seconds, and up
+    /// to 8 idle connections per destination, for 30 seconds each
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            dns_ttl: Duration::from_secs(60),
+            max_dns_entries: 1024,
+            max_idle: 8,
+            idle_timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// How long the addresses a domain resolved to are reused
+    pub fn with_dns_ttl(mut self, dns_ttl: Duration) -> Self {
+        self.dns_ttl = dns_ttl;
+        self
+    }
+
+    /// Most domains whose resolution is kept at once
+    pub fn with_max_dns_entries(mut self, max_dns_entries: usize) -> Self {
+        self.max_dns_entries = max_dns_entries;
+        self
+    }
+
+    /// Most idle connections kept per destination
+    pub fn with_max_idle(mut self, max_idle: usize) -> Self {
+        self.max_idle = max_idle;
+        self
+    }
+
+    /// How long an idle connection waits to be taken before it is closed
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Addresses `dest_addr` resolves to, as `SocksAddr::resolved_sorted`
+    /// with `IpPref::System` returns them, looked up again once the
+    /// previous lookup is older than the DNS TTL. Failed lookups are not
+    /// cached.
+    pub async fn resolve(&self, dest_addr: &SocksAddr) -> io::Result<Vec<SocketAddr>> {
+        if !matches!(dest_addr, SocksAddr::Domain(..)) {
+            return dest_addr.resolved_sorted(IpPref::System).await;
+        }
+
+        if let Some(Entry {
+            addrs: Some((addrs, resolved_at)),
+            ..
+        }) = self.lock().get(dest_addr)
+        {
+            if resolved_at.elapsed() < self.dns_ttl {
+                return Ok(addrs.clone());
+            }
+        }
+
+        let addrs = dest_addr.resolved_sorted(IpPref::System).await?;
+        if !self.dns_ttl.is_zero() && self.max_dns_entries > 0 {
+            self.cache_addrs(dest_addr, addrs.clone());
+        }
+
+        Ok(addrs)
+    }
+
+    fn cache_addrs(&self, dest_addr: &SocksAddr, addrs: Vec<SocketAddr>) {
+        let mut entries = self.lock();
+        let cached = |entries: &HashMap<SocksAddr, Entry>| {
+            entries
+                .values()
+                .filter(|entry| entry.addrs.is_some())
+                .count()
+        };
+        let is_cached = entries
+            .get(dest_addr)
+            .is_some_and(|entry| entry.addrs.is_some());
+
+        if !is_cached && cached(&entries) >= self.max_dns_entries {
+            for entry in entries.values_mut() {
+                let is_expired = entry
+                    .addrs
+                    .as_ref()
+                    .is_some_and(|(_, resolved_at)| resolved_at.elapsed() >= self.dns_ttl);
+                if is_expired {
+                    entry.addrs = None;
+                }
+            }
+        }
+
+        if !is_cached && cached(&entries) >= self.max_dns_entries {
+            let oldest = entries
+                .values_mut()
+                .filter(|entry| entry.addrs.is_some())
+                .min_by_key(|entry| entry.addrs.as_ref().map(|(_, resolved_at)| *resolved_at));
+            if let Some(oldest) = oldest {
+                oldest.addrs = None;
+            }
+        }
+
+        // Destinations left with nothing cached are dropped
+        entries.retain(|_, entry| entry.addrs.is_some() || !entry.idle.is_empty());
+        entries.entry(dest_addr.clone()).or_default().addrs = Some((addrs, Instant::now()));
+    }
+
+    /// Connection to `dest_addr`, an idle one opened by `prewarm` when one
+    /// is still open, or else a new one dialed to its resolved addresses.
+    pub async fn connect(&self, dest_addr: &SocksAddr) -> io::Result<TcpStream> {
+        while let Some(stream) = self.take_idle(dest_addr) {
+            if is_open(&stream).await {
+                return Ok(stream);
+            }
+        }
+
+        dial(&self.resolve(dest_addr).await?).await
+    }
+
+    /// Opens connections to `dest_addr` until `count` of them, at most the
+    /// idle limit, wait idle for `connect`.
+    pub async fn prewarm(&self, dest_addr: &SocksAddr, count: usize) -> io::Result<()> {
+        let count = count.min(self.max_idle);
+
+        while self.idle_count(dest_addr) < count {
+            let stream = dial(&self.resolve(dest_addr).await?).await?;
+
+            let mut entries = self.lock();
+            let entry = entries.entry(dest_addr.clone()).or_default();
+            if entry.idle.len() >= count {
+                break;
+            }
+            entry.idle.push_back((stream, Instant::now()));
+        }
+
+        Ok(())
+    }
+
+    fn take_idle(&self, dest_addr: &SocksAddr) -> Option<TcpStream> {
+        let mut entries = self.lock();
+        let entry = entries.get_mut(dest_addr)?;
+
+        // The oldest connections are the first to expire
+        while let Some((stream, idle_since)) = entry.idle.pop_front() {
+            if idle_since.elapsed() < self.idle_timeout {
+                return Some(stream);
+            }
+        }
+
+        None
+    }
+
+    fn idle_count(&self, dest_addr: &SocksAddr) -> usize {
+        let mut entries = self.lock();
+        let Some(entry) = entries.get_mut(dest_addr) else {
+            return 0;
+        };
+
+        entry
+            .idle
+            .retain(|(_, idle_since)| idle_since.elapsed() < self.idle_timeout);
+        entry.idle.len()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<SocksAddr, Entry>> {
+        // The map stays consistent even if a holder of the lock panicked
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Whether the destination kept an idle connection open. The peek is
+/// polled once: pending means the connection is open with nothing to read,
+/// and data it already sent stays there for the client.
+async fn is_open(stream: &TcpStream) -> bool {
+    match time::timeout(Duration::ZERO, stream.peek(&mut [0; 1])).await {
+        Err(_) => true,
+        Ok(Ok(size)) => size > 0,
+        Ok(Err(_)) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Caches `addrs` for `domain` as if a lookup just returned them
+    fn seed(pool: &ConnectPool, domain: &str, addrs: Vec<SocketAddr>) {
+        pool.lock()
+            .entry(SocksAddr::Domain(domain.to_string(), 80))
+            .or_default()
+            .addrs = Some((addrs, Instant::now()));
+    }
+
+    #[tokio::test]
+    async fn lookups_within_the_ttl_are_cached() {
+        let pool = ConnectPool::new();
+        // Does not resolve, so only the cache can answer it
+        let addrs = vec![SocketAddr::from(([192, 0, 2, 1], 80))];
+        seed(&pool, "pool.invalid", addrs.clone());
+
+        let dest_addr = SocksAddr::Domain("pool.invalid".to_string(), 80);
+        assert_eq!(pool.resolve(&dest_addr).await.unwrap(), addrs);
+    }
+
+    #[tokio::test]
+    async fn lookups_after_the_ttl_ask_the_resolver() {
+        let pool = ConnectPool::new().with_dns_ttl(Duration::ZERO);
+        seed(
+            &pool,
+            "pool.invalid",
+            vec![SocketAddr::from(([192, 0, 2, 1], 80))],
+        );
+
+        let dest_addr = SocksAddr::Domain("pool.invalid".to_string(), 80);
+        assert!(pool.resolve(&dest_addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn oldest_resolution_is_evicted_at_the_limit() {
+        let pool = ConnectPool::new().with_max_dns_entries(1);
+        seed(
+            &pool,
+            "pool.invalid",
+            vec![SocketAddr::from(([192, 0, 2, 1], 80))],
+        );
+
+        let dest_addr = SocksAddr::Domain("localhost".to_string(), 80);
+        pool.resolve(&dest_addr).await.unwrap();
+
+        let entries = pool.lock();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[&dest_addr].addrs.is_some());
+    }
+}
@@ -0,0 +1,195 @@
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+use tokio::{
+    io::{self, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// Version of the HAProxy PROXY protocol header the default `connect` of
+/// both handlers sends ahead of the relayed data, see
+/// `send_proxy_protocol`
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ProxyVersion {
+    /// Human-readable header, such as `PROXY TCP4 1.2.3.4 5.6.7.8 1234 80`
+    V1,
+    /// Binary header
+    V2,
+}
+
+/// Signature starting every version 2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Encodes a PROXY protocol header for a TCP connection from `source` to
+/// `destination`. Both addresses are written in the same family, as the
+/// protocol requires: when only one of them is IPv6, the other is written
+/// as an IPv4-mapped IPv6 address.
+pub fn encode_header(
+    version: ProxyVersion,
+    source: SocketAddr,
+    destination: SocketAddr,
+) -> Vec<u8> {
+    let (source_ip, destination_ip) =
+        match (source.ip().to_canonical(), destination.ip().to_canonical()) {
+            (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+                (IpAddr::V4(source_ip), IpAddr::V4(destination_ip))
+            }
+            (source_ip, destination_ip) => (
+                IpAddr::V6(to_ipv6(source_ip)),
+                IpAddr::V6(to_ipv6(destination_ip)),
+            ),
+        };
+
+    match version {
+        ProxyVersion::V1 => {
+            let protocol = if source_ip.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {} {} {} {} {}\r\n",
+                protocol,
+                source_ip,
+                destination_ip,
+                source.port(),
+                destination.port()
+            )
+            .into_bytes()
+        }
+        ProxyVersion::V2 => {
+            let mut addresses = octets(source_ip);
+            addresses.extend(octets(destination_ip));
+            addresses.extend(source.port().to_be_bytes());
+            addresses.extend(destination.port().to_be_bytes());
+
+            let mut header = V2_SIGNATURE.to_vec();
+            // Version 2 with the PROXY command, then the address family
+            // with the STREAM protocol
+            header.push(0x21);
+            header.push(if source_ip.is_ipv4() { 0x11 } else { 0x21 });
+            header.extend((addresses.len() as u16).to_be_bytes());
+            header.extend(addresses);
+            header
+        }
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn octets(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+/// Writes the PROXY protocol header of a client at `client_addr` on the
+/// outbound connection `remote`, before any relayed data
+pub(crate) async fn write_header(
+    remote: &mut TcpStream,
+    version: ProxyVersion,
+    client_addr: SocketAddr,
+) -> io::Result<()> {
+    let header = encode_header(version, client_addr, remote.peer_addr()?);
+    remote.write_all(&header).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn v4(ip: [u8; 4], port: u16) -> SocketAddr {
+        SocketAddr::from((ip, port))
+    }
+
+    fn v6(ip: &str, port: u16) -> SocketAddr {
+        SocketAddr::new(ip.parse().unwrap(), port)
+    }
+
+    #[test]
+    fn v1_headers_are_text_lines() {
+        let header = encode_header(
+            ProxyVersion::V1,
+            v4([192, 0, 2, 1], 56324),
+            v4([198, 51, 100, 7], 443),
+        );
+        assert_eq!(header, b"PROXY TCP4 192.0.2.1 198.51.100.7 56324 443\r\n");
+
+        let header = encode_header(
+            ProxyVersion::V1,
+            v6("2001:db8::1", 56324),
+            v6("2001:db8::2", 443),
+        );
+        assert_eq!(header, b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n");
+    }
+
+    #[test]
+    fn v2_tcp4_header() {
+        let header = encode_header(
+            ProxyVersion::V2,
+            v4([192, 0, 2, 1], 56324),
+            v4([198, 51, 100, 7], 443),
+        );
+
+        assert_eq!(header[..12], V2_SIGNATURE);
+        assert_eq!(header[12..16], [0x21, 0x11, 0, 12]);
+        assert_eq!(
+            header[16..],
+            [192, 0, 2, 1, 198, 51, 100, 7, 0xdc, 0x04, 0x01, 0xbb]
+        );
+    }
+
+    #[test]
+    fn v2_tcp6_header() {
+        let header = encode_header(
+            ProxyVersion::V2,
+            v6("2001:db8::1", 56324),
+            v6("2001:db8::2", 443),
+        );
+
+        assert_eq!(header[..12], V2_SIGNATURE);
+        assert_eq!(header[12..16], [0x21, 0x21, 0, 36]);
+        assert_eq!(header.len(), 16 + 36);
+        assert_eq!(
+            header[16..32],
+            "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets()
+        );
+        assert_eq!(
+            header[32..48],
+            "2001:db8::2".parse::<Ipv6Addr>().unwrap().octets()
+        );
+        assert_eq!(header[48..], [0xdc, 0x04, 0x01, 0xbb]);
+    }
+
+    #[test]
+    fn mixed_families_are_written_as_mapped_ipv6() {
+        let source = v4([192, 0, 2, 1], 56324);
+        let destination = v6("2001:db8::2", 443);
+
+        let header = encode_header(ProxyVersion::V1, source, destination);
+        assert_eq!(
+            header,
+            b"PROXY TCP6 ::ffff:192.0.2.1 2001:db8::2 56324 443\r\n"
+        );
+
+        let header = encode_header(ProxyVersion::V2, source, destination);
+        assert_eq!(header[12..16], [0x21, 0x21, 0, 36]);
+        assert_eq!(
+            header[16..32],
+            Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped().octets()
+        );
+    }
+
+    #[test]
+    fn mapped_addresses_of_both_ends_are_written_as_ipv4() {
+        let header = encode_header(
+            ProxyVersion::V1,
+            v6("::ffff:192.0.2.1", 56324),
+            v4([198, 51, 100, 7], 443),
+        );
+        assert_eq!(header, b"PROXY TCP4 192.0.2.1 198.51.100.7 56324 443\r\n");
+    }
+}
@@ -0,0 +1,357 @@
+#[cfg(all(feature = "splice", target_os = "linux"))]
+mod splice;
+
+use std::{
+    future::{self, Future},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    select, task_local, time,
+};
+
+use crate::{
+    error::{SocksError, TimeoutPhase},
+    stream::ClientStream,
+};
+
+/// Size of the buffer each direction of `relay` reads into, the one
+/// `io::copy` uses
+const BUF_SIZE: usize = 8 * 1024;
+
+task_local! {
+    /// When the connection being served started negotiating
+    pub(crate) static CONNECTION_STARTED: Instant;
+}
+
+/// Bytes relayed through a tunnel, counted on the client side
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct Transfer {
+    /// Bytes read from the client and forwarded to the remote
+    pub sent: u64,
+    /// Bytes read from the remote and written to the client
+    pub received: u64,
+    /// Lifetime of the connection when relaying ended. The clock starts
+    /// when `Socks4` or `Socks5` starts negotiating, right after the first
+    /// byte read from the client picked the protocol, so it includes the
+    /// handshake and dialing. Relaying outside of a negotiation, as
+    /// `transparent::serve_redirected` or a direct call to `relay` does,
+    /// counts from the start of relaying instead.
+    pub duration: Duration,
+}
+
+/// Copies data in both directions between `client` and `remote` until both
+/// sides reach EOF.
+///
+/// With `half_close`, an EOF from one side is forwarded as a shutdown of the
+/// other side's write half while data keeps flowing in the opposite
+/// direction, which protocols that close one direction early rely on.
+/// Without it, the tunnel is closed as soon as either side reaches EOF.
+///
+/// With `max_session_duration` set, the tunnel is closed once it has been
+/// open for that long, whether or not data is still flowing, and the bytes
+/// relayed so far are returned.
+///
+/// With `read_timeout` or `write_timeout` set, a single read from either
+/// side, or a single write to either side, that does not complete in time
+/// closes both ends and fails with an `io::ErrorKind::TimedOut` error
+/// carrying a `SocksError::Timeout`.
+pub async fn relay<C, R>(
+    client: &mut C,
+    remote: &mut R,
+    half_close: bool,
+    max_session_duration: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+) -> Result<Transfer, io::Error>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    R: AsyncRead + AsyncWrite + Unpin,
+{
+    let started = Instant::now();
+    let mut transfer = Transfer::default();
+    let Transfer { sent, received, .. } = &mut transfer;
+
+    let copy = async {
+        let (mut client_reader, mut client_writer) = io::split(&mut *client);
+        let (mut remote_reader, mut remote_writer) = io::split(&mut *remote);
+
+        let upload = copy(
+            &mut client_reader,
+            &mut remote_writer,
+            sent,
+            read_timeout,
+            write_timeout,
+        );
+        let download = copy(
+            &mut remote_reader,
+            &mut client_writer,
+            received,
+            read_timeout,
+            write_timeout,
+        );
+
+        if half_close {
+            tokio::try_join!(upload, download)?;
+        } else {
+            select! {
+                result = upload => result?,
+                result = download => result?,
+            };
+        }
+
+        Ok::<_, io::Error>(())
+    };
+
+    let result = match max_session_duration {
+        Some(duration) => time::timeout(duration, copy).await,
+        None => Ok(copy.await),
+    };
+
+    match result {
+        Ok(Err(err)) if err.kind() != io::ErrorKind::TimedOut => return Err(err),
+        result => {
+            // Shutting down the write halves ends the tunnel when it is over
+            // before both sides closed. Either side may already be gone
+            let _ = client.shutdown().await;
+            let _ = remote.shutdown().await;
+            result.unwrap_or(Ok(()))?;
+        }
+    }
+
+    transfer.duration = connection_duration(started);
+    Ok(transfer)
+}
+
+/// `relay` between two TCP streams, which the default CONNECT and BIND
+/// tunnels use. With the `splice` feature on Linux, the data moves between
+/// the sockets with splice(2) and never gets copied to userspace; anywhere
+/// else this is `relay`.
+pub async fn relay_tcp(
+    client: &mut TcpStream,
+    remote: &mut TcpStream,
+    half_close: bool,
+    max_session_duration: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+) -> Result<Transfer, io::Error> {
+    #[cfg(all(feature = "splice", target_os = "linux"))]
+    return splice::relay(
+        client,
+        remote,
+        half_close,
+        max_session_duration,
+        read_timeout,
+        write_timeout,
+    )
+    .await;
+
+    #[cfg(not(all(feature = "splice", target_os = "linux")))]
+    relay(
+        client,
+        remote,
+        half_close,
+        max_session_duration,
+        read_timeout,
+        write_timeout,
+    )
+    .await
+}
+
+/// `relay_tcp` from the client of a session, flushing the frames it still
+/// holds first. A tapped client is relayed through the `ClientStream` with
+/// `relay` instead, so the tap records the tunnel too.
+pub(crate) async fn relay_client(
+    client: &mut ClientStream<'_>,
+    remote: &mut TcpStream,
+    half_close: bool,
+    max_session_duration: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+) -> Result<Transfer, io::Error> {
+    if client.is_tapped() {
+        return relay(
+            client,
+            remote,
+            half_close,
+            max_session_duration,
+            read_timeout,
+            write_timeout,
+        )
+        .await;
+    }
+
+    client.flush().await?;
+    relay_tcp(
+        client.get_mut(),
+        remote,
+        half_close,
+        max_session_duration,
+        read_timeout,
+        write_timeout,
+    )
+    .await
+}
+
+/// Moves data from `reader` to `writer` until `reader` reaches EOF, then
+/// forwards the EOF as a shutdown of `writer`.
+async fn copy<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    copied: &mut u64,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+) -> Result<(), io::Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; BUF_SIZE];
+
+    loop {
+        let size = within(read_timeout, TimeoutPhase::Read, reader.read(&mut buf)).await?;
+        if size == 0 {
+            return within(write_timeout, TimeoutPhase::Write, writer.shutdown()).await;
+        }
+
+        within(write_timeout, TimeoutPhase::Write, async {
+            writer.write_all(&buf[..size]).await?;
+            writer.flush().await
+        })
+        .await?;
+        *copied += size as u64;
+    }
+}
+
+/// Time since the connection started negotiating, or since `relay_started`
+/// outside of a negotiation
+fn connection_duration(relay_started: Instant) -> Duration {
+    CONNECTION_STARTED
+        .try_with(Instant::elapsed)
+        .unwrap_or_else(|_| relay_started.elapsed())
+}
+
+/// Awaits `io` for at most `timeout`, failing with a `SocksError::Timeout`
+/// for `phase` once it elapses
+async fn within<T>(
+    timeout: Option<Duration>,
+    phase: TimeoutPhase,
+    io: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    match timeout {
+        Some(timeout) => time::timeout(timeout, io)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, SocksError::Timeout { phase }))?,
+        None => io.await,
+    }
+}
+
+/// Completes once the client closed its side of the connection. A client
+/// that already sent more data cannot be watched without consuming it, so
+/// the future never completes then.
+pub(crate) async fn client_closed(stream: &TcpStream) -> io::Result<()> {
+    if stream.peek(&mut [0; 1]).await? == 0 {
+        return Ok(());
+    }
+
+    future::pending().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Closes the client's write half after a request, then answers it from
+    /// the remote and returns what the client received
+    async fn answer_after_client_eof(half_close: bool) -> Vec<u8> {
+        let (mut client, mut client_end) = io::duplex(1024);
+        let (mut remote_end, mut remote) = io::duplex(1024);
+        let session = tokio::spawn(async move {
+            relay(
+                &mut client_end,
+                &mut remote_end,
+                half_close,
+                None,
+                None,
+                None,
+            )
+            .await
+        });
+
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut request = Vec::new();
+        remote.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"request");
+
+        // The relay may be gone already, and the write fail, without it
+        let _ = remote.write_all(b"response").await;
+        let _ = remote.shutdown().await;
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        session.await.unwrap().unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn half_close_keeps_the_other_direction_open() {
+        assert_eq!(answer_after_client_eof(true).await, b"response");
+    }
+
+    #[tokio::test]
+    async fn eof_closes_both_directions_without_half_close() {
+        assert_eq!(answer_after_client_eof(false).await, b"");
+    }
+
+    /// The `SocksError::Timeout` phase `err` carries
+    fn timeout_phase(err: &io::Error) -> Option<TimeoutPhase> {
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        match err.get_ref()?.downcast_ref()? {
+            SocksError::Timeout { phase } => Some(*phase),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn stalled_read_times_out() {
+        let (_client, mut client_end) = io::duplex(1024);
+        let (mut remote_end, _remote) = io::duplex(1024);
+
+        let read_timeout = Some(Duration::from_millis(50));
+        let err = relay(
+            &mut client_end,
+            &mut remote_end,
+            true,
+            None,
+            read_timeout,
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(timeout_phase(&err), Some(TimeoutPhase::Read));
+    }
+
+    #[tokio::test]
+    async fn write_to_a_peer_that_never_reads_times_out() {
+        let (mut client, mut client_end) = io::duplex(1024);
+        let (mut remote_end, _remote) = io::duplex(16);
+        client.write_all(&[0; 1024]).await.unwrap();
+
+        let write_timeout = Some(Duration::from_millis(50));
+        let err = relay(
+            &mut client_end,
+            &mut remote_end,
+            true,
+            None,
+            None,
+            write_timeout,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(timeout_phase(&err), Some(TimeoutPhase::Write));
+    }
+}
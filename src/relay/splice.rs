@@ -0,0 +1,228 @@
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr,
+    time::{Duration, Instant},
+};
+
+use tokio::{io::Interest, net::TcpStream, select, time};
+
+use super::{connection_duration, within, Transfer};
+use crate::error::TimeoutPhase;
+
+/// Bytes moved by a single splice(2) call, the default capacity of a pipe
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// `relay` between two TCP sockets with splice(2). Each direction moves
+/// data from the reading socket into a pipe and from the pipe into the
+/// writing socket, so it never gets copied to userspace.
+pub(super) async fn relay(
+    client: &mut TcpStream,
+    remote: &mut TcpStream,
+    half_close: bool,
+    max_session_duration: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+) -> Result<Transfer, io::Error> {
+    let client_to_remote = Pipe::new()?;
+    let remote_to_client = Pipe::new()?;
+
+    let started = Instant::now();
+    let mut transfer = Transfer::default();
+    let Transfer { sent, received, .. } = &mut transfer;
+
+    let copy = async {
+        let upload = copy(
+            client,
+            remote,
+            &client_to_remote,
+            sent,
+            read_timeout,
+            write_timeout,
+        );
+        let download = copy(
+            remote,
+            client,
+            &remote_to_client,
+            received,
+            read_timeout,
+            write_timeout,
+        );
+
+        if half_close {
+            tokio::try_join!(upload, download)?;
+        } else {
+            select! {
+                result = upload => result?,
+                result = download => result?,
+            }
+        }
+
+        Ok::<_, io::Error>(())
+    };
+
+    let result = match max_session_duration {
+        Some(duration) => time::timeout(duration, copy).await,
+        None => Ok(copy.await),
+    };
+
+    match result {
+        Ok(Err(err)) if err.kind() != io::ErrorKind::TimedOut => return Err(err),
+        result => {
+            // Shutting down the write halves ends the tunnel when it is over
+            // before both sides closed. Either side may already be gone
+            let _ = shutdown_write(client);
+            let _ = shutdown_write(remote);
+            result.unwrap_or(Ok(()))?;
+        }
+    }
+
+    transfer.duration = connection_duration(started);
+    Ok(transfer)
+}
+
+/// Moves data from `reader` to `writer` until `reader` reaches EOF, then
+/// forwards the EOF as a shutdown of the write half of `writer`.
+async fn copy(
+    reader: &TcpStream,
+    writer: &TcpStream,
+    pipe: &Pipe,
+    copied: &mut u64,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+) -> Result<(), io::Error> {
+    loop {
+        // The pipe is drained after every chunk, so only the socket can
+        // keep splice from making progress
+        let read = async {
+            loop {
+                reader.readable().await?;
+                match reader.try_io(Interest::READABLE, || {
+                    splice(reader.as_raw_fd(), pipe.write.as_raw_fd(), CHUNK_SIZE)
+                }) {
+                    Ok(size) => return Ok(size),
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                    Err(err) => return Err(err),
+                }
+            }
+        };
+        let size = within(read_timeout, TimeoutPhase::Read, read).await?;
+
+        if size == 0 {
+            return shutdown_write(writer);
+        }
+
+        let write = async {
+            let mut remaining = size;
+            while remaining > 0 {
+                writer.writable().await?;
+                // A short splice leaves the socket writable, so it is retried
+                // right away and readiness only cleared once it would block
+                let mut moved = 0;
+                let result = writer.try_io(Interest::WRITABLE, || {
+                    while moved < remaining {
+                        moved +=
+                            splice(pipe.read.as_raw_fd(), writer.as_raw_fd(), remaining - moved)?;
+                    }
+                    Ok(())
+                });
+                remaining -= moved;
+                *copied += moved as u64;
+
+                match result {
+                    Ok(()) => {}
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                    Err(err) => return Err(err),
+                }
+            }
+
+            Ok(())
+        };
+        within(write_timeout, TimeoutPhase::Write, write).await?;
+    }
+}
+
+fn splice(fd_in: RawFd, fd_out: RawFd, len: usize) -> io::Result<usize> {
+    let size = unsafe {
+        libc::splice(
+            fd_in,
+            ptr::null_mut(),
+            fd_out,
+            ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(size as usize)
+}
+
+/// `AsyncWriteExt::shutdown` needs the stream mutably, which both
+/// directions share
+fn shutdown_write(stream: &TcpStream) -> io::Result<()> {
+    if unsafe { libc::shutdown(stream.as_raw_fd(), libc::SHUT_WR) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // The pipe was just created and is owned by nothing else
+        unsafe {
+            Ok(Self {
+                read: OwnedFd::from_raw_fd(fds[0]),
+                write: OwnedFd::from_raw_fd(fds[1]),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::test_util::tcp_pair;
+
+    #[tokio::test]
+    async fn data_larger_than_the_socket_buffers_is_relayed() {
+        let (mut client, mut client_end) = tcp_pair().await;
+        let (mut remote_end, mut remote) = tcp_pair().await;
+        let session = tokio::spawn(async move {
+            relay(&mut client_end, &mut remote_end, true, None, None, None).await
+        });
+
+        let data: Vec<u8> = (0..8 * 1024 * 1024).map(|i| i as u8).collect();
+        let upload = async {
+            client.write_all(&data).await.unwrap();
+            client.shutdown().await.unwrap();
+        };
+        let download = async {
+            let mut received = Vec::new();
+            remote.read_to_end(&mut received).await.unwrap();
+            received
+        };
+        let ((), received) = tokio::join!(upload, download);
+        assert!(received == data);
+
+        drop(remote);
+        let transfer = session.await.unwrap().unwrap();
+        assert_eq!(transfer.sent, data.len() as u64);
+    }
+}
@@ -0,0 +1,50 @@
+use std::net::SocketAddr;
+
+use async_trait::async_trait;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+
+use crate::{socks4::reply::Socks4Reply, socks5::reply::Socks5Reply};
+
+/// Reply of either protocol version, for code that answers requests
+/// without knowing which version the client speaks. Each implementation
+/// forwards to the `reply` method of the type.
+#[async_trait]
+pub trait SocksReply {
+    async fn reply<S>(&self, stream: &mut S, bind_addr: SocketAddr) -> Result<(), io::Error>
+    where
+        S: AsyncReadExt + AsyncWriteExt + Unpin + Send;
+}
+
+#[async_trait]
+impl SocksReply for Socks4Reply {
+    async fn reply<S>(&self, stream: &mut S, bind_addr: SocketAddr) -> Result<(), io::Error>
+    where
+        S: AsyncReadExt + AsyncWriteExt + Unpin + Send,
+    {
+        Socks4Reply::reply(self, stream, bind_addr).await
+    }
+}
+
+#[async_trait]
+impl SocksReply for Socks5Reply {
+    async fn reply<S>(&self, stream: &mut S, bind_addr: SocketAddr) -> Result<(), io::Error>
+    where
+        S: AsyncReadExt + AsyncWriteExt + Unpin + Send,
+    {
+        Socks5Reply::reply(self, stream, bind_addr).await
+    }
+}
+
+/// Address a success reply carries for a socket bound to `bound_addr`:
+/// `advertised_addr` when the handler sets one, with a zero port replaced
+/// by the port of the socket, otherwise `bound_addr` itself
+pub(crate) fn advertised(
+    advertised_addr: Option<SocketAddr>,
+    bound_addr: SocketAddr,
+) -> SocketAddr {
+    match advertised_addr {
+        Some(addr) if addr.port() == 0 => SocketAddr::new(addr.ip(), bound_addr.port()),
+        Some(addr) => addr,
+        None => bound_addr,
+    }
+}
@@ -0,0 +1,85 @@
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use tokio::net::TcpStream;
+use tower_service::Service;
+
+use crate::{error::SocksError, socks4::Socks4Handler, socks5::Socks5Handler, Socks};
+
+/// `tower_service::Service` serving one accepted client per call with a
+/// shared handler, as `serve` does, so tower middleware can be layered in
+/// front of the handler. The future resolves once the client is served,
+/// with the error the connection ended with.
+///
+/// The service is always ready, so an accept loop drives it by waiting for
+/// the middleware to be ready, calling it with the accepted stream and
+/// spawning the returned future. Layers that make sense in front of it:
+///
+/// - `ConcurrencyLimit` bounds the number of clients served at once,
+///   holding off the accept loop while at the limit. Per-request limits
+///   are better left to `acquire_permit`, which answers with a proper reply.
+/// - `LoadShed` drops clients, closing their connection without a reply,
+///   instead of holding off the accept loop when a limit is reached.
+/// - `RateLimit` spreads out the clients accepted over time.
+/// - `Timeout` caps the whole connection, relaying included, much like
+///   `max_session_duration`. The handshake and connect timeouts of the
+///   handlers are finer grained.
+///
+/// Layers that replay requests, such as `Retry`, do not apply, as an
+/// accepted stream cannot be served twice.
+pub struct SocksService<H> {
+    handler: Arc<H>,
+}
+
+impl<H> SocksService<H> {
+    pub fn new(handler: impl Into<Arc<H>>) -> Self {
+        Self {
+            handler: handler.into(),
+        }
+    }
+}
+
+// Implemented by hand, as deriving would require the handler to be `Clone`
+// and `Debug` while only the `Arc` around it is cloned
+impl<H> Clone for SocksService<H> {
+    fn clone(&self) -> Self {
+        Self {
+            handler: self.handler.clone(),
+        }
+    }
+}
+
+impl<H> fmt::Debug for SocksService<H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SocksService").finish_non_exhaustive()
+    }
+}
+
+impl<H> Service<TcpStream> for SocksService<H>
+where
+    H: Socks4Handler + Socks5Handler + Send + Sync + 'static,
+    <H as Socks4Handler>::Error: Send,
+    <H as Socks5Handler>::Error: Send,
+{
+    type Response = ();
+    type Error = SocksError;
+    type Future = Pin<Box<dyn Future<Output = Result<(), SocksError>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut stream: TcpStream) -> Self::Future {
+        let handler = self.handler.clone();
+
+        Box::pin(async move {
+            let mut socks = Socks::<H>::from_stream(&mut stream, handler).await?;
+            socks.execute(&mut stream).await
+        })
+    }
+}
@@ -0,0 +1,74 @@
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::error::SocksError;
+
+use super::reply::Socks4Reply;
+
+/// Client side of the SOCKS4 protocol, reading the reply a SOCKS4 server
+/// sends to a request, the counterpart of `Socks4Reply::reply`:
+///
+/// ```text
+/// +----+----+----+----+----+----+----+----+
+/// | VN | CD | DSTPORT |      DSTIP        |
+/// +----+----+----+----+----+----+----+----+
+///   1    1      2              4
+/// ```
+///
+/// VN must be 0. A CD other than `Granted` yields
+/// `SocksError::Socks4RequestFailed` with the rejection code, and unknown
+/// codes are read as `Rejected`. Otherwise the address of the reply is
+/// returned.
+pub async fn read_reply<S>(stream: &mut S) -> Result<SocketAddrV4, SocksError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = [0; 8];
+    stream.read_exact(&mut buf).await?;
+    let [version, reply, port @ .., a, b, c, d] = buf;
+
+    if version != 0x00 {
+        return Err(SocksError::UnsupportedVersion(version));
+    }
+
+    let reply = Socks4Reply::from(reply);
+    if reply != Socks4Reply::Granted {
+        return Err(SocksError::Socks4RequestFailed(reply));
+    }
+
+    Ok(SocketAddrV4::new(
+        Ipv4Addr::new(a, b, c, d),
+        u16::from_be_bytes(port),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn granted_reply_returns_its_address() {
+        let mut stream: &[u8] = &[0x00, 0x5a, 0x04, 0x38, 192, 0, 2, 1];
+
+        let addr = read_reply(&mut stream).await.unwrap();
+        assert_eq!(addr, SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 1080));
+    }
+
+    #[tokio::test]
+    async fn other_replies_fail() {
+        let mut stream: &[u8] = &[0x00, 0x5c, 0, 0, 0, 0, 0, 0];
+        assert!(matches!(
+            read_reply(&mut stream).await,
+            Err(SocksError::Socks4RequestFailed(
+                Socks4Reply::RejectedByCannotConnectIdentd
+            ))
+        ));
+
+        let mut stream: &[u8] = &[0x04, 0x5a, 0, 0, 0, 0, 0, 0];
+        assert!(matches!(
+            read_reply(&mut stream).await,
+            Err(SocksError::UnsupportedVersion(0x04))
+        ));
+    }
+}
@@ -19,11 +19,11 @@ impl TryFrom<u8> for Socks4Command {
     }
 }
 
-impl Into<u8> for Socks4Command {
-    fn into(self) -> u8 {
-        match self {
-            Self::Connect => 0x01,
-            Self::Bind => 0x02,
+impl From<Socks4Command> for u8 {
+    fn from(value: Socks4Command) -> Self {
+        match value {
+            Socks4Command::Connect => 0x01,
+            Socks4Command::Bind => 0x02,
         }
     }
 }
@@ -0,0 +1,218 @@
+use std::{collections::HashSet, net::SocketAddr, time::Duration};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time,
+};
+
+use crate::error::SocksError;
+
+/// Longest response read from identd, the 1000 characters RFC 1413 allows
+const MAX_RESPONSE_SIZE: usize = 1000;
+
+/// Identity check for SOCKS4 clients, meant to be called from
+/// `Socks4Handler::identd`.
+///
+/// The USERID of the request can be matched against an allowlist, and
+/// optionally verified with an identification protocol (RFC 1413) query to
+/// port 113 of the client. When the query cannot be made the check fails
+/// with `SocksError::IdentdUnreachable`, and when identd reports another
+/// user with `SocksError::IdentdUserIdMismatch`, which reply
+/// `RejectedByCannotConnectIdentd` and `RejectedByIdentdReportDifferentUserIds`.
+#[derive(Clone, Debug)]
+pub struct Identd {
+    allowed_user_ids: Option<HashSet<String>>,
+    lookup: bool,
+    timeout: Duration,
+}
+
+impl Default for Identd {
+    fn default() -> Self {
+        Self {
+            allowed_user_ids: None,
+            lookup: false,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Identd {
+    pub const PORT: u16 = 113;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only accept these user ids. Every user id is accepted by default.
+    pub fn with_allowed_user_ids<I, S>(mut self, user_ids: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.allowed_user_ids = Some(user_ids.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Verify the user id with the identd of the client. Disabled by default.
+    pub fn with_lookup(mut self, lookup: bool) -> Self {
+        self.lookup = lookup;
+        self
+    }
+
+    /// Time allowed for the whole identd query, 10 seconds by default
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub async fn verify(
+        &self,
+        user_id: &str,
+        peer_addr: &SocketAddr,
+        local_addr: &SocketAddr,
+    ) -> Result<bool, SocksError> {
+        if let Some(allowed_user_ids) = &self.allowed_user_ids {
+            if !allowed_user_ids.contains(user_id) {
+                return Ok(false);
+            }
+        }
+
+        if !self.lookup {
+            return Ok(true);
+        }
+
+        let reported_user_id = time::timeout(self.timeout, self.query(peer_addr, local_addr))
+            .await
+            .map_err(|_| SocksError::IdentdUnreachable)??;
+
+        if reported_user_id != user_id {
+            return Err(SocksError::IdentdUserIdMismatch(reported_user_id));
+        }
+
+        Ok(true)
+    }
+
+    /// Asks the identd of the client who owns the connection:
+    ///
+    /// ```text
+    /// <port-on-client> , <port-on-server>
+    /// <port-on-client> , <port-on-server> : USERID : <opsys> : <user-id>
+    /// <port-on-client> , <port-on-server> : ERROR : <error-type>
+    /// ```
+    ///
+    /// An ERROR response means identd does not vouch for any user, which
+    /// is reported as an empty user id.
+    async fn query(
+        &self,
+        peer_addr: &SocketAddr,
+        local_addr: &SocketAddr,
+    ) -> Result<String, SocksError> {
+        let mut stream = TcpStream::connect((peer_addr.ip(), Self::PORT))
+            .await
+            .map_err(|_| SocksError::IdentdUnreachable)?;
+
+        let query = format!("{} , {}\r\n", peer_addr.port(), local_addr.port());
+        stream
+            .write_all(query.as_bytes())
+            .await
+            .map_err(|_| SocksError::IdentdUnreachable)?;
+
+        let response = read_response(stream).await?;
+        parse_response(&response)
+    }
+}
+
+/// Reads the response line of identd, the client controlling how long it
+/// is, failing once it exceeds `MAX_RESPONSE_SIZE`
+async fn read_response<R: AsyncRead + Unpin>(reader: R) -> Result<String, SocksError> {
+    let mut response = String::new();
+    BufReader::new(reader.take(MAX_RESPONSE_SIZE as u64))
+        .read_line(&mut response)
+        .await
+        .map_err(|_| SocksError::IdentdUnreachable)?;
+
+    if response.len() == MAX_RESPONSE_SIZE && !response.ends_with('\n') {
+        return Err(SocksError::IdentdUnreachable);
+    }
+
+    Ok(response)
+}
+
+/// User id an identd response vouches for, empty for an ERROR response
+fn parse_response(response: &str) -> Result<String, SocksError> {
+    let fields: Vec<&str> = response.trim_end().splitn(4, ':').collect();
+
+    match fields.get(1).map(|field| field.trim()) {
+        Some("USERID") => fields
+            .get(3)
+            .map(|user_id| user_id.trim().to_string())
+            .ok_or(SocksError::IdentdUnreachable),
+        Some("ERROR") => Ok(String::new()),
+        _ => Err(SocksError::IdentdUnreachable),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn userid_responses_report_the_user_id() {
+        let user_id = parse_response("6193, 23 : USERID : UNIX : stjohns\r\n").unwrap();
+        assert_eq!(user_id, "stjohns");
+
+        // Only the first three colons separate fields
+        let user_id = parse_response("6193,23:USERID:UNIX:a:b").unwrap();
+        assert_eq!(user_id, "a:b");
+    }
+
+    #[test]
+    fn error_responses_report_no_user_id() {
+        let user_id = parse_response("6195, 23 : ERROR : NO-USER\r\n").unwrap();
+        assert_eq!(user_id, "");
+    }
+
+    #[test]
+    fn malformed_responses_fail() {
+        for response in [
+            "",
+            "garbage\r\n",
+            "6193, 23 : USERID : UNIX\r\n",
+            "6193, 23 : OK : x",
+        ] {
+            assert!(
+                matches!(parse_response(response), Err(SocksError::IdentdUnreachable)),
+                "{response:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn responses_are_read_up_to_the_size_limit() {
+        let response = format!("6193, 23 : USERID : UNIX : {}\r\n", "a".repeat(900));
+        assert_eq!(read_response(response.as_bytes()).await.unwrap(), response);
+
+        let response = format!("6193, 23 : USERID : UNIX : {}\r\n", "a".repeat(1000));
+        assert!(matches!(
+            read_response(response.as_bytes()).await,
+            Err(SocksError::IdentdUnreachable)
+        ));
+    }
+
+    #[tokio::test]
+    async fn user_ids_outside_the_allowlist_are_rejected() {
+        let identd = Identd::new().with_allowed_user_ids(["alice"]);
+        let peer_addr = SocketAddr::from(([127, 0, 0, 1], 6193));
+        let local_addr = SocketAddr::from(([127, 0, 0, 1], 1080));
+
+        assert!(!identd
+            .verify("mallory", &peer_addr, &local_addr)
+            .await
+            .unwrap());
+        assert!(identd
+            .verify("alice", &peer_addr, &local_addr)
+            .await
+            .unwrap());
+    }
+}
@@ -1,83 +1,370 @@
+pub mod client;
 pub mod command;
+pub mod identd;
 pub mod reply;
 
 use std::{
     error::Error,
+    fmt,
     net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use async_trait::async_trait;
 use tokio::{
     io::{self, AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    net::TcpStream,
+    select, time,
 };
 
-use crate::{addr::SocksAddr, error::SocksError};
+#[cfg(feature = "proxy-protocol")]
+use crate::proxy_protocol::{self, ProxyVersion};
+#[cfg(feature = "tap")]
+use crate::tap::Tap;
+use crate::{
+    addr::{IpPref, SocksAddr},
+    dial::dial_from,
+    drain::DrainState,
+    error::{SocksError, TimeoutPhase},
+    handshake::{HandshakeBudget, DEFAULT_MAX_HANDSHAKE_SIZE},
+    listener::{bind_listener, DEFAULT_BACKLOG},
+    permit::Permit,
+    relay::{client_closed, relay_client, Transfer, CONNECTION_STARTED},
+    reply::advertised,
+    stream::{ClientStream, ReplyFraming},
+};
 
 use command::Socks4Command;
 use reply::Socks4Reply;
 
 #[async_trait]
 pub trait Socks4Handler {
-    type Error: From<SocksError> + From<io::Error> + Error;
+    type Error: From<SocksError> + From<io::Error> + Error + 'static;
+
+    /// Called before anything is read from a new client, to drop unwanted
+    /// peers cheaply. A denied connection is closed without any reply.
+    /// Allows every peer by default.
+    #[allow(unused_variables)]
+    async fn allow_peer(&self, peer_addr: &SocketAddr) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
 
     #[allow(unused_variables)]
     async fn allow_command(&self, command: &Socks4Command) -> Result<bool, Self::Error> {
         Ok(true)
     }
 
+    /// Drain state checked before each request is served. Once it started,
+    /// requests are `Rejected` with `SocksError::Draining` before
+    /// `allow_command` is called. `serve` starts it when its shutdown
+    /// completes. None by default, which never drains.
+    fn drain_state(&self) -> Option<&DrainState> {
+        None
+    }
+
+    /// Most bytes a client may send in its request, after the version
+    /// number. USERID and the SOCKS4a domain are read up to their NULL, so
+    /// this is what bounds them. A client going over fails with
+    /// `SocksError::HandshakeTooLarge`, which replies `Rejected`. Defaults
+    /// to `handshake::DEFAULT_MAX_HANDSHAKE_SIZE`.
+    fn max_handshake_size(&self) -> usize {
+        DEFAULT_MAX_HANDSHAKE_SIZE
+    }
+
+    /// Whether requests for `0.0.0.x` with x non-zero are read as SOCKS4a,
+    /// with the destination domain following the USERID. SOCKS4 alone
+    /// cannot tell such a request from one for that literal IP, which
+    /// disabling the extension connects to instead, without reading a
+    /// domain. Enabled by default.
+    fn socks4a_enabled(&self) -> bool {
+        true
+    }
+
+    /// Whether trailing control characters, such as a `\r` or `\n`, are
+    /// trimmed from the USERID before `identd` checks it. Some legacy
+    /// clients read the user name from a line of input and send its line
+    /// ending along, which would never match an allowlist or an RFC 1413
+    /// answer. Disabled by default, the USERID is then checked as sent.
+    fn trim_user_id(&self) -> bool {
+        false
+    }
+
+    /// Checks the USERID of the request, `local_addr` being the server side
+    /// of the connection. Returning `false` replies `Rejected`; see
+    /// [`identd::Identd`] for allowlists and RFC 1413 verification, whose
+    /// errors reply with the matching identd rejection codes. Accepts every
+    /// client by default.
     #[allow(unused_variables)]
-    async fn identd(&self, user_id: &str, peer_addr: &SocketAddr) -> Result<bool, Self::Error> {
+    async fn identd(
+        &self,
+        user_id: &str,
+        peer_addr: &SocketAddr,
+        local_addr: &SocketAddr,
+    ) -> Result<bool, Self::Error> {
         Ok(true)
     }
 
+    /// Called once a request is accepted and before it is served, to bound
+    /// the number of concurrent tunnels. The returned `Permit` is held until
+    /// the command finishes. Returning an error rejects the request with a
+    /// `Rejected` reply. Never limits by default.
+    async fn acquire_permit(&self) -> Result<Permit, Self::Error> {
+        Ok(Permit::default())
+    }
+
+    /// Whether the tunnel forwards a half-close. An EOF from one side is then
+    /// forwarded as a shutdown of the other side's write half, while data
+    /// keeps flowing in the opposite direction until it reaches EOF too.
+    /// Enabled by default; disable it to close the tunnel as soon as either
+    /// side reaches EOF, for peers that leave half-open connections behind.
+    fn half_close(&self) -> bool {
+        true
+    }
+
+    /// Hard cap on the lifetime of a CONNECT or BIND tunnel. Once it elapses
+    /// both ends are closed, even if data is still flowing, and
+    /// `on_transfer_complete` receives the bytes relayed so far. The clock
+    /// starts when relaying starts and is not reset by activity. Unlimited
+    /// by default.
+    fn max_session_duration(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Longest a CONNECT or BIND tunnel waits on a single read from either
+    /// side. A direction that stays silent that long closes both ends and
+    /// fails the tunnel with an `io::ErrorKind::TimedOut` error carrying
+    /// `SocksError::Timeout`. Unlike an idle timeout, which only measures
+    /// inactivity across both directions, it also fires when one direction
+    /// stalls while the other keeps busy, so it must exceed the longest
+    /// silence the relayed protocol allows. Unlimited by default.
+    fn read_timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Longest a CONNECT or BIND tunnel waits for either side to accept a
+    /// single write, catching a peer that stopped reading and let its
+    /// receive window fill up. Failing it ends the tunnel as `read_timeout`
+    /// does. Unlimited by default.
+    fn write_timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Called when a CONNECT or BIND tunnel goes live, right after its
+    /// success reply and before relaying starts, to log the tunnel or track
+    /// it in a connection table. `bind_addr` is the address the success
+    /// reply is about: the local address of the outbound connection for
+    /// CONNECT and of the listener for BIND. `on_transfer_complete` follows
+    /// once relaying ends, and only tunnels this was called for reach it.
+    #[allow(unused_variables)]
+    fn on_established(&self, dest_addr: &SocksAddr, bind_addr: SocketAddr) {}
+
+    /// Called when a CONNECT or BIND tunnel finishes relaying, with the
+    /// bytes relayed and, in `Transfer::duration`, how long the connection
+    /// lasted, for throughput and anomaly tracking in one place
+    #[allow(unused_variables)]
+    fn on_transfer_complete(&self, dest_addr: &SocksAddr, transfer: &Transfer) {}
+
+    /// Local address the default `connect` binds the outbound socket to
+    /// before dialing `dest_addr`, picking the source IP, and so the egress
+    /// interface, per destination for policy-based routing. A zero port
+    /// lets the system pick one. The family must match the destination:
+    /// an IPv4 address cannot reach an IPv6 destination, so the resolved
+    /// addresses of the other family fail to dial. None by default, which
+    /// leaves the choice to the routing table.
+    #[allow(unused_variables)]
+    fn outbound_bind_addr(&self, dest_addr: &SocksAddr) -> Option<SocketAddr> {
+        None
+    }
+
+    /// PROXY protocol header the default `connect` sends to the destination
+    /// before anything else, carrying the address of the client and of the
+    /// destination, for backends that log or filter on the real client IP.
+    /// It is written once the connection is established, before the success
+    /// reply, so a failure to write it fails the request. None by default,
+    /// which sends no header.
+    #[cfg(feature = "proxy-protocol")]
+    fn send_proxy_protocol(&self) -> Option<ProxyVersion> {
+        None
+    }
+
+    /// Time the default `connect` allows to dial the destination. Once it
+    /// elapses the request fails with `SocksError::Timeout` and is
+    /// `Rejected`, as SOCKS4 has no reply for timeouts. Only the system
+    /// timeout applies by default.
+    fn connect_timeout(&self) -> Option<Duration> {
+        None
+    }
+
     async fn connect(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream<'_>,
         dest_addr: &SocksAddr,
     ) -> Result<(), Self::Error> {
-        let mut connect_stream = TcpStream::connect((dest_addr.domain(), dest_addr.port())).await?;
+        let bind_addr = self.outbound_bind_addr(dest_addr);
+        let attempt = async {
+            let addrs = dest_addr.resolved_sorted(IpPref::System).await?;
+            dial_from(&addrs, bind_addr).await
+        };
+        let mut connect_stream = match self.connect_timeout() {
+            Some(timeout) => {
+                time::timeout(timeout, attempt)
+                    .await
+                    .map_err(|_| SocksError::Timeout {
+                        phase: TimeoutPhase::Connect,
+                    })??
+            }
+            None => attempt.await?,
+        };
+        #[cfg(feature = "proxy-protocol")]
+        if let Some(version) = self.send_proxy_protocol() {
+            proxy_protocol::write_header(&mut connect_stream, version, stream.peer_addr()?).await?;
+        }
         let bind_addr = connect_stream.local_addr()?;
-        Socks4Reply::Granted.reply(stream, bind_addr).await?;
-
-        io::copy_bidirectional(stream, &mut connect_stream).await?;
+        Socks4Reply::Granted
+            .reply(stream, advertised(self.advertised_addr(), bind_addr))
+            .await?;
+        self.on_established(dest_addr, bind_addr);
+
+        let transfer = relay_client(
+            stream,
+            &mut connect_stream,
+            self.half_close(),
+            self.max_session_duration(),
+            self.read_timeout(),
+            self.write_timeout(),
+        )
+        .await?;
+        self.on_transfer_complete(dest_addr, &transfer);
 
         Ok(())
     }
 
-    async fn bind(&self, stream: &mut TcpStream, dest_addr: &SocksAddr) -> Result<(), Self::Error> {
-        let listener = TcpListener::bind((dest_addr.domain(), dest_addr.port())).await?;
-        let bind_addr = listener.local_addr()?.clone();
-        Socks4Reply::Granted.reply(stream, bind_addr).await?;
+    /// Address advertised in place of the server's own in the `Granted`
+    /// replies of the default commands, such as the proxy's public address
+    /// when it runs behind NAT. A zero port is replaced with the port of the
+    /// socket the reply is about. It applies to the reply of CONNECT and to
+    /// the first reply of BIND, the address the application server must
+    /// connect to, while the second reply of BIND carries the connecting
+    /// host as is. Only an IPv4 address fits in DSTIP. None by default,
+    /// replies then carry the addresses the sockets are bound to.
+    fn advertised_addr(&self) -> Option<SocketAddr> {
+        None
+    }
 
-        let (mut bind_stream, _) = listener.accept().await?;
+    /// Length of the queue of pending connections on the listener of the
+    /// default `bind`, for servers expecting bursts of inbound connections.
+    /// Defaults to `listener::DEFAULT_BACKLOG`, the backlog of
+    /// `TcpListener::bind`.
+    fn bind_backlog(&self) -> u32 {
+        DEFAULT_BACKLOG
+    }
 
-        io::copy_bidirectional(stream, &mut bind_stream).await?;
+    /// Serves BIND with the two replies of the SOCKS4 protocol. The first
+    /// `Granted` reply carries the address the listener is bound to, which
+    /// the client passes on to the application server. Once that server
+    /// connects, a second `Granted` reply carries its address, and only then
+    /// does relaying start.
+    async fn bind(
+        &self,
+        stream: &mut ClientStream<'_>,
+        dest_addr: &SocksAddr,
+    ) -> Result<(), Self::Error> {
+        let listener =
+            bind_listener((dest_addr.domain(), dest_addr.port()), self.bind_backlog()).await?;
+        let bind_addr = listener.local_addr()?;
+        Socks4Reply::Granted
+            .reply(stream, advertised(self.advertised_addr(), bind_addr))
+            .await?;
+
+        // The client may give up on the BIND by closing the control
+        // connection, which must not leave the listener behind
+        let (mut bind_stream, peer_addr) = select! {
+            result = listener.accept() => result?,
+            result = client_closed(stream) => {
+                result?;
+                return Err(SocksError::ClientDisconnected.into());
+            }
+        };
+        Socks4Reply::Granted.reply(stream, peer_addr).await?;
+        self.on_established(dest_addr, bind_addr);
+
+        let transfer = relay_client(
+            stream,
+            &mut bind_stream,
+            self.half_close(),
+            self.max_session_duration(),
+            self.read_timeout(),
+            self.write_timeout(),
+        )
+        .await?;
+        self.on_transfer_complete(dest_addr, &transfer);
 
         Ok(())
     }
 }
 
-#[derive(Clone, Debug)]
 pub struct Socks4<H: Socks4Handler + Send + Sync> {
     peer_addr: SocketAddr,
     local_addr: SocketAddr,
-    handler: H,
+    handler: Arc<H>,
+    #[cfg(feature = "tap")]
+    tap: Option<Box<dyn Tap + Send + Sync>>,
+}
+
+// Implemented by hand, as deriving would require the handler to be `Clone`
+// and `Debug` while only the `Arc` around it is cloned
+impl<H: Socks4Handler + Send + Sync> Clone for Socks4<H> {
+    fn clone(&self) -> Self {
+        Self {
+            peer_addr: self.peer_addr,
+            local_addr: self.local_addr,
+            handler: self.handler.clone(),
+            #[cfg(feature = "tap")]
+            tap: None,
+        }
+    }
+}
+
+impl<H: Socks4Handler + Send + Sync> fmt::Debug for Socks4<H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Socks4")
+            .field("peer_addr", &self.peer_addr)
+            .field("local_addr", &self.local_addr)
+            .finish_non_exhaustive()
+    }
 }
 
 impl<H: Socks4Handler + Send + Sync> Socks4<H> {
     pub const VERSION: u8 = 0x04;
 
-    pub fn new(peer_addr: SocketAddr, local_addr: SocketAddr, handler: H) -> Self {
+    /// `handler` is shared rather than owned, so passing an `Arc` serves
+    /// many connections with a single handler instance.
+    pub fn new(peer_addr: SocketAddr, local_addr: SocketAddr, handler: impl Into<Arc<H>>) -> Self {
         Self {
             peer_addr,
             local_addr,
-            handler,
+            handler: handler.into(),
+            #[cfg(feature = "tap")]
+            tap: None,
         }
     }
+
+    /// Copies every byte the session reads from or writes to the client
+    /// into `tap`, see `Socks::with_tap`. A clone starts without a tap.
+    #[cfg(feature = "tap")]
+    pub fn with_tap(mut self, tap: impl Tap + Send + Sync + 'static) -> Self {
+        self.tap = Some(Box::new(tap));
+        self
+    }
+
     pub async fn execute(&mut self, stream: &mut TcpStream) -> Result<(), SocksError> {
         match self.negotiate(stream).await {
             Ok(_) => Ok(()),
+            // Nothing is left to shut down
+            Err(err) if SocksError::is_client_disconnected(&err) => {
+                Err(SocksError::ClientDisconnected)
+            }
             Err(err) => {
                 stream.shutdown().await?;
                 Err(SocksError::ExecuteError(err.to_string()))
@@ -85,6 +372,28 @@ impl<H: Socks4Handler + Send + Sync> Socks4<H> {
         }
     }
     pub async fn negotiate(&mut self, stream: &mut TcpStream) -> Result<(), H::Error> {
+        let stream = &mut ClientStream::new(stream);
+        #[cfg(feature = "tap")]
+        stream.set_tap(self.tap.take());
+
+        let result = CONNECTION_STARTED
+            .scope(Instant::now(), self.negotiate_session(stream))
+            .await;
+
+        #[cfg(feature = "tap")]
+        {
+            self.tap = stream.take_tap();
+        }
+
+        result
+    }
+
+    async fn negotiate_session(&self, stream: &mut ClientStream<'_>) -> Result<(), H::Error> {
+        if !self.handler.allow_peer(&self.peer_addr).await? {
+            stream.shutdown().await?;
+            return Err(SocksError::PeerNotAllowed(self.peer_addr).into());
+        }
+
         let (command, dest_addr, user_id) = match self.negotiate_request(stream).await {
             Ok(val) => val,
             Err(err) => {
@@ -94,10 +403,16 @@ impl<H: Socks4Handler + Send + Sync> Socks4<H> {
             }
         };
 
-        let is_success = match self.handler.identd(&user_id, &self.peer_addr).await {
+        let is_success = match self
+            .handler
+            .identd(&user_id, &self.peer_addr, &self.local_addr)
+            .await
+        {
             Ok(val) => val,
             Err(err) => {
-                Socks4Reply::Rejected.reply(stream, self.local_addr).await?;
+                Socks4Reply::from_error(&err)
+                    .reply(stream, self.local_addr)
+                    .await?;
 
                 return Err(err);
             }
@@ -109,10 +424,16 @@ impl<H: Socks4Handler + Send + Sync> Socks4<H> {
             return Err(SocksError::AuthFailed.into());
         }
 
-        match command {
-            Socks4Command::Connect => self.connect(stream, dest_addr).await,
-            Socks4Command::Bind => self.bind(stream, dest_addr).await,
-        }
+        let _permit = match self.handler.acquire_permit().await {
+            Ok(val) => val,
+            Err(err) => {
+                Socks4Reply::Rejected.reply(stream, self.local_addr).await?;
+
+                return Err(err);
+            }
+        };
+
+        self.serve(stream, command, dest_addr).await
     }
 
     /// +----+----+----+----+----+----+----+----+----+----+....+----+
@@ -125,10 +446,20 @@ impl<H: Socks4Handler + Send + Sync> Socks4<H> {
     /// of all zero bits.
     async fn negotiate_request(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream<'_>,
     ) -> Result<(Socks4Command, SocksAddr, String), H::Error> {
+        let mut budget = HandshakeBudget::new(self.handler.max_handshake_size());
+
         let command: Socks4Command = stream.read_u8().await?.try_into()?;
 
+        if self
+            .handler
+            .drain_state()
+            .is_some_and(DrainState::is_draining)
+        {
+            return Err(SocksError::Draining.into());
+        }
+
         let is_support_command = self.handler.allow_command(&command).await?;
 
         if !is_support_command {
@@ -139,6 +470,7 @@ impl<H: Socks4Handler + Send + Sync> Socks4<H> {
 
         let mut buf = [0; 4];
         stream.read_exact(&mut buf).await?;
+        budget.consume(1 + 2 + buf.len())?;
 
         let ip = Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]);
 
@@ -147,6 +479,7 @@ impl<H: Socks4Handler + Send + Sync> Socks4<H> {
         let mut buf = Vec::new();
         loop {
             let val = stream.read_u8().await?;
+            budget.consume(1)?;
             if val == 0x00 {
                 break;
             } else {
@@ -154,51 +487,397 @@ impl<H: Socks4Handler + Send + Sync> Socks4<H> {
             }
         }
 
-        let user_id = String::from_utf8(buf).map_err(SocksError::Utf8BytesToStringError)?;
+        let mut user_id = String::from_utf8(buf).map_err(SocksError::Utf8BytesToStringError)?;
+        if self.handler.trim_user_id() {
+            user_id.truncate(user_id.trim_end_matches(char::is_control).len());
+        }
 
         // socks4a 协议，如果ip地址是0.0.0.x的形式，则需要读取域名信息。注意x必须非0
         // https://www.openssh.com/txt/socks4a.protocol
         let ip_bytes = ip.octets();
-        let dist_addr =
-            if ip_bytes[0] == 0 && ip_bytes[1] == 0 && ip_bytes[2] == 0 && ip_bytes[3] != 0 {
-                let mut buf = Vec::new();
-                loop {
-                    let val = stream.read_u8().await?;
-                    if val == 0x00 {
-                        break;
-                    } else {
-                        buf.push(val);
-                    }
+        let dist_addr = if self.handler.socks4a_enabled()
+            && ip_bytes[0] == 0
+            && ip_bytes[1] == 0
+            && ip_bytes[2] == 0
+            && ip_bytes[3] != 0
+        {
+            let mut buf = Vec::new();
+            loop {
+                let val = stream.read_u8().await?;
+                budget.consume(1)?;
+                if val == 0x00 {
+                    break;
+                } else {
+                    buf.push(val);
                 }
+            }
 
-                let domain = String::from_utf8(buf).map_err(SocksError::Utf8BytesToStringError)?;
-                SocksAddr::Domain(domain, port)
-            } else {
-                ipv4_addr
-            };
+            let domain = String::from_utf8(buf).map_err(SocksError::Utf8BytesToStringError)?;
+            SocksAddr::Domain(domain, port)
+        } else {
+            ipv4_addr
+        };
 
         Ok((command, dist_addr, user_id))
     }
 
-    async fn connect(&self, stream: &mut TcpStream, dist_addr: SocksAddr) -> Result<(), H::Error> {
-        match self.handler.connect(stream, &dist_addr).await {
+    /// Runs the handler serving `command`, replying `Rejected` when it
+    /// fails before sending the replies the command expects, one for CONNECT
+    /// and two for BIND. Once they are out, the client is relaying and a
+    /// reply would land in the tunnel.
+    async fn serve(
+        &self,
+        stream: &mut ClientStream<'_>,
+        command: Socks4Command,
+        dist_addr: SocksAddr,
+    ) -> Result<(), H::Error> {
+        let expected = match command {
+            Socks4Command::Connect => 1,
+            Socks4Command::Bind => 2,
+        };
+        let count = stream.count_replies(ReplyFraming::Socks4, expected);
+
+        let result = match command {
+            Socks4Command::Connect => self.handler.connect(stream, &dist_addr).await,
+            Socks4Command::Bind => self.handler.bind(stream, &dist_addr).await,
+        };
+
+        match result {
             Ok(_) => Ok(()),
             Err(err) => {
-                Socks4Reply::Rejected.reply(stream, self.local_addr).await?;
+                if count.get().sent < expected {
+                    Socks4Reply::Rejected.reply(stream, self.local_addr).await?;
+                }
 
                 Err(err)
             }
         }
     }
+}
 
-    async fn bind(&self, stream: &mut TcpStream, dist_addr: SocksAddr) -> Result<(), H::Error> {
-        match self.handler.bind(stream, &dist_addr).await {
-            Ok(_) => Ok(()),
-            Err(err) => {
-                Socks4Reply::Rejected.reply(stream, self.local_addr).await?;
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
 
-                Err(err)
+    use super::*;
+    use crate::test_util::{tcp_pair, unresponsive_listener};
+
+    struct DefaultHandler;
+
+    #[async_trait]
+    impl Socks4Handler for DefaultHandler {
+        type Error = SocksError;
+    }
+
+    /// Gives each dial 200 milliseconds
+    struct ConnectTimeoutHandler;
+
+    #[async_trait]
+    impl Socks4Handler for ConnectTimeoutHandler {
+        type Error = SocksError;
+
+        fn connect_timeout(&self) -> Option<Duration> {
+            Some(Duration::from_millis(200))
+        }
+    }
+
+    /// Records the USERID and destination of a CONNECT, granting it without
+    /// connecting anywhere
+    #[derive(Default)]
+    struct RecordingHandler {
+        socks4a_disabled: bool,
+        trim_user_id: bool,
+        user_id: Mutex<Option<String>>,
+        dest_addr: Mutex<Option<SocksAddr>>,
+    }
+
+    #[async_trait]
+    impl Socks4Handler for RecordingHandler {
+        type Error = SocksError;
+
+        fn socks4a_enabled(&self) -> bool {
+            !self.socks4a_disabled
+        }
+
+        fn trim_user_id(&self) -> bool {
+            self.trim_user_id
+        }
+
+        async fn identd(
+            &self,
+            user_id: &str,
+            _peer_addr: &SocketAddr,
+            _local_addr: &SocketAddr,
+        ) -> Result<bool, Self::Error> {
+            *self.user_id.lock().unwrap() = Some(user_id.to_string());
+            Ok(true)
+        }
+
+        async fn connect(
+            &self,
+            stream: &mut ClientStream<'_>,
+            dest_addr: &SocksAddr,
+        ) -> Result<(), Self::Error> {
+            *self.dest_addr.lock().unwrap() = Some(dest_addr.clone());
+            let bind_addr = SocketAddr::from(([127, 0, 0, 1], 80));
+            Socks4Reply::Granted.reply(stream, bind_addr).await?;
+            Ok(())
+        }
+    }
+
+    /// Serves `request`, after VN, with `handler`
+    async fn record(handler: RecordingHandler, request: &[u8]) -> Arc<RecordingHandler> {
+        let handler = Arc::new(handler);
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks4 = Socks4::<RecordingHandler>::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            handler.clone(),
+        );
+
+        client.write_all(request).await.unwrap();
+        socks4.negotiate(&mut server).await.unwrap();
+
+        handler
+    }
+
+    /// Denies every peer
+    struct DeniedPeerHandler;
+
+    #[async_trait]
+    impl Socks4Handler for DeniedPeerHandler {
+        type Error = SocksError;
+
+        async fn allow_peer(&self, _peer_addr: &SocketAddr) -> Result<bool, Self::Error> {
+            Ok(false)
+        }
+    }
+
+    /// Fails the request, after granting it when `granted` is set
+    struct FailingHandler {
+        granted: bool,
+    }
+
+    #[async_trait]
+    impl Socks4Handler for FailingHandler {
+        type Error = SocksError;
+
+        async fn connect(
+            &self,
+            stream: &mut ClientStream<'_>,
+            _dest_addr: &SocksAddr,
+        ) -> Result<(), Self::Error> {
+            if self.granted {
+                let bind_addr = SocketAddr::from(([127, 0, 0, 1], 80));
+                Socks4Reply::Granted.reply(stream, bind_addr).await?;
             }
+
+            Err(SocksError::Timeout {
+                phase: TimeoutPhase::Read,
+            })
         }
     }
+
+    /// Replies the client got for a CONNECT served by `handler`
+    async fn connect_replies(handler: FailingHandler) -> Vec<u8> {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks4 = Socks4::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            handler,
+        );
+
+        client
+            .write_all(&[0x01, 0, 80, 127, 0, 0, 1, 0x00])
+            .await
+            .unwrap();
+        let result = socks4.negotiate(&mut server).await;
+        assert!(matches!(result, Err(SocksError::Timeout { .. })));
+        drop(server);
+
+        let mut replies = Vec::new();
+        client.read_to_end(&mut replies).await.unwrap();
+        replies
+    }
+
+    #[tokio::test]
+    async fn failure_after_granted_is_not_replied() {
+        let replies = connect_replies(FailingHandler { granted: true }).await;
+        assert_eq!(replies, [0x00, 0x5a, 0, 80, 127, 0, 0, 1]);
+    }
+
+    #[tokio::test]
+    async fn failure_before_any_reply_is_rejected() {
+        let replies = connect_replies(FailingHandler { granted: false }).await;
+        assert_eq!(replies.len(), 8);
+        assert_eq!(replies[1], 0x5b);
+    }
+
+    #[tokio::test]
+    async fn bind_is_aborted_when_the_client_closes() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks4 = Socks4::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            DefaultHandler,
+        );
+        let session = tokio::spawn(async move { socks4.negotiate(&mut server).await });
+
+        client
+            .write_all(&[0x02, 0, 80, 127, 0, 0, 1, 0x00])
+            .await
+            .unwrap();
+        let mut reply = [0; 8];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [0x00, 0x5a]);
+
+        // Gives up before anything connects to the listener
+        client.shutdown().await.unwrap();
+        let result = time::timeout(Duration::from_secs(5), session).await;
+        let result = result.unwrap().unwrap();
+        assert!(matches!(result, Err(SocksError::ClientDisconnected)));
+    }
+
+    #[tokio::test]
+    async fn oversized_user_id_is_rejected() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks4 = Socks4::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            DefaultHandler,
+        );
+
+        let mut request = vec![0x01, 0, 80, 127, 0, 0, 1];
+        request.extend([b'a'; DEFAULT_MAX_HANDSHAKE_SIZE]);
+        request.push(0x00);
+        client.write_all(&request).await.unwrap();
+        let result = socks4.negotiate(&mut server).await;
+
+        let mut reply = [0; 8];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [0x00, 0x5b]);
+        assert!(matches!(result, Err(SocksError::HandshakeTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn socks4a_requests_carry_a_domain() {
+        let mut request = vec![0x01, 0, 80, 0, 0, 0, 1, 0x00];
+        request.extend(b"example.com\0");
+
+        let handler = record(RecordingHandler::default(), &request).await;
+        let dest_addr = handler.dest_addr.lock().unwrap().clone();
+        assert_eq!(
+            dest_addr,
+            Some(SocksAddr::Domain("example.com".to_string(), 80))
+        );
+    }
+
+    #[tokio::test]
+    async fn socks4a_disabled_reads_a_plain_ip() {
+        let handler = RecordingHandler {
+            socks4a_disabled: true,
+            ..Default::default()
+        };
+        let handler = record(handler, &[0x01, 0, 80, 0, 0, 0, 1, 0x00]).await;
+
+        let dest_addr = handler.dest_addr.lock().unwrap().clone();
+        let ip = Ipv4Addr::new(0, 0, 0, 1);
+        assert_eq!(dest_addr, Some(SocksAddr::IPV4(SocketAddrV4::new(ip, 80))));
+    }
+
+    #[tokio::test]
+    async fn connect_timeout_is_rejected() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks4 = Socks4::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            ConnectTimeoutHandler,
+        );
+        let (listener, _streams) = unresponsive_listener().await;
+        let port = listener.local_addr().unwrap().port().to_be_bytes();
+
+        client
+            .write_all(&[0x01, port[0], port[1], 127, 0, 0, 1, 0x00])
+            .await
+            .unwrap();
+        let result = socks4.negotiate(&mut server).await;
+
+        let mut reply = [0; 8];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [0x00, 0x5b]);
+        assert!(matches!(result, Err(SocksError::Timeout { .. })));
+    }
+
+    #[test]
+    fn clones_share_a_handler_that_is_neither_clone_nor_debug() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1080));
+        let socks4 = Socks4::new(addr, addr, DefaultHandler);
+
+        let clone = socks4.clone();
+        assert!(Arc::ptr_eq(&socks4.handler, &clone.handler));
+        assert!(format!("{:?}", clone).starts_with("Socks4 { peer_addr: 127.0.0.1:1080"));
+    }
+
+    #[tokio::test]
+    async fn second_bind_reply_follows_the_inbound_connection() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks4 = Socks4::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            DefaultHandler,
+        );
+        let session = tokio::spawn(async move { socks4.negotiate(&mut server).await });
+
+        client
+            .write_all(&[0x02, 0, 80, 127, 0, 0, 1, 0x00])
+            .await
+            .unwrap();
+        let listen_addr = client::read_reply(&mut client).await.unwrap();
+        let early = time::timeout(Duration::from_millis(50), client.read_u8()).await;
+        assert!(early.is_err());
+
+        let inbound = TcpStream::connect(listen_addr).await.unwrap();
+        let peer_addr = client::read_reply(&mut client).await.unwrap();
+        assert_eq!(SocketAddr::V4(peer_addr), inbound.local_addr().unwrap());
+
+        drop(inbound);
+        drop(client);
+        session.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn user_id_line_endings_are_trimmed_on_request() {
+        let request = b"\x01\x00\x50\x7f\x00\x00\x01alice\r\n\0";
+
+        let handler = record(RecordingHandler::default(), request).await;
+        let user_id = handler.user_id.lock().unwrap().clone();
+        assert_eq!(user_id.as_deref(), Some("alice\r\n"));
+
+        let handler = RecordingHandler {
+            trim_user_id: true,
+            ..Default::default()
+        };
+        let handler = record(handler, request).await;
+        let user_id = handler.user_id.lock().unwrap().clone();
+        assert_eq!(user_id.as_deref(), Some("alice"));
+    }
+
+    #[tokio::test]
+    async fn denied_peer_gets_eof_without_a_reply() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks4 = Socks4::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            DeniedPeerHandler,
+        );
+
+        let result = socks4.negotiate(&mut server).await;
+        assert!(
+            matches!(result, Err(SocksError::PeerNotAllowed(addr)) if addr == client.local_addr().unwrap())
+        );
+
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert!(received.is_empty());
+    }
 }
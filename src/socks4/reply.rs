@@ -1,7 +1,12 @@
-use std::net::SocketAddr;
+use std::{
+    error::Error,
+    net::{Ipv4Addr, SocketAddr},
+};
 
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
 
+use crate::error::SocksError;
+
 /// 90: request granted
 /// 91: request rejected or failed
 /// 92: request rejected becasue SOCKS server cannot connect to identd on the client
@@ -11,7 +16,7 @@ pub enum Socks4Reply {
     Granted = 0x5a,
     Rejected = 0x5b,
     RejectedByCannotConnectIdentd = 0x5c,
-    RejectedByIdentdReportDifferentUserIds = 0x5f,
+    RejectedByIdentdReportDifferentUserIds = 0x5d,
 }
 
 impl From<u8> for Socks4Reply {
@@ -20,24 +25,45 @@ impl From<u8> for Socks4Reply {
             0x5a => Self::Granted,
             0x5b => Self::Rejected,
             0x5c => Self::RejectedByCannotConnectIdentd,
-            0x5f => Self::RejectedByIdentdReportDifferentUserIds,
+            0x5d => Self::RejectedByIdentdReportDifferentUserIds,
             _ => Self::Rejected,
         }
     }
 }
 
-impl Into<u8> for Socks4Reply {
-    fn into(self) -> u8 {
-        match self {
-            Self::Granted => 0x5a,
-            Self::Rejected => 0x5b,
-            Self::RejectedByCannotConnectIdentd => 0x5c,
-            Self::RejectedByIdentdReportDifferentUserIds => 0x5f,
+impl From<Socks4Reply> for u8 {
+    fn from(value: Socks4Reply) -> Self {
+        match value {
+            Socks4Reply::Granted => 0x5a,
+            Socks4Reply::Rejected => 0x5b,
+            Socks4Reply::RejectedByCannotConnectIdentd => 0x5c,
+            Socks4Reply::RejectedByIdentdReportDifferentUserIds => 0x5d,
         }
     }
 }
 
 impl Socks4Reply {
+    /// Picks the reply for an error returned by a handler, by looking for an
+    /// identd failure through the error and its sources. Anything else
+    /// replies `Rejected`.
+    pub(super) fn from_error(err: &(dyn Error + 'static)) -> Self {
+        let mut source = Some(err);
+
+        while let Some(err) = source {
+            match err.downcast_ref::<SocksError>() {
+                Some(SocksError::IdentdUnreachable) => return Self::RejectedByCannotConnectIdentd,
+                Some(SocksError::IdentdUserIdMismatch(_)) => {
+                    return Self::RejectedByIdentdReportDifferentUserIds
+                }
+                _ => {}
+            }
+
+            source = err.source();
+        }
+
+        Self::Rejected
+    }
+
     /// +----+----+----+----+----+----+----+----+
     /// | VN | CD | DSTPORT |      DSTIP        |
     /// +----+----+----+----+----+----+----+----+
@@ -50,21 +76,60 @@ impl Socks4Reply {
     /// 91: request rejected or failed
     /// 92: request rejected becasue SOCKS server cannot connect to identd on the client
     /// 93: request rejected because the client program and identd report different user-ids
-    pub async fn reply<S>(&mut self, stream: &mut S, bind_addr: SocketAddr) -> Result<(), io::Error>
+    ///
+    /// DSTIP only holds an IPv4 address. An IPv4-mapped IPv6 `bind_addr` is
+    /// written as its IPv4 form and any other IPv6 address as `0.0.0.0`,
+    /// such as the local address of a connection to an IPv6-only SOCKS4a
+    /// destination, keeping its port.
+    pub async fn reply<S>(&self, stream: &mut S, bind_addr: SocketAddr) -> Result<(), io::Error>
     where
         S: AsyncReadExt + AsyncWriteExt + Unpin + Send,
     {
-        let (ip, port) = match bind_addr {
-            SocketAddr::V4(addr) => (addr.ip().octets().to_vec(), addr.port()),
-            SocketAddr::V6(addr) => (addr.ip().octets().to_vec(), addr.port()),
+        let ip = match bind_addr {
+            SocketAddr::V4(addr) => *addr.ip(),
+            SocketAddr::V6(addr) => addr.ip().to_ipv4_mapped().unwrap_or(Ipv4Addr::UNSPECIFIED),
         };
 
         let mut buf = vec![0x00, (*self).into()];
-        buf.extend(port.to_be_bytes());
-        buf.extend(ip);
+        buf.extend(bind_addr.port().to_be_bytes());
+        buf.extend(ip.octets());
 
         stream.write_all(&buf).await?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    /// Bytes of a `Granted` reply about `bind_addr`
+    async fn granted(bind_addr: SocketAddr) -> [u8; 8] {
+        let (mut client, mut server) = io::duplex(64);
+        Socks4Reply::Granted
+            .reply(&mut server, bind_addr)
+            .await
+            .unwrap();
+
+        let mut reply = [0; 8];
+        client.read_exact(&mut reply).await.unwrap();
+        reply
+    }
+
+    #[tokio::test]
+    async fn ipv6_addresses_are_written_as_ipv4() {
+        let mapped = Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped();
+        assert_eq!(
+            granted(SocketAddr::from((mapped, 1080))).await,
+            [0x00, 0x5a, 0x04, 0x38, 192, 0, 2, 1]
+        );
+
+        assert_eq!(
+            granted(SocketAddr::from((Ipv6Addr::LOCALHOST, 1080))).await,
+            [0x00, 0x5a, 0x04, 0x38, 0, 0, 0, 0]
+        );
+    }
+}
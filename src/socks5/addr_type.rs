@@ -11,6 +11,23 @@ pub enum Socks5AddrType {
     IPV6 = 0x04,
 }
 
+impl Socks5AddrType {
+    /// Total length of a reply whose BND.ADDR has this type, VER, REP, RSV
+    /// and ATYP included: 10 bytes for IPv4 and 22 for IPv6.
+    ///
+    /// A domain BND.ADDR is its length byte followed by that many bytes,
+    /// which `domain_len` gives. Without it, this is the 5 bytes to read
+    /// first to learn that length. `domain_len` is ignored for IP addresses.
+    pub fn reply_len(&self, domain_len: Option<u8>) -> usize {
+        match (self, domain_len) {
+            (Socks5AddrType::IPV4, _) => 4 + 4 + 2,
+            (Socks5AddrType::IPV6, _) => 4 + 16 + 2,
+            (Socks5AddrType::Domain, Some(len)) => 4 + 1 + len as usize + 2,
+            (Socks5AddrType::Domain, None) => 4 + 1,
+        }
+    }
+}
+
 impl TryFrom<u8> for Socks5AddrType {
     type Error = SocksError;
     fn try_from(code: u8) -> Result<Self, Self::Error> {
@@ -32,3 +49,30 @@ impl From<Socks5AddrType> for u8 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::addr::{write_socks_addr, SocksAddr};
+
+    #[tokio::test]
+    async fn reply_len_matches_encoded_replies() {
+        let addrs = [
+            SocketAddr::from(([192, 0, 2, 1], 1080)).into(),
+            SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], 1080)).into(),
+            SocksAddr::Domain("example.com".to_string(), 1080),
+        ];
+
+        for addr in addrs {
+            let mut reply = vec![0x05, 0x00, 0x00];
+            write_socks_addr(&mut reply, &addr).await.unwrap();
+
+            let addr_type: Socks5AddrType = reply[3].try_into().unwrap();
+            // Read up to the length octet of a domain first, then the rest
+            assert!(addr_type.reply_len(None) <= reply.len());
+            assert_eq!(addr_type.reply_len(reply.get(4).copied()), reply.len());
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    addr::{read_socks_addr, SocksAddr},
+    error::SocksError,
+};
+
+use super::{
+    addr_type::Socks5AddrType, command::Socks5Command, method::Socks5Method, reply::Socks5Reply,
+    request::encode_socks5_request,
+};
+
+/// VER of the username/password sub-negotiation, in the request and the
+/// server response alike
+const USER_PASS_VERSION: u8 = 0x01;
+
+/// Client side of the SOCKS5 protocol, for talking to another SOCKS5 server
+/// such as an upstream proxy. `stream` is a connection to that server.
+///
+/// Performs the method negotiation, offering NO AUTHENTICATION REQUIRED and,
+/// when `credentials` are given, USERNAME/PASSWORD, then the username/password
+/// sub-negotiation if the server picked it.
+pub async fn handshake<S>(
+    stream: &mut S,
+    credentials: Option<(&str, &str)>,
+) -> Result<Socks5Method, SocksError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut methods = vec![Socks5Method::None];
+    if credentials.is_some() {
+        methods.push(Socks5Method::UserPass);
+    }
+
+    let mut buf = vec![Socks5Reply::VERSION, methods.len() as u8];
+    buf.extend(methods.iter().map(|&method| u8::from(method)));
+    stream.write_all(&buf).await?;
+
+    let mut buf = [0; 2];
+    stream.read_exact(&mut buf).await?;
+    let [version, method] = buf;
+
+    if version != Socks5Reply::VERSION {
+        return Err(SocksError::UnsupportedVersion(version));
+    }
+
+    let method = Socks5Method::from(method);
+
+    match (method, credentials) {
+        (Socks5Method::None, _) => Ok(method),
+        (Socks5Method::UserPass, Some((username, password))) => {
+            auth_by_user_pass(stream, username, password).await?;
+            Ok(method)
+        }
+        _ => Err(SocksError::UnsupportedMethods(methods)),
+    }
+}
+
+/// username/password method, RFC 1929
+///
+/// ```text
+/// +----+------+----------+------+----------+
+/// |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+/// +----+------+----------+------+----------+
+/// | 1  |  1   | 1 to 255 |  1   | 1 to 255 |
+/// +----+------+----------+------+----------+
+/// ```
+///
+/// A STATUS other than X'00' in the server response is a failure, and a
+/// VER other than X'01' yields `SocksError::UnsupportedVersion`.
+async fn auth_by_user_pass<S>(
+    stream: &mut S,
+    username: &str,
+    password: &str,
+) -> Result<(), SocksError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let too_long = || io::Error::new(io::ErrorKind::InvalidInput, "Credentials exceed 255 bytes");
+    let username_length = u8::try_from(username.len()).map_err(|_| too_long())?;
+    let password_length = u8::try_from(password.len()).map_err(|_| too_long())?;
+
+    let mut buf = vec![USER_PASS_VERSION, username_length];
+    buf.extend(username.as_bytes());
+    buf.push(password_length);
+    buf.extend(password.as_bytes());
+    stream.write_all(&buf).await?;
+
+    let mut buf = [0; 2];
+    stream.read_exact(&mut buf).await?;
+    let [version, status] = buf;
+
+    if version != USER_PASS_VERSION {
+        return Err(SocksError::UnsupportedVersion(version));
+    }
+    if status != 0x00 {
+        return Err(SocksError::AuthFailed);
+    }
+
+    Ok(())
+}
+
+/// Sends a request once `handshake` succeeded and reads the reply. A reply
+/// other than `Succeeded` yields `SocksError::RequestFailed`; otherwise the
+/// BND.ADDR and BND.PORT of the reply are returned.
+///
+/// BIND is answered with a second reply once the inbound connection is
+/// accepted, which `read_reply` reads.
+pub async fn request<S>(
+    stream: &mut S,
+    command: Socks5Command,
+    dest_addr: &SocksAddr,
+) -> Result<SocksAddr, SocksError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let buf = encode_socks5_request(command, dest_addr)?;
+    stream.write_all(&buf).await?;
+
+    read_reply(stream).await
+}
+
+/// Reads a reply, the layout of the request with REP in place of CMD, and
+/// returns the bound address when REP is `Succeeded`.
+///
+/// ```text
+/// +----+-----+-------+------+----------+----------+
+/// |VER | REP |  RSV  | ATYP | BND.ADDR | BND.PORT |
+/// +----+-----+-------+------+----------+----------+
+/// | 1  |  1  | X'00' |  1   | Variable |    2     |
+/// +----+-----+-------+------+----------+----------+
+/// ```
+///
+/// BND.ADDR may have any ATYP, a domain being read through its length
+/// octet, so nothing past the reply is consumed. Clients reading replies
+/// on their own can size them with `Socks5AddrType::reply_len`.
+pub async fn read_reply<S>(stream: &mut S) -> Result<SocksAddr, SocksError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).await?;
+    let [version, reply, _, addr_type] = header;
+
+    if version != Socks5Reply::VERSION {
+        return Err(SocksError::UnsupportedVersion(version));
+    }
+
+    let reply = Socks5Reply::from(reply);
+    let addr_type: Socks5AddrType = addr_type.try_into()?;
+
+    // The address is consumed even on failure, leaving the stream at the
+    // end of the reply
+    let bind_addr = read_socks_addr(stream, addr_type).await?;
+
+    if reply != Socks5Reply::Succeeded {
+        return Err(SocksError::RequestFailed(reply));
+    }
+
+    Ok(bind_addr)
+}
+
+/// Connects to `dest_addr` through the SOCKS5 server at the other end of
+/// `stream`, running `handshake` then a CONNECT `request`. Once it returns,
+/// `stream` carries the tunnel to the destination.
+pub async fn connect<S>(
+    stream: &mut S,
+    dest_addr: &SocksAddr,
+    credentials: Option<(&str, &str)>,
+) -> Result<SocksAddr, SocksError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    handshake(stream, credentials).await?;
+
+    request(stream, Socks5Command::Connect, dest_addr).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn domain_bind_addr_is_read_to_its_end() {
+        let mut stream: &[u8] = &[
+            0x05, 0x00, 0x00, 0x03, 11, b'e', b'x', b'a', b'm', b'p', b'l', b'e', b'.', b'c', b'o',
+            b'm', 0x04, 0x38, b'd', b'a', b't', b'a',
+        ];
+
+        let bind_addr = read_reply(&mut stream).await.unwrap();
+        assert_eq!(
+            bind_addr,
+            SocksAddr::Domain("example.com".to_string(), 1080)
+        );
+        assert_eq!(stream, b"data");
+    }
+
+    #[tokio::test]
+    async fn requests_are_encoded_as_the_server_parses_them() {
+        let (mut client, mut server) = io::duplex(1024);
+        let dest_addr = SocksAddr::Domain("example.com".to_string(), 443);
+
+        server
+            .write_all(&[0x05, 0x00, 0x00, 0x01, 192, 0, 2, 1, 0x04, 0x38])
+            .await
+            .unwrap();
+        let bind_addr = request(&mut client, Socks5Command::Connect, &dest_addr)
+            .await
+            .unwrap();
+        assert_eq!(bind_addr, "192.0.2.1:1080".parse().unwrap());
+
+        let expected = encode_socks5_request(Socks5Command::Connect, &dest_addr).unwrap();
+        let mut sent = vec![0; expected.len()];
+        server.read_exact(&mut sent).await.unwrap();
+        assert_eq!(sent, expected);
+    }
+
+    #[tokio::test]
+    async fn auth_response_of_another_version_is_rejected() {
+        let (mut client, mut server) = io::duplex(1024);
+        server.write_all(&[0x05, 0x00]).await.unwrap();
+
+        let result = auth_by_user_pass(&mut client, "user", "pass").await;
+        assert!(matches!(result, Err(SocksError::UnsupportedVersion(0x05))));
+    }
+}
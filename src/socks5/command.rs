@@ -1,5 +1,7 @@
 use crate::error::SocksError;
 
+use super::reply::Socks5Reply;
+
 /// CONNECT X'01'
 /// BIND X'02'
 /// UDP ASSOCIATE X'03'
@@ -22,12 +24,33 @@ impl TryFrom<u8> for Socks5Command {
     }
 }
 
-impl Into<u8> for Socks5Command {
-    fn into(self) -> u8 {
-        match self {
-            Self::Connect => 0x01,
-            Self::Bind => 0x02,
-            Self::Associate => 0x03,
+impl From<Socks5Command> for u8 {
+    fn from(value: Socks5Command) -> Self {
+        match value {
+            Socks5Command::Connect => 0x01,
+            Socks5Command::Bind => 0x02,
+            Socks5Command::Associate => 0x03,
+        }
+    }
+}
+
+/// Outcome of `Socks5Handler::allow_command`
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum CommandDecision {
+    Allow,
+    /// Refuses the command with this reply, such as `UnsupportedCommand`
+    /// for a command the server does not implement or `NotAllowed` for one
+    /// the client may not use
+    Deny(Socks5Reply),
+}
+
+/// `true` allows, `false` denies with `UnsupportedCommand`
+impl From<bool> for CommandDecision {
+    fn from(is_allowed: bool) -> Self {
+        if is_allowed {
+            Self::Allow
+        } else {
+            Self::Deny(Socks5Reply::UnsupportedCommand)
         }
     }
 }
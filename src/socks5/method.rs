@@ -1,3 +1,7 @@
+use std::{fmt, str::FromStr};
+
+use crate::error::SocksError;
+
 /// 0x00 NO AUTHENTICATION REQUIRED
 /// 0x01 GSSAPI
 /// 0x02 USERNAME/PASSWORD
@@ -28,15 +32,83 @@ impl From<u8> for Socks5Method {
     }
 }
 
-impl Into<u8> for Socks5Method {
-    fn into(self) -> u8 {
+impl From<Socks5Method> for u8 {
+    fn from(value: Socks5Method) -> Self {
+        match value {
+            Socks5Method::None => 0x00,
+            Socks5Method::GssApi => 0x01,
+            Socks5Method::UserPass => 0x02,
+            Socks5Method::IanaAssigned(value) => value,
+            Socks5Method::Private(value) => value,
+            Socks5Method::Unacceptable => 0xff,
+        }
+    }
+}
+
+/// Writes the method as `FromStr` parses it: `none`, `gssapi` and
+/// `userpass` by name, any other method as a hex code such as `0x80`.
+impl fmt::Display for Socks5Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::None => 0x00,
-            Self::GssApi => 0x01,
-            Self::UserPass => 0x02,
-            Self::IanaAssigned(value) => value,
-            Self::Private(value) => value,
-            Self::Unacceptable => 0xff,
+            Self::None => f.write_str("none"),
+            Self::GssApi => f.write_str("gssapi"),
+            Self::UserPass => f.write_str("userpass"),
+            method => write!(f, "{:#04x}", u8::from(*method)),
+        }
+    }
+}
+
+/// Parses a method from a config string, either its name (`none`,
+/// `gssapi` or `userpass`) or its hex code with a `0x` prefix, so the
+/// methods a `negotiate_method` picks from can be configured, IANA
+/// assigned and private ones included.
+impl FromStr for Socks5Method {
+    type Err = SocksError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "gssapi" => Ok(Self::GssApi),
+            "userpass" => Ok(Self::UserPass),
+            _ => s
+                .strip_prefix("0x")
+                .or_else(|| s.strip_prefix("0X"))
+                .and_then(|code| u8::from_str_radix(code, 16).ok())
+                .map(Self::from)
+                .ok_or_else(|| SocksError::InvalidMethod(s.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn displayed_methods_parse_back() {
+        let methods = [
+            Socks5Method::None,
+            Socks5Method::GssApi,
+            Socks5Method::UserPass,
+            Socks5Method::IanaAssigned(0x03),
+            Socks5Method::Private(0x80),
+            Socks5Method::Unacceptable,
+        ];
+
+        for method in methods {
+            assert_eq!(method.to_string().parse::<Socks5Method>().unwrap(), method);
+        }
+        assert_eq!(Socks5Method::Private(0x80).to_string(), "0x80");
+    }
+
+    #[test]
+    fn unknown_names_are_invalid() {
+        for s in ["basic", "0x", "0x100", "80"] {
+            assert!(
+                matches!(s.parse::<Socks5Method>(), Err(SocksError::InvalidMethod(_))),
+                "{}",
+                s
+            );
         }
     }
 }
@@ -1,29 +1,86 @@
 pub mod addr_type;
+pub mod client;
 pub mod command;
 pub mod method;
+pub mod outcome;
 pub mod reply;
+pub mod request;
+pub mod udp;
+pub mod upstream;
 
 use std::{
     error::Error,
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use async_trait::async_trait;
-use reply::Socks5Reply;
+use reply::{ReplyGuard, Socks5Reply};
 use tokio::{
     io::{self, AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    net::{TcpStream, UdpSocket},
+    select, time,
 };
 
-use crate::{addr::SocksAddr, error::SocksError};
+#[cfg(feature = "dns-cache")]
+use crate::dns_cache::DnsCache;
+#[cfg(feature = "proxy-protocol")]
+use crate::proxy_protocol::{self, ProxyVersion};
+#[cfg(feature = "tap")]
+use crate::tap::Tap;
+use crate::{
+    addr::{read_socks_addr, IpPref, SocksAddr},
+    dial::dial_from,
+    drain::DrainState,
+    error::{SocksError, TimeoutPhase},
+    handshake::{HandshakeBudget, DEFAULT_MAX_HANDSHAKE_SIZE},
+    listener::{bind_listener, DEFAULT_BACKLOG},
+    permit::Permit,
+    relay::{client_closed, relay_client, Transfer, CONNECTION_STARTED},
+    reply::advertised,
+    stream::ClientStream,
+};
+#[cfg(all(unix, feature = "unix-socket"))]
+use crate::{relay::relay, unix_socket};
 
 use addr_type::Socks5AddrType;
-use command::Socks5Command;
+use command::{CommandDecision, Socks5Command};
 use method::Socks5Method;
-
+use outcome::Socks5Outcome;
+
+/// Policy hooks and the reply a denial produces:
+///
+/// | Hook                | Denial reply                               |
+/// |---------------------|--------------------------------------------|
+/// | `allow_peer`        | none, the connection is closed             |
+/// | `negotiate_method`  | METHOD X'FF', then the connection closes   |
+/// | `allow_command`     | the reply of `CommandDecision::Deny`       |
+/// | `allow_addr_type`   | `UnsupportedAddressType`                   |
+/// | `allow_port`        | `NotAllowed`                               |
+/// | `allow_resolved_ip` | `NotAllowed`                               |
+///
+/// A hook returning an error instead replies `Failure`. With
+/// `reset_on_deny`, `allow_peer` and the `NotAllowed` denials reset the
+/// connection instead.
 #[async_trait]
 pub trait Socks5Handler {
-    type Error: From<SocksError> + From<io::Error> + Error;
+    type Error: From<SocksError> + From<io::Error> + Error + 'static;
+
+    /// Called before anything is read from a new client, to drop unwanted
+    /// peers cheaply. A denied connection is closed without any reply.
+    /// Allows every peer by default.
+    #[allow(unused_variables)]
+    async fn allow_peer(&self, peer_addr: &SocketAddr) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+
+    /// Called with the METHODS octets exactly as the client sent them,
+    /// duplicates and ordering included, before `negotiate_method` picks one.
+    /// Meant for logging and fingerprinting clients.
+    #[allow(unused_variables)]
+    fn on_methods_offered(&self, raw: &[u8]) {}
 
     async fn negotiate_method(
         &self,
@@ -41,9 +98,58 @@ pub trait Socks5Handler {
         Ok(false)
     }
 
+    /// USERNAME/PASSWORD authentication on the raw octets, which RFC 1929
+    /// does not require to be UTF-8. Delegates to `auth_by_user_pass` by
+    /// default, rejecting credentials that are not valid UTF-8; override it
+    /// to accept any bytes.
+    async fn auth_by_user_pass_bytes(
+        &self,
+        username: &[u8],
+        password: &[u8],
+    ) -> Result<bool, Self::Error> {
+        match (std::str::from_utf8(username), std::str::from_utf8(password)) {
+            (Ok(username), Ok(password)) => self.auth_by_user_pass(username, password).await,
+            _ => Ok(false),
+        }
+    }
+
+    /// Runs the sub-negotiation of a private method (X'80' to X'FE') chosen by
+    /// `negotiate_method`. The handler owns every byte exchanged on `stream`
+    /// until it returns, including any status it reports to the client, and
+    /// returns whether the client is authenticated. Rejects by default.
     #[allow(unused_variables)]
-    async fn allow_command(&self, command: &Socks5Command) -> Result<bool, Self::Error> {
-        Ok(true)
+    async fn private_auth(
+        &self,
+        method: u8,
+        stream: &mut ClientStream<'_>,
+    ) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+
+    /// Decides whether `command` is served. `CommandDecision::Deny` picks
+    /// the reply the request is refused with, while an error replies
+    /// `Failure` and fails the session with `SocksError::ExecuteError`.
+    /// Allows every command by default.
+    #[allow(unused_variables)]
+    async fn allow_command(&self, command: &Socks5Command) -> Result<CommandDecision, Self::Error> {
+        Ok(CommandDecision::Allow)
+    }
+
+    /// Drain state checked before each request is served. Once it started,
+    /// requests are refused with `SocksError::Draining`, which replies
+    /// `NotAllowed`, before `allow_command` is called. `serve` starts it when
+    /// its shutdown completes. None by default, which never drains.
+    fn drain_state(&self) -> Option<&DrainState> {
+        None
+    }
+
+    /// Most bytes a client may send from the version identifier to the end
+    /// of its request, the sub-negotiation of a private method excepted.
+    /// A client going over fails with `SocksError::HandshakeTooLarge`. Each
+    /// further request of a connection kept open after BIND is allowed as
+    /// much again. Defaults to `handshake::DEFAULT_MAX_HANDSHAKE_SIZE`.
+    fn max_handshake_size(&self) -> usize {
+        DEFAULT_MAX_HANDSHAKE_SIZE
     }
 
     #[allow(unused_variables)]
@@ -51,119 +157,682 @@ pub trait Socks5Handler {
         Ok(true)
     }
 
+    /// Checks DST.PORT of CONNECT and BIND requests, and the destination
+    /// port of each datagram relayed for UDP ASSOCIATE, whose DST.PORT is
+    /// the client's own. A denied port replies `NotAllowed`, or drops the
+    /// datagram. Allows every port by default.
+    #[allow(unused_variables)]
+    async fn allow_port(&self, port: u16) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+
+    /// Checks each address `resolve` returned before the default `connect`
+    /// dials it, so rules also apply to destinations given as domains.
+    /// Denied addresses are skipped, and when none is left the request
+    /// replies `NotAllowed`. The UDP ASSOCIATE relay checks the destination
+    /// of each datagram the same way, dropping it when none is left.
+    /// Allows every address by default.
+    #[allow(unused_variables)]
+    async fn allow_resolved_ip(&self, ip: &IpAddr) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+
+    /// Whether a peer `allow_peer` turns away, and a request that would be
+    /// answered `NotAllowed`, get the connection reset with a TCP RST
+    /// instead. No reply is sent and the linger time of the socket drops to
+    /// zero, so the reset goes out once the stream is dropped, which `serve`
+    /// does as soon as `execute` returns.
+    ///
+    /// A polite reply confirms to a scanner that a proxy listens there,
+    /// while a reset looks like nothing does. Past `allow_peer` though, the
+    /// method selection was already answered and only the reason of the
+    /// denial is hidden. Legitimate clients cannot tell the reset from a
+    /// network failure either. Disabled by default.
+    fn reset_on_deny(&self) -> bool {
+        false
+    }
+
+    /// Keep serving requests on the control connection after a BIND
+    /// completes, instead of returning from `negotiate`. Some protocols, such
+    /// as FTP in active mode, issue several BIND requests over the same
+    /// connection. Disabled by default.
+    ///
+    /// Only an overridden `bind` that returns while the client is still
+    /// connected, say once it handed the incoming connection over, makes
+    /// use of it: the default one relays until the client closes, leaving
+    /// no further request to serve.
+    fn keep_alive_after_bind(&self) -> bool {
+        false
+    }
+
+    /// Called once a request is accepted and before it is served, to bound
+    /// the number of concurrent tunnels. The returned `Permit` is held until
+    /// the command finishes. Returning an error rejects the request with a
+    /// `Failure` reply. Never limits by default.
+    async fn acquire_permit(&self) -> Result<Permit, Self::Error> {
+        Ok(Permit::default())
+    }
+
+    /// Whether the tunnel forwards a half-close. An EOF from one side is then
+    /// forwarded as a shutdown of the other side's write half, while data
+    /// keeps flowing in the opposite direction until it reaches EOF too.
+    /// Enabled by default; disable it to close the tunnel as soon as either
+    /// side reaches EOF, for peers that leave half-open connections behind.
+    fn half_close(&self) -> bool {
+        true
+    }
+
+    /// Hard cap on the lifetime of a CONNECT or BIND tunnel. Once it elapses
+    /// both ends are closed, even if data is still flowing, and
+    /// `on_transfer_complete` receives the bytes relayed so far. The clock
+    /// starts when relaying starts and is not reset by activity. Unlimited
+    /// by default.
+    fn max_session_duration(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Longest a CONNECT or BIND tunnel waits on a single read from either
+    /// side. A direction that stays silent that long closes both ends and
+    /// fails the tunnel with an `io::ErrorKind::TimedOut` error carrying
+    /// `SocksError::Timeout`. Unlike an idle timeout, which only measures
+    /// inactivity across both directions, it also fires when one direction
+    /// stalls while the other keeps busy, so it must exceed the longest
+    /// silence the relayed protocol allows. Unlimited by default.
+    fn read_timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Longest a CONNECT or BIND tunnel waits for either side to accept a
+    /// single write, catching a peer that stopped reading and let its
+    /// receive window fill up. Failing it ends the tunnel as `read_timeout`
+    /// does. Unlimited by default.
+    fn write_timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Called once per connection when the handshake ends, with the time
+    /// from the start of negotiation to the first reply to the request, so
+    /// including name resolution and dialing for CONNECT. `is_success` is
+    /// false when the handshake failed or the first reply was not
+    /// `Succeeded`, in which case `duration` runs up to the failure.
+    #[allow(unused_variables)]
+    fn on_handshake_complete(&self, duration: Duration, is_success: bool) {}
+
+    /// Called when a CONNECT or BIND tunnel goes live, right after its
+    /// success reply and before relaying starts, to log the tunnel or track
+    /// it in a connection table. `bind_addr` is the address the success
+    /// reply is about: the local address of the outbound connection for
+    /// CONNECT and of the listener for BIND. `on_transfer_complete` follows
+    /// once relaying ends, and only tunnels this was called for reach it.
+    #[allow(unused_variables)]
+    fn on_established(&self, dest_addr: &SocksAddr, bind_addr: SocketAddr) {}
+
+    /// Called when a CONNECT or BIND tunnel finishes relaying, with the
+    /// bytes relayed and, in `Transfer::duration`, how long the connection
+    /// lasted, for throughput and anomaly tracking in one place
+    #[allow(unused_variables)]
+    fn on_transfer_complete(&self, dest_addr: &SocksAddr, transfer: &Transfer) {}
+
+    /// Whether the default `connect` answers a request for a domain with
+    /// that domain and port in BND.ADDR/BND.PORT, instead of the local
+    /// address of the outbound socket. Most clients ignore BND.ADDR for
+    /// CONNECT, but some upstreams and test harnesses check that it echoes
+    /// the destination; see `Socks5Reply::reply_with_addr` for custom
+    /// `connect` implementations. Requests for an IP are unaffected.
+    /// Disabled by default.
+    fn connect_reply_domain(&self) -> bool {
+        false
+    }
+
+    /// Whether the method selection and the USERNAME/PASSWORD status are
+    /// held back and sent in the same write as the reply to the request,
+    /// so a client pipelining its handshake gets it answered in one packet
+    /// rather than one per frame, even with Nagle disabled.
+    ///
+    /// A frame is only held when the client already sent its next message,
+    /// so a client waiting for it is never starved. Frames are held on the
+    /// `ClientStream` of the session and flushed:
+    ///
+    /// - ahead of the next bytes written to it, such as the reply to the
+    ///   request, whoever writes it
+    /// - before a private sub-negotiation hands the stream to `private_auth`
+    /// - when the session ends, whether it succeeded or failed
+    ///
+    /// Disabled by default.
+    fn coalesce_replies(&self) -> bool {
+        false
+    }
+
+    /// Local address the default `connect` binds the outbound socket to
+    /// before dialing `dest_addr`, picking the source IP, and so the egress
+    /// interface, per destination for policy-based routing. A zero port
+    /// lets the system pick one. The family must match the destination:
+    /// an IPv4 address cannot reach an IPv6 destination, so the resolved
+    /// addresses of the other family fail to dial. None by default, which
+    /// leaves the choice to the routing table.
+    #[allow(unused_variables)]
+    fn outbound_bind_addr(&self, dest_addr: &SocksAddr) -> Option<SocketAddr> {
+        None
+    }
+
+    /// PROXY protocol header the default `connect` sends to the destination
+    /// before anything else, carrying the address of the client and of the
+    /// destination, for backends that log or filter on the real client IP.
+    /// It is written once the connection is established, before the success
+    /// reply, so a failure to write it fails the request. None by default,
+    /// which sends no header.
+    #[cfg(feature = "proxy-protocol")]
+    fn send_proxy_protocol(&self) -> Option<ProxyVersion> {
+        None
+    }
+
+    /// Time the default `connect` allows each attempt to dial the
+    /// destination, all of its resolved addresses included. Once it elapses
+    /// the attempt fails with `SocksError::Timeout`, which is retried like
+    /// any timeout and otherwise replies `TTLExpired`. Only the system
+    /// timeout applies by default.
+    fn connect_timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Number of times the default `connect` dials again after a transient
+    /// failure, a timeout or a refused connection, before giving up. Other
+    /// errors fail the request at once. Never retries by default.
+    fn connect_retries(&self) -> u32 {
+        0
+    }
+
+    /// Delay before the first retry of `connect_retries`, doubled after each
+    /// further attempt. 100 milliseconds by default.
+    fn retry_backoff(&self) -> Duration {
+        Duration::from_millis(100)
+    }
+
+    /// Called by the default `connect` with the outbound connection, before
+    /// the success reply, and relays the stream it returns. Override it to
+    /// prepare or inspect the connection, such as setting socket options or
+    /// recording where it goes; an error fails the request. Returns the
+    /// stream unchanged by default.
+    #[allow(unused_variables)]
+    async fn wrap_remote(
+        &self,
+        stream: TcpStream,
+        dest_addr: &SocksAddr,
+    ) -> Result<TcpStream, Self::Error> {
+        Ok(stream)
+    }
+
+    /// Pipes bytes between the client and the remote once a CONNECT or BIND
+    /// succeeded and its replies were sent. Override it to wrap, inspect or
+    /// record the raw streams; the returned `Transfer` is passed on to
+    /// `on_transfer_complete`. Uses `relay_tcp` with `half_close`,
+    /// `max_session_duration`, `read_timeout` and `write_timeout` by default.
+    async fn on_relay(
+        &self,
+        client: &mut ClientStream<'_>,
+        remote: &mut TcpStream,
+    ) -> Result<Transfer, Self::Error> {
+        let transfer = relay_client(
+            client,
+            remote,
+            self.half_close(),
+            self.max_session_duration(),
+            self.read_timeout(),
+            self.write_timeout(),
+        )
+        .await?;
+
+        Ok(transfer)
+    }
+
+    /// Resolves the destination into the socket addresses the default
+    /// `connect` dials, in order. A failed lookup is reported as
+    /// `io::ErrorKind::HostUnreachable`, which replies `HostUnreachable`.
+    /// With the `dns-cache` feature, lookups go through `dns_cache` when it
+    /// returns a cache.
+    ///
+    /// The default `connect` drops the lookup when the client disconnects
+    /// before it completes, so a slow resolver does not hold on to clients
+    /// that gave up.
+    async fn resolve(&self, dest_addr: &SocksAddr) -> Result<Vec<SocketAddr>, Self::Error> {
+        #[cfg(feature = "dns-cache")]
+        if let Some(dns_cache) = self.dns_cache() {
+            return Ok(dns_cache.resolve(dest_addr).await?);
+        }
+
+        Ok(dest_addr.resolved_sorted(IpPref::System).await?)
+    }
+
+    /// Whether the default `connect` tolerates clients that wrongly append
+    /// the port to DST.ADDR, as in `example.com:80`. When resolving such a
+    /// domain fails, the `:port` suffix is stripped and the rest resolved
+    /// at DST.PORT, the embedded port being ignored even when it differs.
+    ///
+    /// It makes requests ambiguous: a domain that does not resolve is
+    /// retried as another one, which allow rules written for the domain as
+    /// sent do not expect. Disabled by default, which is strict.
+    fn lenient_domain_port(&self) -> bool {
+        false
+    }
+
+    /// Cache the default `resolve` answers from, usually a field of the
+    /// handler so every connection shares it. None by default, which
+    /// resolves every request.
+    #[cfg(feature = "dns-cache")]
+    fn dns_cache(&self) -> Option<&DnsCache> {
+        None
+    }
+
+    /// Prefix of the domains the default `connect` routes to a Unix socket
+    /// instead of dialing them over TCP, such as `unix:`, following the
+    /// convention of `unix_socket::unix_socket_path`.
+    ///
+    /// Such a request is answered `Succeeded` with BND.ADDR `0.0.0.0:0`,
+    /// there being no TCP address, and relayed with `relay` rather than
+    /// `on_relay`, which only takes TCP streams. `allow_port` still screens
+    /// the port of the request and `connect_timeout` bounds the connection,
+    /// but nothing is resolved nor passed to `wrap_remote`. A missing socket
+    /// replies `Failure`, a socket nothing listens on `ConnectionRefused`.
+    ///
+    /// Every Unix socket the server may open becomes reachable by clients,
+    /// so only set it for trusted clients. None by default, which routes
+    /// nothing to Unix sockets.
+    #[cfg(all(unix, feature = "unix-socket"))]
+    fn unix_socket_prefix(&self) -> Option<&str> {
+        None
+    }
+
+    /// Dials `dest_addr` and relays between it and the client. The
+    /// `Succeeded` reply carries the local address of the outbound socket,
+    /// the BND.ADDR of RFC 1928, so its ATYP follows the family of the
+    /// destination that was reached. An error returned before replying is
+    /// answered by `Socks5` with the server address of the client connection
+    /// instead, the destination possibly never having been dialed.
+    ///
+    /// A failed dial is never relayed. Its error is answered with the
+    /// matching reply, `ConnectionRefused` for a refused connection as
+    /// picked by `Socks5Reply::from_io_error`, after which `execute` shuts
+    /// the client connection down. A custom `connect`, or `bind`, fails
+    /// with any reply code, vendor-specific ones included, by returning
+    /// `SocksError::Reply` before replying.
     async fn connect(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream<'_>,
         dest_addr: &SocksAddr,
     ) -> Result<(), Self::Error> {
-        let mut connect_stream = TcpStream::connect((dest_addr.domain(), dest_addr.port())).await?;
+        #[cfg(all(unix, feature = "unix-socket"))]
+        if let Some(path) = self
+            .unix_socket_prefix()
+            .and_then(|prefix| unix_socket::unix_socket_path(dest_addr, prefix))
+        {
+            return connect_unix(self, stream, dest_addr, path).await;
+        }
+
+        let resolve = async {
+            let fallback = match self.lenient_domain_port() {
+                true => without_domain_port(dest_addr),
+                false => None,
+            };
+            let fallback = match (self.resolve(dest_addr).await, fallback) {
+                (Err(_), Some(fallback)) => fallback,
+                (result, _) => return result,
+            };
+
+            self.resolve(&fallback).await
+        };
+
+        // A client that gave up while the name resolves would otherwise keep
+        // the lookup, and this task, alive until the resolver answers
+        let addrs = select! {
+            addrs = resolve => addrs?,
+            result = client_closed(stream) => {
+                result?;
+                return Err(SocksError::ClientDisconnected.into());
+            }
+        };
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::HostUnreachable,
+                format!("no addresses resolved for {}", dest_addr.domain()),
+            )
+            .into());
+        }
+
+        let mut allowed_addrs = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            if self.allow_resolved_ip(&addr.ip()).await? {
+                allowed_addrs.push(addr);
+            }
+        }
+        if allowed_addrs.is_empty() {
+            return Err(SocksError::DestinationNotAllowed(dest_addr.clone()).into());
+        }
+        let addrs = allowed_addrs;
+
+        let bind_addr = self.outbound_bind_addr(dest_addr);
+        let mut retries = 0;
+        let mut backoff = self.retry_backoff();
+        let connect_stream = loop {
+            let attempt = dial_from(&addrs, bind_addr);
+            let result = match self.connect_timeout() {
+                Some(timeout) => time::timeout(timeout, attempt)
+                    .await
+                    .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+                None => attempt.await,
+            };
+
+            match result {
+                Ok(connect_stream) => break connect_stream,
+                Err(err)
+                    if retries < self.connect_retries()
+                        && matches!(
+                            err.kind(),
+                            io::ErrorKind::TimedOut | io::ErrorKind::ConnectionRefused
+                        ) =>
+                {
+                    retries += 1;
+                    time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+                    return Err(SocksError::Timeout {
+                        phase: TimeoutPhase::Connect,
+                    }
+                    .into())
+                }
+                Err(err) => return Err(err.into()),
+            }
+        };
         let bind_addr = connect_stream.local_addr()?;
-        Socks5Reply::Succeeded.reply(stream, bind_addr).await?;
+        let mut connect_stream = self.wrap_remote(connect_stream, dest_addr).await?;
+        #[cfg(feature = "proxy-protocol")]
+        if let Some(version) = self.send_proxy_protocol() {
+            proxy_protocol::write_header(&mut connect_stream, version, stream.peer_addr()?).await?;
+        }
+        match dest_addr {
+            SocksAddr::Domain(..) if self.connect_reply_domain() => {
+                Socks5Reply::Succeeded
+                    .reply_with_addr(stream, dest_addr)
+                    .await?;
+            }
+            _ => {
+                let reply_bind_addr = advertised(self.advertised_addr(), bind_addr);
+                Socks5Reply::Succeeded
+                    .reply(stream, reply_addr(self, reply_bind_addr))
+                    .await?;
+            }
+        }
+
+        self.on_established(dest_addr, bind_addr);
 
-        io::copy_bidirectional(stream, &mut connect_stream).await?;
+        let transfer = self.on_relay(stream, &mut connect_stream).await?;
+        self.on_transfer_complete(dest_addr, &transfer);
 
         Ok(())
     }
 
-    async fn bind(&self, stream: &mut TcpStream, dest_addr: &SocksAddr) -> Result<(), Self::Error> {
-        let listener = TcpListener::bind((dest_addr.domain(), dest_addr.port())).await?;
-        let bind_addr = listener.local_addr()?.clone();
-        Socks5Reply::Succeeded.reply(stream, bind_addr).await?;
+    /// Length of the queue of pending connections on the listener of the
+    /// default `bind`, for servers expecting bursts of inbound connections.
+    /// Defaults to `listener::DEFAULT_BACKLOG`, the backlog of
+    /// `TcpListener::bind`.
+    fn bind_backlog(&self) -> u32 {
+        DEFAULT_BACKLOG
+    }
 
-        let (mut bind_stream, peer_addr) = listener.accept().await?;
+    /// Domain reported in the first reply of the default `bind`, together
+    /// with the listening port, instead of the listening IP. Useful when the
+    /// server listens on an interface known by a hostname; see
+    /// `Socks5Reply::reply_with_addr` for client support. Reports the IP by
+    /// default.
+    fn bind_reply_domain(&self) -> Option<String> {
+        None
+    }
 
-        Socks5Reply::Succeeded.reply(stream, peer_addr).await?;
-        io::copy_bidirectional(stream, &mut bind_stream).await?;
+    async fn bind(
+        &self,
+        stream: &mut ClientStream<'_>,
+        dest_addr: &SocksAddr,
+    ) -> Result<(), Self::Error> {
+        let listener =
+            bind_listener((dest_addr.domain(), dest_addr.port()), self.bind_backlog()).await?;
+        let bind_addr = listener.local_addr()?;
+        match self.bind_reply_domain() {
+            Some(domain) => {
+                let listen_addr = SocksAddr::Domain(domain, bind_addr.port());
+                Socks5Reply::Succeeded
+                    .reply_with_addr(stream, &listen_addr)
+                    .await?;
+            }
+            None => {
+                let listen_addr = advertised(self.advertised_addr(), bind_addr);
+                Socks5Reply::reply_bind_listening(stream, reply_addr(self, listen_addr)).await?;
+            }
+        }
+
+        // The client may give up on the BIND by closing the control
+        // connection, which must not leave the listener behind
+        let (mut bind_stream, peer_addr) = select! {
+            result = listener.accept() => result?,
+            result = client_closed(stream) => {
+                result?;
+                return Err(SocksError::ClientDisconnected.into());
+            }
+        };
+
+        Socks5Reply::reply_bind_connected(stream, reply_addr(self, peer_addr)).await?;
+        self.on_established(dest_addr, bind_addr);
+        let transfer = self.on_relay(stream, &mut bind_stream).await?;
+        self.on_transfer_complete(dest_addr, &transfer);
 
         Ok(())
     }
 
+    /// Write IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) in replies as
+    /// plain IPv4 with ATYP X'01', which is what a server listening on a
+    /// dual-stack socket usually means for its IPv4 clients. Disabled by
+    /// default, replies then carry the literal IPv6 address.
+    fn unmap_ipv4_replies(&self) -> bool {
+        false
+    }
+
+    /// Address advertised in place of the server's own in the success
+    /// replies of the default commands, such as the proxy's public address
+    /// when it runs behind NAT. A zero port is replaced with the port of the
+    /// socket the reply is about. Per command:
+    ///
+    /// - BIND: the first reply, the address the application server must
+    ///   connect to, and where advertising matters most. The second reply
+    ///   carries the connecting host and is left alone.
+    /// - CONNECT: the reply, instead of the local address of the outbound
+    ///   socket, unless `connect_reply_domain` echoes a domain.
+    /// - UDP ASSOCIATE: the reply, unless `udp_advertised_addr` is set.
+    ///
+    /// `bind_reply_domain` takes precedence over it for BIND. None by
+    /// default, replies then carry the addresses the sockets are bound to.
+    fn advertised_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Address advertised to the client in the UDP ASSOCIATE reply.
+    ///
+    /// The relay socket binds to a wildcard address, which is not something
+    /// a client can send datagrams to. By default the reply carries the IP
+    /// of the control connection's local address together with the port of
+    /// the relay socket. Return `Some` to advertise another address, such as
+    /// the proxy's external IP when it runs behind NAT. A zero port is
+    /// replaced with the port of the relay socket. Falls back to
+    /// `advertised_addr` by default.
+    fn udp_advertised_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Size of the buffer the UDP ASSOCIATE relay receives datagrams in.
+    /// Larger datagrams are truncated, so lowering it only suits clients
+    /// known to send small datagrams. Defaults to `udp::MAX_DATAGRAM_SIZE`.
+    fn udp_buffer_size(&self) -> usize {
+        udp::MAX_DATAGRAM_SIZE
+    }
+
+    /// Whether the UDP ASSOCIATE relay reassembles fragmented datagrams,
+    /// those with a nonzero FRAG, instead of dropping them as RFC 1928
+    /// requires from servers without fragmentation support. Datagrams sent
+    /// back to the client are never fragmented. Disabled by default.
+    fn supports_fragmentation(&self) -> bool {
+        false
+    }
+
+    /// Called for each client datagram the UDP ASSOCIATE relay drops, with
+    /// the address it came from and the reason, such as a nonzero RSV or a
+    /// truncated header. `on_udp_complete` receives the counts per reason.
+    /// Such datagrams are dropped silently by default; override it to log
+    /// clients that send garbage.
+    #[allow(unused_variables)]
+    fn on_udp_drop(&self, src_addr: &SocketAddr, reason: udp::UdpDropReason) {}
+
+    /// Called when a UDP association ends, with its counters
     #[allow(unused_variables)]
+    fn on_udp_complete(&self, client_addr: &SocksAddr, stats: &udp::UdpStats) {}
+
+    /// Inclusive range of ports the UDP ASSOCIATE relay socket binds in,
+    /// tried in order until one is free. If none is, the request fails with
+    /// a `Failure` reply. Any ephemeral port by default.
+    fn udp_bind_port_range(&self) -> Option<(u16, u16)> {
+        None
+    }
+
+    /// Whether the default `associate` serves UDP ASSOCIATE. When disabled,
+    /// it binds nothing and fails with `SocksError::UnsupportedCommand`,
+    /// which replies `UnsupportedCommand`. Disabled by default: a handler
+    /// opts in to the UDP relay, so one written for TCP does not open relay
+    /// sockets it never meant to.
+    fn udp_enabled(&self) -> bool {
+        false
+    }
+
+    /// Replies `UnsupportedCommand` unless `udp_enabled`. Otherwise binds
+    /// one relay socket and relays through it until the control
+    /// connection closes. A control connection carries a single
+    /// association: `udp::relay` answers any further request on it with a
+    /// `Failure` reply, so a client cannot open relay sockets
+    /// without bound.
     async fn associate(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream<'_>,
         dest_addr: &SocksAddr,
     ) -> Result<(), Self::Error> {
-        // let udp_socket = UdpSocket::bind((dest_addr.domain(), dest_addr.port())).await?;
-        // let bind_addr = udp_socket.local_addr()?.clone();
-        // Socks5Reply::Succeeded.reply(stream, bind_addr).await?;
-
-        // loop {
-        //     // +----+------+------+----------+----------+----------+
-        //     // |RSV | FRAG | ATYP | DST.ADDR | DST.PORT |   DATA   |
-        //     // +----+------+------+----------+----------+----------+
-        //     // | 2  |  1   |  1   | Variable |    2     | Variable |
-        //     // +----+------+------+----------+----------+----------+
-        //     //     The fields in the UDP request header are:
-
-        //     //   o  RSV  Reserved X'0000'
-        //     //   o  FRAG    Current fragment number
-        //     //   o  ATYP    address type of following addresses:
-        //     //      o  IP V4 address: X'01'
-        //     //      o  DOMAINNAME: X'03'
-        //     //      o  IP V6 address: X'04'
-        //     //   o  DST.ADDR       desired destination address
-        //     //   o  DST.PORT       desired destination port
-        //     //   o  DATA     user data
-
-        //     let mut buf = vec![0u8; 65535];
-        //     if let Ok((size, peer_addr)) = udp_socket.recv_from(&mut buf).await {
-        //         if buf[0] != 0 || buf[1] != 0 {
-        //             continue;
-        //         }
-
-        //         let addr_type: Socks5AddrType = buf[3].try_into()?;
-
-        //         let (dist_addr, offset) = match addr_type {
-        //             Socks5AddrType::IPV4 => {
-        //                 let mut buf = [0; 4];
-        //                 stream.read_exact(&mut buf).await?;
-
-        //                 let ip = Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]);
-        //                 let port = stream.read_u16().await?;
-
-        //                 (SocksAddr::IPV4(SocketAddrV4::new(ip, port)), 3 + 4)
-        //             }
-        //             Socks5AddrType::Domain => {
-        //                 let length = stream.read_u8().await?;
-        //                 let mut buf = vec![0; length as usize];
-        //                 stream.read_exact(&mut buf).await?;
-
-        //                 let domain =
-        //                     String::from_utf8(buf).map_err(SocksError::Utf8BytesToStringError)?;
-        //                 let port = stream.read_u16().await?;
-
-        //                 (SocksAddr::Domain(domain, port), 3 + length)
-        //             }
-        //             Socks5AddrType::IPV6 => {
-        //                 let mut buf = [0; 16];
-        //                 stream.read_exact(&mut buf).await?;
-
-        //                 let ip = Ipv6Addr::new(
-        //                     u16::from_be_bytes([buf[0], buf[1]]),
-        //                     u16::from_be_bytes([buf[2], buf[3]]),
-        //                     u16::from_be_bytes([buf[4], buf[5]]),
-        //                     u16::from_be_bytes([buf[6], buf[7]]),
-        //                     u16::from_be_bytes([buf[8], buf[9]]),
-        //                     u16::from_be_bytes([buf[10], buf[11]]),
-        //                     u16::from_be_bytes([buf[12], buf[13]]),
-        //                     u16::from_be_bytes([buf[14], buf[15]]),
-        //                 );
-        //                 let port = stream.read_u16().await?;
-
-        //                 (SocksAddr::IPV6(SocketAddrV6::new(ip, port, 0, 0)), 3 + 16)
-        //             }
-        //         };
-        //         let data = &buf[offset as usize..size];
-        //         udp_socket.send_to(buf, dist_addr).await.unwrap();
-        //     }
-        // }
-
-        // Ok(())
-        unimplemented!()
+        if !self.udp_enabled() {
+            return Err(SocksError::UnsupportedCommand(Socks5Command::Associate.into()).into());
+        }
+
+        let local_addr = stream.local_addr()?;
+        let unspecified_ip = match local_addr {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let udp_socket = match self.udp_bind_port_range() {
+            Some((start, end)) => {
+                let mut udp_socket = None;
+                for port in start..=end {
+                    if let Ok(socket) = UdpSocket::bind((unspecified_ip, port)).await {
+                        udp_socket = Some(socket);
+                        break;
+                    }
+                }
+
+                udp_socket.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!("No free UDP port in {}-{}", start, end),
+                    )
+                })?
+            }
+            None => UdpSocket::bind((unspecified_ip, 0)).await?,
+        };
+        let udp_port = udp_socket.local_addr()?.port();
+
+        let advertised_addr = self.udp_advertised_addr().or(self.advertised_addr());
+        let bind_addr = advertised(advertised_addr, SocketAddr::new(local_addr.ip(), udp_port));
+        Socks5Reply::Succeeded
+            .reply(stream, reply_addr(self, bind_addr))
+            .await?;
+
+        udp::relay(self, stream, &udp_socket, dest_addr).await?;
+
+        Ok(())
+    }
+}
+
+/// CONNECT to a Unix socket, see `Socks5Handler::unix_socket_prefix`
+#[cfg(all(unix, feature = "unix-socket"))]
+async fn connect_unix<H: Socks5Handler + ?Sized>(
+    handler: &H,
+    stream: &mut ClientStream<'_>,
+    dest_addr: &SocksAddr,
+    path: &std::path::Path,
+) -> Result<(), H::Error> {
+    let attempt = tokio::net::UnixStream::connect(path);
+    let mut connect_stream = match handler.connect_timeout() {
+        Some(timeout) => {
+            time::timeout(timeout, attempt)
+                .await
+                .map_err(|_| SocksError::Timeout {
+                    phase: TimeoutPhase::Connect,
+                })??
+        }
+        None => attempt.await?,
+    };
+
+    let bind_addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+    Socks5Reply::Succeeded.reply(stream, bind_addr).await?;
+    handler.on_established(dest_addr, bind_addr);
+
+    let transfer = relay(
+        stream,
+        &mut connect_stream,
+        handler.half_close(),
+        handler.max_session_duration(),
+        handler.read_timeout(),
+        handler.write_timeout(),
+    )
+    .await?;
+    handler.on_transfer_complete(dest_addr, &transfer);
+
+    Ok(())
+}
+
+/// `dest_addr` without the port a client appended to its domain, see
+/// `Socks5Handler::lenient_domain_port`. Only a numeric port after a host
+/// without colons counts, which leaves IPv6 literals alone.
+fn without_domain_port(dest_addr: &SocksAddr) -> Option<SocksAddr> {
+    let SocksAddr::Domain(domain, port) = dest_addr else {
+        return None;
+    };
+    let (host, embedded_port) = domain.rsplit_once(':')?;
+    if host.is_empty() || host.contains(':') || embedded_port.parse::<u16>().is_err() {
+        return None;
+    }
+
+    Some(SocksAddr::Domain(host.to_string(), *port))
+}
+
+/// Address written in a reply, see `Socks5Handler::unmap_ipv4_replies`
+fn reply_addr<H: Socks5Handler + ?Sized>(handler: &H, addr: SocketAddr) -> SocketAddr {
+    if handler.unmap_ipv4_replies() {
+        SocketAddr::new(addr.ip().to_canonical(), addr.port())
+    } else {
+        addr
     }
 }
 
+/// Drops the linger time of `stream` to zero, so that closing it resets the
+/// connection, see `Socks5Handler::reset_on_deny`. Later tokio releases
+/// deprecate `set_linger` since a linger time blocks the thread on drop,
+/// which a zero one does not.
+#[allow(deprecated)]
+fn arm_reset(stream: &TcpStream) -> io::Result<()> {
+    stream.set_linger(Some(Duration::ZERO))
+}
+
 struct HandshakeError {
     err: SocksError,
     reply: Socks5Reply,
@@ -193,52 +862,247 @@ impl From<io::Error> for HandshakeError {
 }
 
 /// https://datatracker.ietf.org/doc/html/rfc1928
-#[derive(Clone, Debug)]
+///
+/// The control connection is a `TcpStream`: `negotiate` and `execute` take
+/// one, and the handler hooks get the `ClientStream` wrapping it, socket
+/// options, peeking and splice(2) relaying relying on it. SOCKS over TLS
+/// is thus served by terminating TLS in front of the server, such as in a
+/// TLS-terminating load balancer or stunnel, and not by handing it a TLS
+/// stream.
 pub struct Socks5<H: Socks5Handler + Send + Sync> {
-    #[allow(dead_code)]
     peer_addr: SocketAddr,
     local_addr: SocketAddr,
-    handler: H,
+    handler: Arc<H>,
+    strict: bool,
+    #[cfg(feature = "tap")]
+    tap: Option<Box<dyn Tap + Send + Sync>>,
+}
+
+// Implemented by hand, as deriving would require the handler to be `Clone`
+// and `Debug` while only the `Arc` around it is cloned
+impl<H: Socks5Handler + Send + Sync> Clone for Socks5<H> {
+    fn clone(&self) -> Self {
+        Self {
+            peer_addr: self.peer_addr,
+            local_addr: self.local_addr,
+            handler: self.handler.clone(),
+            strict: self.strict,
+            #[cfg(feature = "tap")]
+            tap: None,
+        }
+    }
+}
+
+impl<H: Socks5Handler + Send + Sync> fmt::Debug for Socks5<H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Socks5")
+            .field("peer_addr", &self.peer_addr)
+            .field("local_addr", &self.local_addr)
+            .field("strict", &self.strict)
+            .finish_non_exhaustive()
+    }
 }
 
 impl<H: Socks5Handler + Send + Sync> Socks5<H> {
     pub const VERSION: u8 = 0x05;
     pub const SUB_NEGOTIATION: u8 = 0x01;
 
-    pub fn new(peer_addr: SocketAddr, local_addr: SocketAddr, handler: H) -> Self {
+    /// `handler` is shared rather than owned, so passing an `Arc` serves
+    /// many connections with a single handler instance.
+    pub fn new(peer_addr: SocketAddr, local_addr: SocketAddr, handler: impl Into<Arc<H>>) -> Self {
         Self {
             peer_addr,
             local_addr,
-            handler,
+            handler: handler.into(),
+            strict: false,
+            #[cfg(feature = "tap")]
+            tap: None,
         }
     }
 
+    /// In strict mode `negotiate` reads the leading VER byte itself and
+    /// rejects anything but X'05' with `SocksError::UnsupportedVersion`.
+    ///
+    /// `Socks::from_stream` has already consumed the version byte to pick the
+    /// protocol, so strict mode is only meant for driving `Socks5` directly
+    /// on a fresh stream.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Copies every byte the session reads from or writes to the client
+    /// into `tap`, see `Socks::with_tap`. A clone starts without a tap.
+    #[cfg(feature = "tap")]
+    pub fn with_tap(mut self, tap: impl Tap + Send + Sync + 'static) -> Self {
+        self.tap = Some(Box::new(tap));
+        self
+    }
+
     pub async fn execute(&mut self, stream: &mut TcpStream) -> Result<(), SocksError> {
         match self.negotiate(stream).await {
             Ok(_) => Ok(()),
+            // Nothing is left to shut down
+            Err(err) if SocksError::is_client_disconnected(&err) => {
+                Err(SocksError::ClientDisconnected)
+            }
             Err(err) => {
-                stream.shutdown().await?;
+                // A reset armed by `reset_on_deny` must not be preceded by a FIN
+                if !matches!(stream.linger(), Ok(Some(linger)) if linger.is_zero()) {
+                    stream.shutdown().await?;
+                }
                 Err(SocksError::ExecuteError(err.to_string()))
             }
         }
     }
 
+    /// Negotiation is one-shot: a single command is served and the method
+    /// returns once it completes. The only exception is BIND, where
+    /// [`Socks5Handler::keep_alive_after_bind`] lets the control connection
+    /// carry further requests.
+    ///
+    /// The stream must start right after the VER byte, as `Socks::from_stream`
+    /// leaves it, unless strict mode is on. See `negotiate_full` for a
+    /// stream that starts with VER.
     pub async fn negotiate(&mut self, stream: &mut TcpStream) -> Result<(), H::Error> {
-        let method = match self.negotiate_method(stream).await {
+        self.negotiate_with_outcome(stream).await?;
+
+        Ok(())
+    }
+
+    /// Same as `negotiate` on a stream that starts with the VER byte, for
+    /// driving `Socks5` on its own rather than through `Socks`. VER is read
+    /// and validated first, and anything but X'05' fails with
+    /// `SocksError::UnsupportedVersion`, as in strict mode, whatever the
+    /// mode is.
+    pub async fn negotiate_full(&mut self, stream: &mut TcpStream) -> Result<(), H::Error> {
+        self.negotiate_reporting(stream, true, true).await?;
+
+        Ok(())
+    }
+
+    /// Same as `negotiate`, also reporting the negotiated method, the
+    /// request and the reply it was answered with.
+    pub async fn negotiate_with_outcome(
+        &mut self,
+        stream: &mut TcpStream,
+    ) -> Result<Socks5Outcome, H::Error> {
+        self.negotiate_reporting(stream, self.strict, true).await
+    }
+
+    /// Runs the negotiation up to the request and returns its command and
+    /// destination without serving it, for callers that relay on their own.
+    /// The method and authentication replies are sent, and so is the reply
+    /// to a request refused by the policy hooks, such as `allow_command` or
+    /// `allow_port`, which fails as with `negotiate`.
+    ///
+    /// The request itself is left unanswered: the caller must send the final
+    /// reply, such as with `Socks5Reply::reply`, before relaying anything,
+    /// or the client keeps waiting. `connect`, `bind`, `associate` and
+    /// `acquire_permit` are not called, and neither is
+    /// `on_handshake_complete` once the request is read. The stream starts
+    /// as for `negotiate`.
+    pub async fn negotiate_only(
+        &mut self,
+        stream: &mut TcpStream,
+    ) -> Result<(Socks5Command, SocksAddr), H::Error> {
+        let outcome = self.negotiate_reporting(stream, self.strict, false).await?;
+
+        Ok((outcome.command, outcome.dest_addr))
+    }
+
+    /// Negotiates a session, serving the request when `dispatch` is set and
+    /// returning as soon as it is read otherwise
+    async fn negotiate_reporting(
+        &mut self,
+        stream: &mut TcpStream,
+        read_version: bool,
+        dispatch: bool,
+    ) -> Result<Socks5Outcome, H::Error> {
+        let started = Instant::now();
+        let mut reported = false;
+        let stream = &mut ClientStream::new(stream);
+        #[cfg(feature = "tap")]
+        stream.set_tap(self.tap.take());
+
+        let session = async {
+            let result = self
+                .negotiate_session(stream, read_version, dispatch, started, &mut reported)
+                .await;
+            // Frames still held back go out whatever the outcome
+            let flushed = stream
+                .flush()
+                .await
+                .map_err(|err| SocksError::from_reply_error(err).into());
+
+            result.and_then(|outcome| flushed.map(|_| outcome))
+        };
+        let result = CONNECTION_STARTED.scope(started, session).await;
+
+        // Failed before the handler replied, or without the handler at all
+        if result.is_err() && !reported {
+            self.handler.on_handshake_complete(started.elapsed(), false);
+        }
+
+        #[cfg(feature = "tap")]
+        {
+            self.tap = stream.take_tap();
+        }
+
+        result
+    }
+
+    async fn negotiate_session(
+        &self,
+        stream: &mut ClientStream<'_>,
+        read_version: bool,
+        dispatch: bool,
+        started: Instant,
+        reported: &mut bool,
+    ) -> Result<Socks5Outcome, H::Error> {
+        if !self.handler.allow_peer(&self.peer_addr).await? {
+            if self.handler.reset_on_deny() {
+                arm_reset(stream)?;
+            } else {
+                stream.shutdown().await?;
+            }
+            return Err(SocksError::PeerNotAllowed(self.peer_addr).into());
+        }
+
+        let mut budget = HandshakeBudget::new(self.handler.max_handshake_size());
+
+        if read_version {
+            let version = stream.read_u8().await?;
+            budget.consume(1)?;
+            if version != Self::VERSION {
+                return Err(SocksError::UnsupportedVersion(version).into());
+            }
+        }
+
+        let method = match self.negotiate_method(stream, &mut budget).await {
             Ok(val) => {
                 self.negotiate_method_reply(stream, val).await?;
                 val
             }
+            // Nobody is left to answer
+            Err(err) if SocksError::is_client_disconnected(&err) => return Err(err),
+            // The client must close the connection on X'FF', there is nothing
+            // left to negotiate
             Err(err) => {
                 self.negotiate_method_reply(stream, Socks5Method::Unacceptable)
                     .await?;
+                stream.shutdown().await?;
                 return Err(err);
             }
         };
 
-        match self.auth(stream, &method).await {
+        match self.auth(stream, &method, &mut budget).await {
             Ok(is_success) => {
                 self.auth_reply(stream, &method, is_success).await?;
+
+                if !is_success {
+                    return Err(SocksError::AuthFailed.into());
+                }
             }
             Err(err) => {
                 self.auth_reply(stream, &method, false).await?;
@@ -246,103 +1110,246 @@ impl<H: Socks5Handler + Send + Sync> Socks5<H> {
             }
         };
 
-        let (command, address) = match self.negotiate_request(stream).await {
-            Ok(val) => val,
-            Err(err) => {
-                err.reply.reply(stream, self.local_addr).await?;
-                return Err(err.err.into());
+        loop {
+            let mut guard = ReplyGuard::new(reply_addr(&*self.handler, self.local_addr));
+
+            let (command, address) = match self.negotiate_request(stream, &mut budget).await {
+                Ok(val) => val,
+                Err(err) => {
+                    if !self.reset_denied(stream, &guard, err.reply)? {
+                        guard
+                            .reply(stream, err.reply)
+                            .await
+                            .map_err(SocksError::from_reply_error)?;
+                    }
+                    return Err(err.err.into());
+                }
+            };
+
+            if !dispatch {
+                return Ok(Socks5Outcome {
+                    peer_addr: self.peer_addr,
+                    method,
+                    command,
+                    dest_addr: address,
+                    reply: None,
+                });
             }
-        };
 
-        match command {
-            Socks5Command::Connect => self.connect(stream, &address).await?,
-            Socks5Command::Bind => self.bind(stream, &address).await?,
-            Socks5Command::Associate => self.associate(stream, &address).await?,
-        };
+            let _permit = match self.handler.acquire_permit().await {
+                Ok(val) => val,
+                Err(err) => {
+                    guard
+                        .reply(stream, Socks5Reply::Failure)
+                        .await
+                        .map_err(SocksError::from_reply_error)?;
+                    return Err(err);
+                }
+            };
+
+            guard.track(stream, &command);
+            let serve = match command {
+                Socks5Command::Connect => self.handler.connect(stream, &address),
+                Socks5Command::Bind => self.handler.bind(stream, &address),
+                Socks5Command::Associate => self.handler.associate(stream, &address),
+            };
+
+            let result = guard
+                .serve(serve, |reply| {
+                    if !*reported {
+                        *reported = true;
+                        self.handler.on_handshake_complete(
+                            started.elapsed(),
+                            reply == Socks5Reply::Succeeded,
+                        );
+                    }
+                })
+                .await;
+
+            if let Err(err) = result {
+                let reply = Socks5Reply::from_error(&err);
+                if !self.reset_denied(stream, &guard, reply)? {
+                    guard
+                        .reply(stream, reply)
+                        .await
+                        .map_err(SocksError::from_reply_error)?;
+                }
+                return Err(err);
+            }
 
-        Ok(())
+            let outcome = Socks5Outcome {
+                peer_addr: self.peer_addr,
+                method,
+                command,
+                dest_addr: address,
+                reply: guard.last_reply(),
+            };
+
+            if command != Socks5Command::Bind || !self.handler.keep_alive_after_bind() {
+                return Ok(outcome);
+            }
+
+            // The client closed the control connection instead of sending
+            // another request, which ends the session normally.
+            if stream.peek(&mut [0; 1]).await? == 0 {
+                return Ok(outcome);
+            }
+
+            budget = HandshakeBudget::new(self.handler.max_handshake_size());
+        }
+    }
+
+    /// Arms the reset of `reset_on_deny` in place of `reply` when it is a
+    /// `NotAllowed` the request was not answered with yet, returning
+    /// whether the reply must be skipped.
+    fn reset_denied(
+        &self,
+        stream: &TcpStream,
+        guard: &ReplyGuard,
+        reply: Socks5Reply,
+    ) -> io::Result<bool> {
+        if reply != Socks5Reply::NotAllowed
+            || guard.last_reply().is_some()
+            || !self.handler.reset_on_deny()
+        {
+            return Ok(false);
+        }
+
+        arm_reset(stream)?;
+        Ok(true)
     }
 
     /// The client connects to the server, and sends a version
     /// identifier/method selection message:
     ///  
+    /// ```text
     ///                     +----+----------+----------+
     ///                     |VER | NMETHODS | METHODS  |
     ///                     +----+----------+----------+
     ///                     | 1  |    1     | 1 to 255 |
     ///                     +----+----------+----------+
+    /// ```
     ///  
     /// The VER field is set to X'05' for this version of the protocol.  The
     /// NMETHODS field contains the number of method identifier octets that
     /// appear in the METHODS field.
-    async fn negotiate_method(&self, stream: &mut TcpStream) -> Result<Socks5Method, H::Error> {
-        let method_length = stream.read_u8().await?;
-        let mut methods = vec![0; method_length as usize];
-        stream.read_exact(&mut methods).await?;
-
-        let methods: Vec<Socks5Method> = methods.iter().map(|&v| v.into()).collect();
+    async fn negotiate_method(
+        &self,
+        stream: &mut ClientStream<'_>,
+        budget: &mut HandshakeBudget,
+    ) -> Result<Socks5Method, H::Error> {
+        // A client closing right after VER, as scanners do, merely went away
+        let method_length = match stream.read_u8().await {
+            Ok(method_length) => method_length as usize,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(SocksError::ClientDisconnected.into())
+            }
+            Err(err) => return Err(err.into()),
+        };
+        // NMETHODS fits in an octet, so both lists live on the stack
+        budget.consume(1 + method_length)?;
+        let mut raw = [0; u8::MAX as usize];
+        let raw = &mut raw[..method_length];
+        stream.read_exact(raw).await?;
+        self.handler.on_methods_offered(raw);
+
+        let mut methods = [Socks5Method::Unacceptable; u8::MAX as usize];
+        let methods = &mut methods[..method_length];
+        for (method, &value) in methods.iter_mut().zip(raw.iter()) {
+            *method = value.into();
+        }
 
-        let method = self.handler.negotiate_method(&methods).await?;
+        let method = self.handler.negotiate_method(methods).await?;
 
-        if methods.contains(&method) {
+        // GSS-API and the IANA assigned methods have no sub-negotiation
+        // here, picking one is answered X'FF' as if nothing was acceptable
+        let supported = matches!(
+            method,
+            Socks5Method::None | Socks5Method::UserPass | Socks5Method::Private(_)
+        );
+        if supported && methods.contains(&method) {
             Ok(method)
         } else {
-            Ok(Socks5Method::Unacceptable)
+            Err(SocksError::UnsupportedMethods(methods.to_vec()).into())
         }
     }
 
     /// The server selects from one of the methods given in METHODS, and
     /// sends a METHOD selection message:
     ///  
+    /// ```text
     ///                           +----+--------+
     ///                           |VER | METHOD |
     ///                           +----+--------+
     ///                           | 1  |   1    |
     ///                           +----+--------+
+    /// ```
     ///  
     /// If the selected METHOD is X'FF', none of the methods listed by the
     /// client are acceptable, and the client MUST close the connection.
     ///  
     /// The values currently defined for METHOD are:
     ///  
+    /// ```text
     ///            o  X'00' NO AUTHENTICATION REQUIRED
     ///            o  X'01' GSSAPI
     ///            o  X'02' USERNAME/PASSWORD
     ///            o  X'03' to X'7F' IANA ASSIGNED
     ///            o  X'80' to X'FE' RESERVED FOR PRIVATE METHODS
     ///            o  X'FF' NO ACCEPTABLE METHODS
+    /// ```
     ///  
     /// The client and server then enter a method-specific sub-negotiation.
     async fn negotiate_method_reply(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream<'_>,
         method: Socks5Method,
     ) -> Result<(), H::Error> {
-        stream.write_all(&[Self::VERSION, method.into()]).await?;
+        // X'FF' is followed by a shutdown, it must not be held back
+        let hold = self.handler.coalesce_replies() && method != Socks5Method::Unacceptable;
+        reply::write_handshake(stream, &[Self::VERSION, method.into()], hold)
+            .await
+            .map_err(SocksError::from_reply_error)?;
 
         Ok(())
     }
 
     /// GSS-API method
+    /// ```text
     /// +------+------+------+.......................+
     /// + ver  | mtyp | len  |       token           |
     /// +------+------+------+.......................+
     /// + 0x01 | 0x01 | 0x02 | up to 2^16 - 1 octets |
     /// +------+------+------+.......................+
-    async fn auth(&self, stream: &mut TcpStream, method: &Socks5Method) -> Result<bool, H::Error> {
+    /// ```
+    ///
+    /// Private methods (X'80' to X'FE') define their own sub-negotiation, so
+    /// the stream is handed to the handler as is.
+    async fn auth(
+        &self,
+        stream: &mut ClientStream<'_>,
+        method: &Socks5Method,
+        budget: &mut HandshakeBudget,
+    ) -> Result<bool, H::Error> {
         if method.eq(&Socks5Method::None) {
             return Ok(true);
         }
 
+        if let Socks5Method::Private(method) = method {
+            stream.flush().await.map_err(SocksError::from_reply_error)?;
+            return self.handler.private_auth(*method, stream).await;
+        }
+
         let version = stream.read_u8().await?;
+        budget.consume(1)?;
 
         if version != Self::SUB_NEGOTIATION {
             return Err(crate::error::SocksError::UnsupportedVersion(version).into());
         }
 
         match method {
-            Socks5Method::UserPass => self.auth_by_user_pass(stream).await,
-            _ => todo!(),
+            Socks5Method::UserPass => self.auth_by_user_pass(stream, budget).await,
+            method => Err(SocksError::UnsupportedMethods(vec![*method]).into()),
         }
     }
 
@@ -353,27 +1360,32 @@ impl<H: Socks5Handler + Send + Sync> Socks5<H> {
     /// | 1  |  1   | 1 to 255 |  1   | 1 to 255 |
     /// +----+------+----------+------+----------+
     ///
-    async fn auth_by_user_pass(&self, stream: &mut TcpStream) -> Result<bool, H::Error> {
+    async fn auth_by_user_pass(
+        &self,
+        stream: &mut ClientStream<'_>,
+        budget: &mut HandshakeBudget,
+    ) -> Result<bool, H::Error> {
         let username_length = stream.read_u8().await?;
+        budget.consume(1 + username_length as usize)?;
         let mut username = vec![0; username_length as usize];
         stream.read_exact(&mut username).await?;
 
-        let username = String::from_utf8(username).map_err(SocksError::Utf8BytesToStringError)?;
-
         let password_length = stream.read_u8().await?;
+        budget.consume(1 + password_length as usize)?;
         let mut password = vec![0; password_length as usize];
         stream.read_exact(&mut password).await?;
 
-        let password = String::from_utf8(password).map_err(SocksError::Utf8BytesToStringError)?;
-
-        let is_success = self.handler.auth_by_user_pass(&username, &password).await?;
+        let is_success = self
+            .handler
+            .auth_by_user_pass_bytes(&username, &password)
+            .await?;
 
         Ok(is_success)
     }
 
     async fn auth_reply(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream<'_>,
         method: &Socks5Method,
         is_success: bool,
     ) -> Result<(), H::Error> {
@@ -382,25 +1394,34 @@ impl<H: Socks5Handler + Send + Sync> Socks5<H> {
         }
 
         match method {
+            // The handler writes its own replies during a private sub-negotiation
+            Socks5Method::Private(_) => Ok(()),
             Socks5Method::UserPass => {
-                stream
-                    .write_all(&[Self::SUB_NEGOTIATION, is_success.into()])
-                    .await?;
+                // X'00' is success, any other STATUS a failure
+                let status = if is_success { 0x00 } else { 0x01 };
+                let hold = self.handler.coalesce_replies() && is_success;
+                reply::write_handshake(stream, &[Self::SUB_NEGOTIATION, status], hold)
+                    .await
+                    .map_err(SocksError::from_reply_error)?;
                 Ok(())
             }
-            _ => todo!(),
+            // Refused by `negotiate_method`, there is no reply to send
+            _ => Ok(()),
         }
     }
 
     /// The SOCKS request is formed as follows:
+    /// ```text
     ///     +----+-----+-------+------+----------+----------+
     ///    |VER | CMD |  RSV  | ATYP | DST.ADDR | DST.PORT |
     ///    +----+-----+-------+------+----------+----------+
     ///    | 1  |  1  | X'00' |  1   | Variable |    2     |
     ///    +----+-----+-------+------+----------+----------+
+    /// ```
     ///
     /// Where:
     ///
+    /// ```text
     ///      o  VER    protocol version: X'05'
     ///      o  CMD
     ///         o  CONNECT X'01'
@@ -414,33 +1435,55 @@ impl<H: Socks5Handler + Send + Sync> Socks5<H> {
     ///      o  DST.ADDR       desired destination address
     ///      o  DST.PORT desired destination port in network octet
     ///         order
+    /// ```
     async fn negotiate_request(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream<'_>,
+        budget: &mut HandshakeBudget,
     ) -> Result<(Socks5Command, SocksAddr), HandshakeError> {
-        let version = stream.read_u8().await?;
+        let mut header = [0; 4];
+        stream.read_exact(&mut header).await?;
+        budget.consume(header.len())?;
+        let [version, command, _, addr_type] = header;
+
         if version != Self::VERSION {
             return Err(SocksError::UnsupportedVersion(version).into());
         }
 
-        let command: Socks5Command = stream.read_u8().await?.try_into()?;
+        let command: Socks5Command = command.try_into()?;
+
+        if self
+            .handler
+            .drain_state()
+            .is_some_and(DrainState::is_draining)
+        {
+            return Err(HandshakeError::new(
+                SocksError::Draining,
+                Socks5Reply::NotAllowed,
+            ));
+        }
 
-        let is_support_command = self.handler.allow_command(&command).await.map_err(|err| {
+        let decision = self.handler.allow_command(&command).await.map_err(|err| {
             HandshakeError::new(
                 SocksError::ExecuteError(err.to_string()),
                 Socks5Reply::Failure,
             )
         })?;
 
-        if !is_support_command {
-            return Err(HandshakeError::new(
-                SocksError::UnsupportedCommand(command.into()),
-                Socks5Reply::UnsupportedCommand,
-            ));
+        match decision {
+            CommandDecision::Allow => {}
+            CommandDecision::Deny(Socks5Reply::UnsupportedCommand) => {
+                return Err(HandshakeError::new(
+                    SocksError::UnsupportedCommand(command.into()),
+                    Socks5Reply::UnsupportedCommand,
+                ));
+            }
+            CommandDecision::Deny(reply) => {
+                return Err(HandshakeError::new(SocksError::Reply(reply), reply));
+            }
         }
 
-        stream.read_u8().await?;
-        let addr_type: Socks5AddrType = stream.read_u8().await?.try_into()?;
+        let addr_type: Socks5AddrType = addr_type.try_into()?;
 
         let is_support_addr_type =
             self.handler
@@ -460,83 +1503,964 @@ impl<H: Socks5Handler + Send + Sync> Socks5<H> {
             ));
         }
 
-        let dist_addr = match addr_type {
-            Socks5AddrType::IPV4 => {
-                let mut buf = [0; 4];
-                stream.read_exact(&mut buf).await?;
+        let dist_addr = read_socks_addr(stream, addr_type).await?;
+        budget.consume(dist_addr.to_bytes().map_or(0, |bytes| bytes.len()))?;
+
+        // DST.PORT of UDP ASSOCIATE is where the client sends from, the
+        // relay screens the destination of each datagram instead
+        if command != Socks5Command::Associate {
+            let is_allowed_port =
+                self.handler
+                    .allow_port(dist_addr.port())
+                    .await
+                    .map_err(|err| {
+                        HandshakeError::new(
+                            SocksError::ExecuteError(err.to_string()),
+                            Socks5Reply::Failure,
+                        )
+                    })?;
+
+            if !is_allowed_port {
+                return Err(HandshakeError::new(
+                    SocksError::DestinationNotAllowed(dist_addr),
+                    Socks5Reply::NotAllowed,
+                ));
+            }
+        }
 
-                let ip = Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]);
-                let port = stream.read_u16().await?;
+        Ok((command, dist_addr))
+    }
+}
 
-                SocksAddr::IPV4(SocketAddrV4::new(ip, port))
-            }
-            Socks5AddrType::Domain => {
-                let length = stream.read_u8().await?;
-                let mut buf = vec![0; length as usize];
-                stream.read_exact(&mut buf).await?;
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
 
-                let domain = String::from_utf8(buf).map_err(SocksError::Utf8BytesToStringError)?;
-                let port = stream.read_u16().await?;
+    use super::*;
+    use crate::test_util::{tcp_pair, unresponsive_listener};
 
-                SocksAddr::Domain(domain, port)
-            }
-            Socks5AddrType::IPV6 => {
-                let mut buf = [0; 16];
-                stream.read_exact(&mut buf).await?;
-
-                let ip = Ipv6Addr::new(
-                    u16::from_be_bytes([buf[0], buf[1]]),
-                    u16::from_be_bytes([buf[2], buf[3]]),
-                    u16::from_be_bytes([buf[4], buf[5]]),
-                    u16::from_be_bytes([buf[6], buf[7]]),
-                    u16::from_be_bytes([buf[8], buf[9]]),
-                    u16::from_be_bytes([buf[10], buf[11]]),
-                    u16::from_be_bytes([buf[12], buf[13]]),
-                    u16::from_be_bytes([buf[14], buf[15]]),
-                );
-                let port = stream.read_u16().await?;
-
-                SocksAddr::IPV6(SocketAddrV6::new(ip, port, 0, 0))
-            }
-        };
+    struct DefaultHandler;
 
-        Ok((command, dist_addr))
+    #[async_trait]
+    impl Socks5Handler for DefaultHandler {
+        type Error = SocksError;
     }
 
-    async fn connect(&self, stream: &mut TcpStream, dist_addr: &SocksAddr) -> Result<(), H::Error> {
-        match self.handler.connect(stream, &dist_addr).await {
-            Ok(_) => Ok(()),
-            Err(err) => {
-                Socks5Reply::Failure.reply(stream, self.local_addr).await?;
+    struct ReplyThenFailHandler;
+
+    #[async_trait]
+    impl Socks5Handler for ReplyThenFailHandler {
+        type Error = SocksError;
+
+        async fn connect(
+            &self,
+            stream: &mut ClientStream<'_>,
+            _dest_addr: &SocksAddr,
+        ) -> Result<(), Self::Error> {
+            // Written in two parts, as a relay of an upstream reply may be
+            let reply = [0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0, 80];
+            stream.write_all(&reply[..4]).await?;
+            stream.write_all(&reply[4..]).await?;
+            Err(SocksError::Timeout {
+                phase: TimeoutPhase::Read,
+            })
+        }
+    }
 
-                Err(err)
-            }
+    struct GssApiHandler;
+
+    #[async_trait]
+    impl Socks5Handler for GssApiHandler {
+        type Error = SocksError;
+
+        async fn negotiate_method(
+            &self,
+            _methods: &[Socks5Method],
+        ) -> Result<Socks5Method, Self::Error> {
+            Ok(Socks5Method::GssApi)
         }
     }
 
-    async fn bind(&self, stream: &mut TcpStream, dist_addr: &SocksAddr) -> Result<(), H::Error> {
-        match self.handler.bind(stream, &dist_addr).await {
-            Ok(_) => Ok(()),
-            Err(err) => {
-                Socks5Reply::Failure.reply(stream, self.local_addr).await?;
+    /// Denies BIND as not allowed
+    struct NoBindHandler;
+
+    #[async_trait]
+    impl Socks5Handler for NoBindHandler {
+        type Error = SocksError;
+
+        async fn allow_command(
+            &self,
+            command: &Socks5Command,
+        ) -> Result<CommandDecision, Self::Error> {
+            Ok(match command {
+                Socks5Command::Bind => CommandDecision::Deny(Socks5Reply::NotAllowed),
+                _ => CommandDecision::Allow,
+            })
+        }
+    }
 
-                Err(err)
-            }
+    /// Denies requests to port 25
+    struct NoSmtpHandler;
+
+    #[async_trait]
+    impl Socks5Handler for NoSmtpHandler {
+        type Error = SocksError;
+
+        async fn allow_port(&self, port: u16) -> Result<bool, Self::Error> {
+            Ok(port != 25)
         }
     }
 
-    async fn associate(
-        &self,
-        stream: &mut TcpStream,
-        dist_addr: &SocksAddr,
-    ) -> Result<(), H::Error> {
-        match self.handler.associate(stream, &dist_addr).await {
-            Ok(_) => Ok(()),
-            Err(err) => {
-                Socks5Reply::Failure.reply(stream, self.local_addr).await?;
+    /// Denies every peer
+    struct DeniedPeerHandler;
 
-                Err(err)
-            }
+    #[async_trait]
+    impl Socks5Handler for DeniedPeerHandler {
+        type Error = SocksError;
+
+        async fn allow_peer(&self, _peer_addr: &SocketAddr) -> Result<bool, Self::Error> {
+            Ok(false)
         }
     }
+
+    /// Resets denied connections, denying the peer too when `deny_peer`
+    struct ResetHandler {
+        deny_peer: bool,
+    }
+
+    #[async_trait]
+    impl Socks5Handler for ResetHandler {
+        type Error = SocksError;
+
+        async fn allow_peer(&self, _peer_addr: &SocketAddr) -> Result<bool, Self::Error> {
+            Ok(!self.deny_peer)
+        }
+
+        async fn allow_command(
+            &self,
+            _command: &Socks5Command,
+        ) -> Result<CommandDecision, Self::Error> {
+            Ok(CommandDecision::Deny(Socks5Reply::NotAllowed))
+        }
+
+        fn reset_on_deny(&self) -> bool {
+            true
+        }
+    }
+
+    /// Fails every CONNECT with a vendor-specific reply
+    struct VendorReplyHandler;
+
+    #[async_trait]
+    impl Socks5Handler for VendorReplyHandler {
+        type Error = SocksError;
+
+        async fn connect(
+            &self,
+            _stream: &mut ClientStream<'_>,
+            _dest_addr: &SocksAddr,
+        ) -> Result<(), Self::Error> {
+            Err(SocksError::Reply(Socks5Reply::Unassigned(0x09)))
+        }
+    }
+
+    /// Echoes the requested domain in CONNECT replies
+    struct ReplyDomainHandler;
+
+    #[async_trait]
+    impl Socks5Handler for ReplyDomainHandler {
+        type Error = SocksError;
+
+        fn connect_reply_domain(&self) -> bool {
+            true
+        }
+    }
+
+    /// Gives each dial 200 milliseconds
+    struct ConnectTimeoutHandler;
+
+    #[async_trait]
+    impl Socks5Handler for ConnectTimeoutHandler {
+        type Error = SocksError;
+
+        fn connect_timeout(&self) -> Option<Duration> {
+            Some(Duration::from_millis(200))
+        }
+    }
+
+    /// Never finishes resolving a destination
+    struct HangingResolveHandler;
+
+    #[async_trait]
+    impl Socks5Handler for HangingResolveHandler {
+        type Error = SocksError;
+
+        async fn resolve(&self, _dest_addr: &SocksAddr) -> Result<Vec<SocketAddr>, Self::Error> {
+            std::future::pending().await
+        }
+    }
+
+    /// Resolves every destination to no address
+    struct NoAddressHandler;
+
+    #[async_trait]
+    impl Socks5Handler for NoAddressHandler {
+        type Error = SocksError;
+
+        async fn resolve(&self, _dest_addr: &SocksAddr) -> Result<Vec<SocketAddr>, Self::Error> {
+            Ok(vec![])
+        }
+    }
+
+    /// Reports `proxy.test` in the first BIND reply
+    struct BindDomainHandler;
+
+    #[async_trait]
+    impl Socks5Handler for BindDomainHandler {
+        type Error = SocksError;
+
+        fn bind_reply_domain(&self) -> Option<String> {
+            Some("proxy.test".to_string())
+        }
+    }
+
+    /// Writes IPv4-mapped IPv6 addresses in replies as IPv4
+    struct UnmapHandler;
+
+    #[async_trait]
+    impl Socks5Handler for UnmapHandler {
+        type Error = SocksError;
+
+        fn unmap_ipv4_replies(&self) -> bool {
+            true
+        }
+    }
+
+    /// Retries domains with an embedded port without it
+    struct LenientHandler;
+
+    #[async_trait]
+    impl Socks5Handler for LenientHandler {
+        type Error = SocksError;
+
+        fn lenient_domain_port(&self) -> bool {
+            true
+        }
+    }
+
+    /// Accepts `user` and `pass` as USERNAME/PASSWORD credentials
+    struct UserPassHandler;
+
+    #[async_trait]
+    impl Socks5Handler for UserPassHandler {
+        type Error = SocksError;
+
+        async fn negotiate_method(
+            &self,
+            _methods: &[Socks5Method],
+        ) -> Result<Socks5Method, Self::Error> {
+            Ok(Socks5Method::UserPass)
+        }
+
+        async fn auth_by_user_pass(
+            &self,
+            username: &str,
+            password: &str,
+        ) -> Result<bool, Self::Error> {
+            Ok(username == "user" && password == "pass")
+        }
+    }
+
+    #[tokio::test]
+    async fn unsupported_method_is_answered_unacceptable() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            GssApiHandler,
+        )
+        .with_strict(true);
+
+        client.write_all(&[0x05, 0x01, 0x01]).await.unwrap();
+        let result = socks5.negotiate(&mut server).await;
+
+        let mut reply = [0; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0xff]);
+        assert!(matches!(result, Err(SocksError::UnsupportedMethods(_))));
+    }
+
+    #[tokio::test]
+    async fn associate_is_unsupported_by_default() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            DefaultHandler,
+        )
+        .with_strict(true);
+
+        let request = [0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        client.write_all(&request).await.unwrap();
+        let result = socks5.negotiate(&mut server).await;
+
+        let mut reply = [0; 2 + 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..6], [0x05, 0x00, 0x05, 0x07, 0x00, 0x01]);
+        assert!(matches!(result, Err(SocksError::UnsupportedCommand(0x03))));
+    }
+
+    #[tokio::test]
+    async fn handler_reply_is_not_followed_by_a_failure() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            ReplyThenFailHandler,
+        )
+        .with_strict(true);
+
+        let request = [0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0, 80];
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        client.write_all(&request).await.unwrap();
+        let result = socks5.negotiate(&mut server).await;
+        drop(server);
+
+        let mut replies = Vec::new();
+        client.read_to_end(&mut replies).await.unwrap();
+        assert_eq!(
+            replies,
+            [0x05, 0x00, 0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0, 80]
+        );
+        assert!(matches!(result, Err(SocksError::Timeout { .. })));
+    }
+
+    #[tokio::test]
+    async fn connection_is_closed_after_unacceptable_methods() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            DefaultHandler,
+        )
+        .with_strict(true);
+
+        // Only USERNAME/PASSWORD, which the default handler does not accept
+        client.write_all(&[0x05, 0x01, 0x02]).await.unwrap();
+        let result = socks5.negotiate(&mut server).await;
+
+        // EOF follows the reply while the server still holds the stream
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0xff]);
+        assert!(matches!(result, Err(SocksError::UnsupportedMethods(_))));
+    }
+
+    #[tokio::test]
+    async fn bind_is_aborted_when_the_client_closes() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            DefaultHandler,
+        )
+        .with_strict(true);
+        let session = tokio::spawn(async move { socks5.negotiate(&mut server).await });
+
+        let request = [0x05, 0x02, 0x00, 0x01, 127, 0, 0, 1, 0, 0];
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        client.write_all(&request).await.unwrap();
+        let mut reply = [0; 2 + 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..4], [0x05, 0x00, 0x05, 0x00]);
+
+        // Gives up before anything connects to the listener
+        client.shutdown().await.unwrap();
+        let result = time::timeout(Duration::from_secs(5), session).await;
+        let result = result.unwrap().unwrap();
+        assert!(matches!(result, Err(SocksError::ClientDisconnected)));
+    }
+
+    #[tokio::test]
+    async fn refused_connect_is_replied_and_closed() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            DefaultHandler,
+        )
+        .with_strict(true);
+
+        // A port nothing listens on any more
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port().to_be_bytes();
+        drop(listener);
+
+        let request = [0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, port[0], port[1]];
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        client.write_all(&request).await.unwrap();
+        let result = socks5.execute(&mut server).await;
+
+        let mut replies = Vec::new();
+        client.read_to_end(&mut replies).await.unwrap();
+        assert_eq!(replies.len(), 2 + 10);
+        assert_eq!(replies[..4], [0x05, 0x00, 0x05, 0x05]);
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn unresolved_connect_replies_host_unreachable() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            NoAddressHandler,
+        )
+        .with_strict(true);
+
+        let mut request = vec![0x05, 0x01, 0x00, 0x03, 12];
+        request.extend(b"example.test");
+        request.extend(80u16.to_be_bytes());
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        client.write_all(&request).await.unwrap();
+        let result = socks5.negotiate(&mut server).await;
+
+        let mut reply = [0; 2 + 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..4], [0x05, 0x00, 0x05, 0x04]);
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn credentials_that_are_not_utf8_fail_authentication() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            UserPassHandler,
+        )
+        .with_strict(true);
+
+        client.write_all(&[0x05, 0x01, 0x02]).await.unwrap();
+        client
+            .write_all(&[
+                0x01, 0x04, 0xff, 0xfe, b'u', b's', 0x04, b'p', b'a', b's', b's',
+            ])
+            .await
+            .unwrap();
+        let result = socks5.negotiate(&mut server).await;
+
+        let mut reply = [0; 2 + 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0x02, 0x01, 0x01]);
+        assert!(matches!(result, Err(SocksError::AuthFailed)));
+    }
+
+    #[tokio::test]
+    async fn first_bind_reply_can_carry_a_domain() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            BindDomainHandler,
+        )
+        .with_strict(true);
+        let session = tokio::spawn(async move { socks5.negotiate(&mut server).await });
+
+        let request = [0x05, 0x02, 0x00, 0x01, 127, 0, 0, 1, 0, 0];
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        client.write_all(&request).await.unwrap();
+        let mut reply = [0; 2];
+        client.read_exact(&mut reply).await.unwrap();
+
+        let listen_addr = client::read_reply(&mut client).await.unwrap();
+        assert_eq!(listen_addr.domain(), "proxy.test");
+        assert_ne!(listen_addr.port(), 0);
+
+        client.shutdown().await.unwrap();
+        assert!(session.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn resolution_is_aborted_when_the_client_closes() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            HangingResolveHandler,
+        )
+        .with_strict(true);
+        let session = tokio::spawn(async move { socks5.negotiate(&mut server).await });
+
+        let mut request = vec![0x05, 0x01, 0x00, 0x03, 11];
+        request.extend(b"example.com");
+        request.extend(80u16.to_be_bytes());
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        client.write_all(&request).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let result = time::timeout(Duration::from_secs(5), session).await;
+        let result = result.unwrap().unwrap();
+        assert!(matches!(result, Err(SocksError::ClientDisconnected)));
+    }
+
+    #[tokio::test]
+    async fn connect_reply_carries_the_outbound_address() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            DefaultHandler,
+        )
+        .with_strict(true);
+        let session = tokio::spawn(async move { socks5.negotiate(&mut server).await });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dest_addr = listener.local_addr().unwrap().into();
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut reply = [0; 2];
+        client.read_exact(&mut reply).await.unwrap();
+
+        let bind_addr = client::request(&mut client, Socks5Command::Connect, &dest_addr)
+            .await
+            .unwrap();
+        let (_, outbound_addr) = listener.accept().await.unwrap();
+        assert_eq!(bind_addr, outbound_addr.into());
+
+        drop(client);
+        session.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn failure_reply_carries_the_server_address() {
+        let (mut client, mut server) = tcp_pair().await;
+        let server_addr = server.local_addr().unwrap();
+        let mut socks5 =
+            Socks5::new(server.peer_addr().unwrap(), server_addr, DefaultHandler).with_strict(true);
+
+        let mut request = vec![0x05, 0x01, 0x00, 0x03, 12];
+        request.extend(b"host.invalid");
+        request.extend(80u16.to_be_bytes());
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        client.write_all(&request).await.unwrap();
+        let result = socks5.negotiate(&mut server).await;
+        assert!(result.is_err());
+
+        let mut reply = [0; 2 + 3];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[2..], [0x05, 0x04, 0x00]);
+        let addr_type = client.read_u8().await.unwrap().try_into().unwrap();
+        let bind_addr = read_socks_addr(&mut client, addr_type).await.unwrap();
+        assert_eq!(bind_addr, server_addr.into());
+    }
+
+    #[tokio::test]
+    async fn connect_timeout_replies_ttl_expired() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            ConnectTimeoutHandler,
+        )
+        .with_strict(true);
+        let (listener, _streams) = unresponsive_listener().await;
+        let port = listener.local_addr().unwrap().port().to_be_bytes();
+
+        let request = [0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, port[0], port[1]];
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        client.write_all(&request).await.unwrap();
+        let result = socks5.negotiate(&mut server).await;
+
+        let mut reply = [0; 2 + 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..4], [0x05, 0x00, 0x05, 0x06]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn clones_share_a_handler_that_is_neither_clone_nor_debug() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1080));
+        let socks5 = Socks5::new(addr, addr, DefaultHandler);
+
+        let clone = socks5.clone();
+        assert!(Arc::ptr_eq(&socks5.handler, &clone.handler));
+        assert!(format!("{:?}", clone).starts_with("Socks5 { peer_addr: 127.0.0.1:1080"));
+    }
+
+    #[tokio::test]
+    async fn connect_reply_can_echo_the_domain() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            ReplyDomainHandler,
+        )
+        .with_strict(true);
+        let session = tokio::spawn(async move { socks5.negotiate(&mut server).await });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dest_addr = SocksAddr::Domain(
+            "localhost".to_string(),
+            listener.local_addr().unwrap().port(),
+        );
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut reply = [0; 2];
+        client.read_exact(&mut reply).await.unwrap();
+
+        let bind_addr = client::request(&mut client, Socks5Command::Connect, &dest_addr)
+            .await
+            .unwrap();
+        assert_eq!(bind_addr, dest_addr);
+
+        drop(listener.accept().await.unwrap());
+        drop(client);
+        session.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn negotiate_full_reads_the_version_without_strict_mode() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            DefaultHandler,
+        );
+
+        let request = [0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        client.write_all(&request).await.unwrap();
+        let result = socks5.negotiate_full(&mut server).await;
+
+        let mut reply = [0; 2 + 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..4], [0x05, 0x00, 0x05, 0x07]);
+        assert!(matches!(result, Err(SocksError::UnsupportedCommand(0x03))));
+    }
+
+    #[tokio::test]
+    async fn negotiate_full_refuses_other_versions() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            DefaultHandler,
+        );
+
+        client.write_all(&[0x04, 0x01, 0x00]).await.unwrap();
+        let result = socks5.negotiate_full(&mut server).await;
+
+        assert!(matches!(result, Err(SocksError::UnsupportedVersion(0x04))));
+    }
+
+    #[tokio::test]
+    async fn handler_can_fail_with_any_reply() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            VendorReplyHandler,
+        )
+        .with_strict(true);
+
+        let request = [0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0, 80];
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        client.write_all(&request).await.unwrap();
+        let result = socks5.negotiate(&mut server).await;
+
+        let mut reply = [0; 2 + 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..4], [0x05, 0x00, 0x05, 0x09]);
+        assert!(matches!(result, Err(SocksError::Reply(_))));
+    }
+
+    #[tokio::test]
+    async fn outcome_reports_the_client_address() {
+        let (mut client, mut server) = tcp_pair().await;
+        let client_addr = client.local_addr().unwrap();
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            DefaultHandler,
+        )
+        .with_strict(true);
+        let session = tokio::spawn(async move { socks5.negotiate_with_outcome(&mut server).await });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dest_addr = listener.local_addr().unwrap().into();
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut reply = [0; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        client::request(&mut client, Socks5Command::Connect, &dest_addr)
+            .await
+            .unwrap();
+
+        drop(listener.accept().await.unwrap());
+        drop(client);
+        let outcome = session.await.unwrap().unwrap();
+        assert_eq!(outcome.peer_addr, client_addr);
+        assert_eq!(outcome.dest_addr, dest_addr);
+        assert_eq!(outcome.reply, Some(Socks5Reply::Succeeded));
+    }
+
+    #[tokio::test]
+    async fn denied_command_gets_the_reply_picked() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            NoBindHandler,
+        )
+        .with_strict(true);
+
+        let request = [0x05, 0x02, 0x00, 0x01, 127, 0, 0, 1, 0, 0];
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        client.write_all(&request).await.unwrap();
+        let result = socks5.negotiate(&mut server).await;
+
+        let mut reply = [0; 2 + 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..4], [0x05, 0x00, 0x05, 0x02]);
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn closing_after_the_version_is_not_answered() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            DefaultHandler,
+        )
+        .with_strict(true);
+
+        client.write_all(&[0x05]).await.unwrap();
+        client.shutdown().await.unwrap();
+        let result = socks5.negotiate(&mut server).await;
+        drop(server);
+
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert!(reply.is_empty());
+        assert!(matches!(result, Err(SocksError::ClientDisconnected)));
+    }
+
+    #[tokio::test]
+    async fn lenient_domain_port_retries_without_the_port() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let dest_addr = SocksAddr::Domain("localhost:8080".to_string(), port);
+
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            DefaultHandler,
+        )
+        .with_strict(true);
+        let mut request = vec![0x05, 0x01, 0x00, 0x03, 14];
+        request.extend(b"localhost:8080");
+        request.extend(port.to_be_bytes());
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        client.write_all(&request).await.unwrap();
+        let result = socks5.negotiate(&mut server).await;
+
+        let mut reply = [0; 2 + 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..4], [0x05, 0x00, 0x05, 0x04]);
+        assert!(result.is_err());
+
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            LenientHandler,
+        )
+        .with_strict(true);
+        let session = tokio::spawn(async move { socks5.negotiate(&mut server).await });
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut reply = [0; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        client::request(&mut client, Socks5Command::Connect, &dest_addr)
+            .await
+            .unwrap();
+
+        drop(listener.accept().await.unwrap());
+        drop(client);
+        session.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn only_numeric_ports_after_a_domain_are_stripped() {
+        let strip = |domain: &str| without_domain_port(&SocksAddr::Domain(domain.to_string(), 443));
+
+        assert_eq!(
+            strip("example.com:80"),
+            Some(SocksAddr::Domain("example.com".to_string(), 443))
+        );
+        assert_eq!(strip("example.com"), None);
+        assert_eq!(strip("example.com:http"), None);
+        assert_eq!(strip(":80"), None);
+        assert_eq!(strip("::1:80"), None);
+        assert_eq!(without_domain_port(&"127.0.0.1:80".parse().unwrap()), None);
+    }
+
+    #[tokio::test]
+    async fn denied_peer_is_reset() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            ResetHandler { deny_peer: true },
+        )
+        .with_strict(true);
+
+        let result = socks5.negotiate(&mut server).await;
+        assert!(matches!(result, Err(SocksError::PeerNotAllowed(_))));
+        drop(server);
+
+        let err = client.read_u8().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn not_allowed_request_is_reset_instead_of_replied() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            ResetHandler { deny_peer: false },
+        )
+        .with_strict(true);
+        let session = tokio::spawn(async move {
+            let result = socks5.negotiate(&mut server).await;
+            drop(server);
+            result
+        });
+
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut reply = [0; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0x00]);
+
+        let request = [0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0, 80];
+        client.write_all(&request).await.unwrap();
+        assert!(session.await.unwrap().is_err());
+
+        let err = client.read_u8().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    /// Reply to a CONNECT bound to an IPv4-mapped IPv6 address, through a
+    /// dual-stack listener
+    async fn mapped_connect_reply<H>(handler: H) -> Vec<u8>
+    where
+        H: Socks5Handler<Error = SocksError> + Send + Sync + 'static,
+    {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            handler,
+        )
+        .with_strict(true);
+        let session = tokio::spawn(async move { socks5.negotiate(&mut server).await });
+
+        let listener = TcpListener::bind("[::]:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port().to_be_bytes();
+        let mut request = vec![0x05, 0x01, 0x00, 0x04];
+        request.extend(Ipv4Addr::LOCALHOST.to_ipv6_mapped().octets());
+        request.extend(port);
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        client.write_all(&request).await.unwrap();
+
+        let mut reply = [0; 2 + 4];
+        client.read_exact(&mut reply).await.unwrap();
+        let mut addr = vec![0; if reply[5] == 0x04 { 16 + 2 } else { 4 + 2 }];
+        client.read_exact(&mut addr).await.unwrap();
+
+        drop(listener.accept().await.unwrap());
+        drop(client);
+        session.await.unwrap().unwrap();
+        [&reply[2..], &addr].concat()
+    }
+
+    #[tokio::test]
+    async fn mapped_bound_address_is_replied_as_ipv6_by_default() {
+        let reply = mapped_connect_reply(DefaultHandler).await;
+        assert_eq!(reply[..4], [0x05, 0x00, 0x00, 0x04]);
+        assert_eq!(reply[4..20], Ipv4Addr::LOCALHOST.to_ipv6_mapped().octets());
+    }
+
+    #[tokio::test]
+    async fn mapped_bound_address_is_replied_as_ipv4_when_unmapped() {
+        let reply = mapped_connect_reply(UnmapHandler).await;
+        assert_eq!(reply[..4], [0x05, 0x00, 0x00, 0x01]);
+        assert_eq!(reply[4..8], [127, 0, 0, 1]);
+    }
+
+    #[tokio::test]
+    async fn denied_peer_gets_eof_without_a_reply() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            DeniedPeerHandler,
+        )
+        .with_strict(true);
+
+        let result = socks5.negotiate(&mut server).await;
+        assert!(
+            matches!(result, Err(SocksError::PeerNotAllowed(addr)) if addr == client.local_addr().unwrap())
+        );
+
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert!(received.is_empty());
+    }
+
+    #[tokio::test]
+    async fn negotiate_only_leaves_the_request_unanswered() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            NoSmtpHandler,
+        )
+        .with_strict(true);
+
+        let request = [0x05, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0, 80];
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        client.write_all(&request).await.unwrap();
+        let (command, dest_addr) = socks5.negotiate_only(&mut server).await.unwrap();
+        assert_eq!(command, Socks5Command::Connect);
+        assert_eq!(dest_addr, SocketAddr::from(([192, 0, 2, 1], 80)).into());
+        drop(server);
+
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, [0x05, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn negotiate_only_replies_to_denied_requests() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            NoSmtpHandler,
+        )
+        .with_strict(true);
+
+        let request = [0x05, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0, 25];
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        client.write_all(&request).await.unwrap();
+        assert!(socks5.negotiate_only(&mut server).await.is_err());
+        drop(server);
+
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received.len(), 2 + 10);
+        assert_eq!(received[..4], [0x05, 0x00, 0x05, 0x02]);
+    }
 }
@@ -0,0 +1,23 @@
+use std::net::SocketAddr;
+
+use crate::addr::SocksAddr;
+
+use super::{command::Socks5Command, method::Socks5Method, reply::Socks5Reply};
+
+/// What `Socks5::negotiate_with_outcome` did for a client
+///
+/// When the control connection carried several BIND requests, the command,
+/// destination and reply are those of the last one.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Socks5Outcome {
+    /// Client the control connection came from, the address `allow_peer`
+    /// screened, so access logs can record who made the request
+    pub peer_addr: SocketAddr,
+    /// Method selected during method negotiation
+    pub method: Socks5Method,
+    pub command: Socks5Command,
+    pub dest_addr: SocksAddr,
+    /// Last reply written to the client for the request, `None` if the
+    /// handler returned without replying
+    pub reply: Option<Socks5Reply>,
+}
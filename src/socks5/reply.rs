@@ -1,8 +1,23 @@
-use std::net::SocketAddr;
+use std::{
+    error::Error,
+    future::{poll_fn, Future},
+    net::SocketAddr,
+    pin::pin,
+    task::Poll,
+};
 
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+};
 
-use super::addr_type::Socks5AddrType;
+use crate::{
+    addr::{write_socks_addr, SocksAddr},
+    error::SocksError,
+    stream::{ClientStream, Replies, ReplyCount, ReplyFraming},
+};
+
+use super::command::Socks5Command;
 
 /// X'00' succeeded
 /// X'01' general SOCKS server failure
@@ -46,19 +61,19 @@ impl From<u8> for Socks5Reply {
     }
 }
 
-impl Into<u8> for Socks5Reply {
-    fn into(self) -> u8 {
-        match self {
-            Self::Succeeded => 0x00,
-            Self::Failure => 0x01,
-            Self::NotAllowed => 0x02,
-            Self::NetworkUnreachable => 0x03,
-            Self::HostUnreachable => 0x04,
-            Self::ConnectionRefused => 0x05,
-            Self::TTLExpired => 0x06,
-            Self::UnsupportedCommand => 0x07,
-            Self::UnsupportedAddressType => 0x08,
-            Self::Unassigned(val) => val,
+impl From<Socks5Reply> for u8 {
+    fn from(value: Socks5Reply) -> Self {
+        match value {
+            Socks5Reply::Succeeded => 0x00,
+            Socks5Reply::Failure => 0x01,
+            Socks5Reply::NotAllowed => 0x02,
+            Socks5Reply::NetworkUnreachable => 0x03,
+            Socks5Reply::HostUnreachable => 0x04,
+            Socks5Reply::ConnectionRefused => 0x05,
+            Socks5Reply::TTLExpired => 0x06,
+            Socks5Reply::UnsupportedCommand => 0x07,
+            Socks5Reply::UnsupportedAddressType => 0x08,
+            Socks5Reply::Unassigned(val) => val,
         }
     }
 }
@@ -66,19 +81,82 @@ impl Into<u8> for Socks5Reply {
 impl Socks5Reply {
     pub(super) const VERSION: u8 = 0x05;
 
+    /// Picks the reply for an error returned by a handler, by looking for an
+    /// `io::Error` through the error and its sources and mapping it with
+    /// `from_io_error`. A reply the handler picked, as `SocksError::Reply`,
+    /// is sent as is, and so is a failure reply from an upstream server, as
+    /// `SocksError::RequestFailed`. A policy denial or `SocksError::Draining`
+    /// replies `NotAllowed`, a `SocksError::Timeout` replies `TTLExpired`
+    /// and a `SocksError::UnsupportedCommand` replies `UnsupportedCommand`.
+    /// Anything else replies `Failure`.
+    pub(super) fn from_error(err: &(dyn Error + 'static)) -> Self {
+        let mut source = Some(err);
+
+        while let Some(err) = source {
+            match err.downcast_ref::<SocksError>() {
+                // A failed request never claims success
+                Some(SocksError::Reply(Self::Succeeded)) => return Self::Failure,
+                Some(SocksError::Reply(reply) | SocksError::RequestFailed(reply)) => return *reply,
+                Some(
+                    SocksError::PeerNotAllowed(_)
+                    | SocksError::DestinationNotAllowed(_)
+                    | SocksError::Draining,
+                ) => return Self::NotAllowed,
+                Some(SocksError::Timeout { .. }) => return Self::TTLExpired,
+                Some(SocksError::UnsupportedCommand(_)) => return Self::UnsupportedCommand,
+                _ => {}
+            }
+
+            if let Some(SocksError::StdIoError(err)) = err.downcast_ref::<SocksError>() {
+                return Self::from_io_error(err);
+            }
+
+            if let Some(err) = err.downcast_ref::<io::Error>() {
+                return Self::from_io_error(err);
+            }
+
+            source = err.source();
+        }
+
+        Self::Failure
+    }
+
+    /// Maps an I/O error, typically from dialing the destination, to the
+    /// reply a client should receive:
+    ///
+    /// - `ConnectionRefused` replies `ConnectionRefused`
+    /// - `TimedOut` replies `TTLExpired`, as does a `SocksError::Timeout`
+    ///   such as the one of `Socks5Handler::connect_timeout`
+    /// - `HostUnreachable`, including a failed or empty name resolution,
+    ///   replies `HostUnreachable`
+    /// - `NetworkUnreachable` replies `NetworkUnreachable`
+    /// - anything else replies `Failure`
+    pub fn from_io_error(err: &io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::ConnectionRefused => Self::ConnectionRefused,
+            io::ErrorKind::TimedOut => Self::TTLExpired,
+            io::ErrorKind::HostUnreachable => Self::HostUnreachable,
+            io::ErrorKind::NetworkUnreachable => Self::NetworkUnreachable,
+            _ => Self::Failure,
+        }
+    }
+
     /// The SOCKS request information is sent by the client as soon as it has
     /// established a connection to the SOCKS server, and completed the
     /// authentication negotiations.  The server evaluates the request, and
     /// returns a reply formed as follows:
     ///
+    /// ```text
     ///      +----+-----+-------+------+----------+----------+
     ///      |VER | REP |  RSV  | ATYP | BND.ADDR | BND.PORT |
     ///      +----+-----+-------+------+----------+----------+
     ///      | 1  |  1  | X'00' |  1   | Variable |    2     |
     ///      +----+-----+-------+------+----------+----------+
+    /// ```
     ///
     ///   Where:
     ///
+    /// ```text
     ///        o  VER    protocol version: X'05'
     ///        o  REP    Reply field:
     ///        o  RSV    RESERVED
@@ -88,34 +166,274 @@ impl Socks5Reply {
     ///           o  IP V6 address: X'04'
     ///        o  BND.ADDR       server bound address
     ///        o  BND.PORT       server bound port in network octet order
+    /// ```
     /// Fields marked RESERVED (RSV) must be set to X'00'.
-    pub async fn reply<S>(
-        &self,
-        stream: &mut S,
-        bind_addr: SocketAddr,
-    ) -> Result<(), io::Error>
+    pub async fn reply<S>(&self, stream: &mut S, bind_addr: SocketAddr) -> Result<(), io::Error>
+    where
+        S: AsyncReadExt + AsyncWriteExt + Unpin + Send,
+    {
+        self.reply_with_addr(stream, &bind_addr.into()).await
+    }
+
+    /// Same as `reply` for any `SocksAddr`, so BND.ADDR can also be a domain
+    /// with ATYP X'03'. RFC 1928 allows it, and clients such as curl read
+    /// it, but some clients only expect an IP address there. A domain
+    /// longer than 255 bytes is an `io::ErrorKind::InvalidInput` error.
+    pub async fn reply_with_addr<S>(&self, stream: &mut S, bind_addr: &SocksAddr) -> io::Result<()>
+    where
+        S: AsyncReadExt + AsyncWriteExt + Unpin + Send,
+    {
+        // Built up front so the reply goes out in a single write, together
+        // with any handshake frame held back
+        let mut buf = vec![Self::VERSION, (*self).into(), 0x00];
+        write_socks_addr(&mut buf, bind_addr).await?;
+
+        stream.write_all(&buf).await
+    }
+
+    /// BIND is answered with two replies, in this order:
+    ///
+    /// 1. `reply_bind_listening` once the server listens for the inbound
+    ///    connection, carrying the address the client should announce to the
+    ///    application server
+    /// 2. `reply_bind_connected` once the inbound connection is accepted,
+    ///    carrying the address of the connecting host
+    ///
+    /// Data is relayed only after the second reply. On failure a single
+    /// failure reply replaces whichever reply is pending.
+    pub async fn reply_bind_listening<S>(stream: &mut S, listen_addr: SocketAddr) -> io::Result<()>
+    where
+        S: AsyncReadExt + AsyncWriteExt + Unpin + Send,
+    {
+        Self::Succeeded.reply(stream, listen_addr).await
+    }
+
+    /// Second BIND reply, see `reply_bind_listening`
+    pub async fn reply_bind_connected<S>(stream: &mut S, peer_addr: SocketAddr) -> io::Result<()>
+    where
+        S: AsyncReadExt + AsyncWriteExt + Unpin + Send,
+    {
+        Self::Succeeded.reply(stream, peer_addr).await
+    }
+}
+
+/// Writes a method selection or sub-negotiation `frame`, or holds it back
+/// on `stream` when `hold` is set and the client already sent its next
+/// message, which means it is not waiting for the frame. A held frame goes
+/// out with the next write to the stream, or when it is flushed.
+pub(super) async fn write_handshake(
+    stream: &mut ClientStream<'_>,
+    frame: &[u8],
+    hold: bool,
+) -> io::Result<()> {
+    if hold && has_pending_input(stream).await {
+        stream.hold(frame);
+        return Ok(());
+    }
+
+    stream.write_all(frame).await
+}
+
+/// Whether data from the client can be read right away
+async fn has_pending_input(stream: &TcpStream) -> bool {
+    let mut byte = [0; 1];
+    poll_fn(|cx| {
+        let mut buf = ReadBuf::new(&mut byte);
+        match stream.poll_peek(cx, &mut buf) {
+            Poll::Ready(Ok(size)) => Poll::Ready(size > 0),
+            _ => Poll::Ready(false),
+        }
+    })
+    .await
+}
+
+/// Makes sure a request is answered by exactly the replies it expects, one
+/// for CONNECT and UDP ASSOCIATE and two for BIND, whichever path it takes.
+///
+/// Replies written by the handler are counted on the `ClientStream` it
+/// writes them to, so a handler failing after it replied itself does not
+/// get a second frame written after its own, while one failing before it
+/// replied still lets the client know.
+///
+/// Failure replies from the guard carry `bind_addr`, the server side of the
+/// client connection, since no outbound socket may exist at that point.
+/// Success replies are the handler's, which report the address of the
+/// socket it opened.
+pub(super) struct ReplyGuard {
+    bind_addr: SocketAddr,
+    expected: u8,
+    count: ReplyCount,
+}
+
+impl ReplyGuard {
+    pub(super) fn new(bind_addr: SocketAddr) -> Self {
+        Self {
+            bind_addr,
+            expected: 1,
+            count: ReplyCount::default(),
+        }
+    }
+
+    /// Counts the replies written to `stream` for `command` from now on
+    pub(super) fn track<S>(&mut self, stream: &mut ClientStream<'_, S>, command: &Socks5Command) {
+        if *command == Socks5Command::Bind {
+            self.expected = 2;
+        }
+
+        self.count = stream.count_replies(ReplyFraming::Socks5, self.expected);
+    }
+
+    /// Runs the handler serving the tracked command. `on_first_reply` is
+    /// called as soon as the handler wrote its first reply, while it keeps
+    /// serving the command.
+    pub(super) async fn serve<F, R>(&self, fut: F, on_first_reply: R) -> F::Output
+    where
+        F: Future,
+        R: FnOnce(Socks5Reply),
+    {
+        let mut fut = pin!(fut);
+        let mut on_first_reply = Some(on_first_reply);
+
+        poll_fn(|cx| {
+            let poll = fut.as_mut().poll(cx);
+
+            if on_first_reply.is_some() {
+                if let Some(first) = self.count.get().first {
+                    if let Some(on_first_reply) = on_first_reply.take() {
+                        on_first_reply(first.into());
+                    }
+                }
+            }
+
+            poll
+        })
+        .await
+    }
+
+    /// Writes `reply` unless every expected reply was already sent
+    pub(super) async fn reply<S>(&mut self, stream: &mut S, reply: Socks5Reply) -> io::Result<()>
     where
         S: AsyncReadExt + AsyncWriteExt + Unpin + Send,
     {
-        let (address_type, ip, port) = match bind_addr {
-            SocketAddr::V4(addr) => (
-                Socks5AddrType::IPV4,
-                addr.ip().octets().to_vec(),
-                addr.port(),
+        let replies = self.count.get();
+        if replies.sent >= self.expected {
+            return Ok(());
+        }
+
+        // Whatever follows a failure is never sent, it ends the request
+        self.count.set(Replies {
+            sent: self.expected,
+            first: replies.first.or(Some(reply.into())),
+            last: Some(reply.into()),
+        });
+        reply.reply(stream, self.bind_addr).await
+    }
+
+    /// Last reply written for the request, by the guard or the handler
+    pub(super) fn last_reply(&self) -> Option<Socks5Reply> {
+        self.count.get().last.map(Socks5Reply::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::TimeoutPhase;
+
+    use super::*;
+
+    #[test]
+    fn io_errors_map_to_replies() {
+        let cases = [
+            (
+                io::ErrorKind::ConnectionRefused,
+                Socks5Reply::ConnectionRefused,
+            ),
+            (io::ErrorKind::TimedOut, Socks5Reply::TTLExpired),
+            (io::ErrorKind::HostUnreachable, Socks5Reply::HostUnreachable),
+            (
+                io::ErrorKind::NetworkUnreachable,
+                Socks5Reply::NetworkUnreachable,
+            ),
+            (io::ErrorKind::PermissionDenied, Socks5Reply::Failure),
+            (io::ErrorKind::Other, Socks5Reply::Failure),
+        ];
+
+        for (kind, reply) in cases {
+            let err = io::Error::from(kind);
+            assert_eq!(Socks5Reply::from_io_error(&err), reply, "{:?}", kind);
+            assert_eq!(Socks5Reply::from_error(&err), reply, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn socks_errors_map_to_replies() {
+        let peer_addr = SocketAddr::from(([192, 0, 2, 1], 1080));
+        let cases = [
+            (
+                SocksError::Reply(Socks5Reply::Succeeded),
+                Socks5Reply::Failure,
+            ),
+            (
+                SocksError::Reply(Socks5Reply::Unassigned(0x42)),
+                Socks5Reply::Unassigned(0x42),
             ),
-            SocketAddr::V6(addr) => (
-                Socks5AddrType::IPV6,
-                addr.ip().octets().to_vec(),
-                addr.port(),
+            (
+                SocksError::RequestFailed(Socks5Reply::HostUnreachable),
+                Socks5Reply::HostUnreachable,
             ),
-        };
+            (
+                SocksError::PeerNotAllowed(peer_addr),
+                Socks5Reply::NotAllowed,
+            ),
+            (
+                SocksError::DestinationNotAllowed(peer_addr.into()),
+                Socks5Reply::NotAllowed,
+            ),
+            (SocksError::Draining, Socks5Reply::NotAllowed),
+            (
+                SocksError::Timeout {
+                    phase: TimeoutPhase::Connect,
+                },
+                Socks5Reply::TTLExpired,
+            ),
+            (
+                SocksError::UnsupportedCommand(0x03),
+                Socks5Reply::UnsupportedCommand,
+            ),
+            (
+                io::Error::from(io::ErrorKind::ConnectionRefused).into(),
+                Socks5Reply::ConnectionRefused,
+            ),
+            (SocksError::AuthFailed, Socks5Reply::Failure),
+            (SocksError::Incomplete, Socks5Reply::Failure),
+        ];
 
-        let mut buf = vec![Self::VERSION, (*self).into(), 0x00, address_type.into()];
-        buf.extend(ip);
-        buf.extend(port.to_be_bytes());
+        for (err, reply) in cases {
+            assert_eq!(Socks5Reply::from_error(&err), reply, "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn io_errors_are_found_through_sources() {
+        #[derive(Debug)]
+        struct Wrapper(io::Error);
 
-        stream.write_all(&buf).await?;
+        impl std::fmt::Display for Wrapper {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str("dial failed")
+            }
+        }
+
+        impl Error for Wrapper {
+            fn source(&self) -> Option<&(dyn Error + 'static)> {
+                Some(&self.0)
+            }
+        }
 
-        Ok(())
+        let err = Wrapper(io::ErrorKind::NetworkUnreachable.into());
+        assert_eq!(
+            Socks5Reply::from_error(&err),
+            Socks5Reply::NetworkUnreachable
+        );
     }
 }
@@ -0,0 +1,94 @@
+use crate::{addr::SocksAddr, error::SocksError};
+
+use super::{addr_type::Socks5AddrType, command::Socks5Command, reply::Socks5Reply};
+
+/// Parses a SOCKS request from a byte slice, the same layout `negotiate`
+/// reads from the stream:
+///
+/// ```text
+///    +----+-----+-------+------+----------+----------+
+///    |VER | CMD |  RSV  | ATYP | DST.ADDR | DST.PORT |
+///    +----+-----+-------+------+----------+----------+
+///    | 1  |  1  | X'00' |  1   | Variable |    2     |
+///    +----+-----+-------+------+----------+----------+
+/// ```
+///
+/// Returns the command, the destination and the number of bytes consumed.
+/// A slice holding only part of a request yields `SocksError::Incomplete`.
+pub fn parse_socks5_request(buf: &[u8]) -> Result<(Socks5Command, SocksAddr, usize), SocksError> {
+    let header = buf.get(..4).ok_or(SocksError::Incomplete)?;
+
+    if header[0] != Socks5Reply::VERSION {
+        return Err(SocksError::UnsupportedVersion(header[0]));
+    }
+
+    let command: Socks5Command = header[1].try_into()?;
+    let addr_type: Socks5AddrType = header[3].try_into()?;
+
+    let (dest_addr, length) = SocksAddr::from_bytes(&addr_type, &buf[4..])?;
+
+    Ok((command, dest_addr, 4 + length))
+}
+
+/// Encodes a SOCKS request as sent by a client, the inverse of
+/// `parse_socks5_request`. The ATYP is chosen from the `SocksAddr` variant.
+/// Domains longer than 255 bytes yield `SocksError::DomainTooLong`.
+pub fn encode_socks5_request(
+    command: Socks5Command,
+    dest_addr: &SocksAddr,
+) -> Result<Vec<u8>, SocksError> {
+    let mut buf = vec![
+        Socks5Reply::VERSION,
+        command.into(),
+        0x00,
+        dest_addr.addr_type().into(),
+    ];
+    buf.extend(dest_addr.to_bytes()?);
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddrV4, SocketAddrV6};
+
+    use super::*;
+
+    #[test]
+    fn encoded_requests_parse_back() {
+        let requests = [
+            (
+                Socks5Command::Connect,
+                SocksAddr::IPV4(SocketAddrV4::new([192, 0, 2, 1].into(), 80)),
+            ),
+            (
+                Socks5Command::Bind,
+                SocksAddr::Domain("example.com".to_string(), 21),
+            ),
+            (
+                Socks5Command::Associate,
+                SocksAddr::IPV6(SocketAddrV6::new("2001:db8::1".parse().unwrap(), 53, 0, 0)),
+            ),
+        ];
+
+        for (command, dest_addr) in requests {
+            let buf = encode_socks5_request(command, &dest_addr).unwrap();
+
+            let parsed = parse_socks5_request(&buf).unwrap();
+            assert_eq!(parsed, (command, dest_addr, buf.len()));
+        }
+    }
+
+    #[test]
+    fn partial_requests_are_incomplete() {
+        let dest_addr = SocksAddr::Domain("example.com".to_string(), 443);
+        let buf = encode_socks5_request(Socks5Command::Connect, &dest_addr).unwrap();
+
+        for length in 0..buf.len() {
+            assert!(matches!(
+                parse_socks5_request(&buf[..length]),
+                Err(SocksError::Incomplete)
+            ));
+        }
+    }
+}
@@ -0,0 +1,788 @@
+use std::{
+    borrow::Cow,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{self, AsyncReadExt},
+    net::UdpSocket,
+    select, time,
+};
+
+use crate::{addr::SocksAddr, error::SocksError, stream::ClientStream};
+
+use super::{
+    addr_type::Socks5AddrType, reply::Socks5Reply, request::parse_socks5_request, Socks5Handler,
+};
+
+/// Largest UDP payload over IPv4, 65535 minus the IP and UDP headers
+pub const MAX_DATAGRAM_SIZE: usize = 65507;
+
+/// Time a fragmented datagram has to arrive in full, the minimum RFC 1928
+/// allows
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time the domain a client datagram is sent to has to resolve in. The
+/// relay waits for it, so it is kept short, and the datagram is dropped
+/// once it elapses.
+pub const RESOLVE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Why the relay dropped a datagram, passed to `Socks5Handler::on_udp_drop`
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum UdpDropReason {
+    /// RSV was not X'0000'
+    InvalidRsv,
+    /// ATYP was not a known address type
+    InvalidAddressType,
+    /// The datagram ended before the end of its header
+    Truncated,
+    /// DST.ADDR could not be decoded, such as a domain that is not UTF-8
+    InvalidAddress,
+    /// FRAG was set while `Socks5Handler::supports_fragmentation` is off
+    Fragmented,
+    /// `Socks5Handler::allow_port` or `Socks5Handler::allow_resolved_ip`
+    /// denied DST.ADDR/DST.PORT, or failed
+    NotAllowed,
+    /// The domain of DST.ADDR did not resolve within `RESOLVE_TIMEOUT`
+    Unresolved,
+    /// Sending the datagram failed, to its destination or back to the
+    /// client, such as for an unreachable network
+    SendFailed,
+}
+
+/// Counters of a UDP association, passed to
+/// `Socks5Handler::on_udp_complete`
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct UdpStats {
+    /// Client datagrams dropped because RSV was not X'0000'
+    pub invalid_rsv: u64,
+    /// Client datagrams dropped because of an unknown ATYP
+    pub invalid_addr_type: u64,
+    /// Client datagrams dropped because they were shorter than their header
+    pub truncated: u64,
+    /// Client datagrams dropped because DST.ADDR could not be decoded
+    pub invalid_addr: u64,
+    /// Client fragments dropped because fragmentation is not supported
+    pub fragmented: u64,
+    /// Client datagrams dropped because their destination was denied
+    pub not_allowed: u64,
+    /// Client datagrams dropped because their domain did not resolve
+    pub unresolved: u64,
+    /// Datagrams, in either direction, that could not be sent
+    pub send_failed: u64,
+}
+
+impl UdpStats {
+    /// Datagrams dropped for any reason
+    pub fn dropped(&self) -> u64 {
+        self.invalid_rsv
+            + self.invalid_addr_type
+            + self.truncated
+            + self.invalid_addr
+            + self.fragmented
+            + self.not_allowed
+            + self.unresolved
+            + self.send_failed
+    }
+
+    fn record_drop(&mut self, reason: UdpDropReason) {
+        let counter = match reason {
+            UdpDropReason::InvalidRsv => &mut self.invalid_rsv,
+            UdpDropReason::InvalidAddressType => &mut self.invalid_addr_type,
+            UdpDropReason::Truncated => &mut self.truncated,
+            UdpDropReason::InvalidAddress => &mut self.invalid_addr,
+            UdpDropReason::Fragmented => &mut self.fragmented,
+            UdpDropReason::NotAllowed => &mut self.not_allowed,
+            UdpDropReason::Unresolved => &mut self.unresolved,
+            UdpDropReason::SendFailed => &mut self.send_failed,
+        };
+
+        *counter += 1;
+    }
+}
+
+/// Relays datagrams for a UDP ASSOCIATE until the control connection closes.
+///
+/// Datagrams coming from the client's IP are decapsulated and sent to their
+/// DST.ADDR/DST.PORT; every other datagram is treated as a response and is
+/// encapsulated back to the client.
+///
+/// The client UDP address is learned from the first datagram matching what
+/// the client declared in its request, `client_addr`, and every reply of
+/// the association goes there, which keeps working for clients behind NAT:
+///
+/// - an unspecified IP, such as in `0.0.0.0:0`, matches the IP of the
+///   control connection's peer, as does a domain
+/// - a zero port matches any port
+///
+/// A client declaring `0.0.0.0:0` is thus whatever source its first
+/// datagram comes from, as long as it shares the IP of the control
+/// connection.
+///
+/// The control connection carries nothing else. Any further request sent
+/// on it, such as another UDP ASSOCIATE, is answered with a `Failure` reply
+/// while this association keeps running. Bytes that are not a request are
+/// answered the same way and end the association with an
+/// `io::ErrorKind::InvalidData` error.
+///
+/// Datagrams are received in a single buffer of
+/// `Socks5Handler::udp_buffer_size` bytes, reused for the whole association,
+/// and anything beyond it is discarded. Fragmented client datagrams are
+/// reassembled when `Socks5Handler::supports_fragmentation` allows it, and
+/// dropped otherwise.
+///
+/// Each client datagram goes through the hooks screening a CONNECT:
+/// `allow_port`, then `resolve` for a domain, within `RESOLVE_TIMEOUT`, and
+/// `allow_resolved_ip`, and is dropped when they deny it. The last
+/// destination is remembered, so a client sending to one address resolves
+/// it once. A datagram that cannot be sent, or an ICMP error reported by
+/// the relay socket, drops the datagram without ending the association.
+pub async fn relay<H: Socks5Handler + Sync + ?Sized>(
+    handler: &H,
+    stream: &mut ClientStream<'_>,
+    udp_socket: &UdpSocket,
+    client_addr: &SocksAddr,
+) -> Result<(), io::Error> {
+    let buffer_size = handler.udp_buffer_size();
+    let supports_fragmentation = handler.supports_fragmentation();
+
+    let peer_ip = stream.peer_addr()?.ip();
+    let (expected_ip, expected_port) = match client_addr {
+        SocksAddr::IPV4(addr) if !addr.ip().is_unspecified() => {
+            (IpAddr::V4(*addr.ip()), addr.port())
+        }
+        SocksAddr::IPV6(addr) if !addr.ip().is_unspecified() => {
+            (IpAddr::V6(*addr.ip()), addr.port())
+        }
+        _ => (peer_ip, client_addr.port()),
+    };
+    let mut client_udp_addr = None;
+
+    let mut control_buf = [0; 1024];
+    // Requests are parsed whole, however the client's writes split them
+    let mut requests = Vec::new();
+    let mut buf = vec![0u8; buffer_size];
+    let mut packet = Vec::with_capacity(buffer_size);
+    let mut reassembly = Reassembly::default();
+    let mut stats = UdpStats::default();
+    let is_ipv6 = udp_socket.local_addr()?.is_ipv6();
+    let mut last_destination = None;
+
+    loop {
+        select! {
+            // The association terminates when the TCP connection that the
+            // UDP ASSOCIATE request arrived on terminates.
+            read = stream.read(&mut control_buf) => {
+                let size = read?;
+                if size == 0 {
+                    handler.on_udp_complete(client_addr, &stats);
+                    return Ok(());
+                }
+
+                // One association per control connection: any further
+                // request is refused while this one keeps running
+                requests.extend_from_slice(&control_buf[..size]);
+                loop {
+                    match parse_socks5_request(&requests) {
+                        Ok((_, _, length)) => {
+                            requests.drain(..length);
+                            refuse_request(stream).await?;
+                        }
+                        Err(SocksError::Incomplete) => break,
+                        Err(err) => {
+                            refuse_request(stream).await?;
+                            return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+                        }
+                    }
+                }
+            }
+            received = udp_socket.recv_from(&mut buf) => {
+                let (size, src_addr) = match received {
+                    Ok(val) => val,
+                    // ICMP errors for earlier datagrams, which the relay
+                    // socket reports on the next receive
+                    Err(err) if is_datagram_error(&err) => continue,
+                    Err(err) => return Err(err),
+                };
+
+                let is_from_client = match client_udp_addr {
+                    Some(addr) => addr == src_addr,
+                    None => {
+                        src_addr.ip() == expected_ip
+                            && (expected_port == 0 || src_addr.port() == expected_port)
+                    }
+                };
+
+                if is_from_client {
+                    client_udp_addr = Some(src_addr);
+
+                    let header = match parse_header(&buf[..size]) {
+                        Ok((frag, _, _)) if frag != 0 && !supports_fragmentation => {
+                            Err(UdpDropReason::Fragmented)
+                        }
+                        header => header,
+                    };
+                    let (frag, dest_addr, offset) = match header {
+                        Ok(header) => header,
+                        Err(reason) => {
+                            stats.record_drop(reason);
+                            handler.on_udp_drop(&src_addr, reason);
+                            continue;
+                        }
+                    };
+
+                    let data = &buf[offset..size];
+                    let (dest_addr, data) = if frag == 0 {
+                        // A standalone datagram abandons any reassembly, and
+                        // is sent straight from the receive buffer
+                        reassembly.reset();
+                        (dest_addr, Cow::Borrowed(data))
+                    } else {
+                        match reassembly.push(frag, dest_addr, data) {
+                            Some((dest_addr, data)) => (dest_addr, Cow::Owned(data)),
+                            None => continue,
+                        }
+                    };
+
+                    let sent = match destination(handler, &dest_addr, is_ipv6, &mut last_destination).await {
+                        Ok(addr) => udp_socket
+                            .send_to(&data, addr)
+                            .await
+                            .map_err(|_| UdpDropReason::SendFailed),
+                        Err(reason) => Err(reason),
+                    };
+                    if let Err(reason) = sent {
+                        stats.record_drop(reason);
+                        handler.on_udp_drop(&src_addr, reason);
+                    }
+                } else if let Some(addr) = client_udp_addr {
+                    encode_header(&mut packet, src_addr, &buf[..size]);
+                    if udp_socket.send_to(&packet, addr).await.is_err() {
+                        stats.record_drop(UdpDropReason::SendFailed);
+                        handler.on_udp_drop(&src_addr, UdpDropReason::SendFailed);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Answers a request sent on the control connection of a running
+/// association with a `Failure` reply
+async fn refuse_request(stream: &mut ClientStream<'_>) -> io::Result<()> {
+    let local_addr = stream.local_addr()?;
+    Socks5Reply::Failure.reply(stream, local_addr).await
+}
+
+/// Each UDP datagram carries a UDP request header with it:
+///
+/// ```text
+///    +----+------+------+----------+----------+----------+
+///    |RSV | FRAG | ATYP | DST.ADDR | DST.PORT |   DATA   |
+///    +----+------+------+----------+----------+----------+
+///    | 2  |  1   |  1   | Variable |    2     | Variable |
+///    +----+------+------+----------+----------+----------+
+/// ```
+///
+/// `addr` is DST.ADDR/DST.PORT in a datagram a client sends, and the
+/// address the datagram came from in one sent back to it. Meant for
+/// running a UDP relay without the default `associate`.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Socks5UdpHeader {
+    pub frag: u8,
+    pub addr: SocksAddr,
+}
+
+impl Socks5UdpHeader {
+    pub fn new(addr: SocksAddr) -> Self {
+        Self { frag: 0, addr }
+    }
+
+    /// Parses the header at the start of a datagram, returning it with the
+    /// offset of DATA. A nonzero RSV yields `SocksError::InvalidReserved`,
+    /// and a datagram too short for its header `SocksError::Incomplete`.
+    pub fn parse(buf: &[u8]) -> Result<(Self, usize), SocksError> {
+        if buf.len() >= 2 && buf[..2] != [0x00, 0x00] {
+            return Err(SocksError::InvalidReserved(u16::from_be_bytes([
+                buf[0], buf[1],
+            ])));
+        }
+        let header = buf.get(..4).ok_or(SocksError::Incomplete)?;
+
+        let addr_type: Socks5AddrType = header[3].try_into()?;
+        let (addr, length) = SocksAddr::from_bytes(&addr_type, &buf[4..])?;
+
+        Ok((
+            Self {
+                frag: header[2],
+                addr,
+            },
+            4 + length,
+        ))
+    }
+
+    /// Encodes the header followed by `data`, the inverse of `parse`. The
+    /// ATYP is chosen from the `SocksAddr` variant. Domains longer than 255
+    /// bytes yield `SocksError::DomainTooLong`.
+    pub fn encode(&self, data: &[u8]) -> Result<Vec<u8>, SocksError> {
+        let mut buf = vec![0x00, 0x00, self.frag, self.addr.addr_type().into()];
+        buf.extend(self.addr.to_bytes()?);
+        buf.extend(data);
+
+        Ok(buf)
+    }
+}
+
+/// Returns FRAG, the destination and the offset of DATA, or why the
+/// datagram is malformed.
+fn parse_header(buf: &[u8]) -> Result<(u8, SocksAddr, usize), UdpDropReason> {
+    let (header, offset) = Socks5UdpHeader::parse(buf).map_err(|err| match err {
+        SocksError::InvalidReserved(_) => UdpDropReason::InvalidRsv,
+        SocksError::Incomplete => UdpDropReason::Truncated,
+        SocksError::InvalidAddressType(_) => UdpDropReason::InvalidAddressType,
+        _ => UdpDropReason::InvalidAddress,
+    })?;
+
+    Ok((header.frag, header.addr, offset))
+}
+
+/// Address a client datagram to `dest_addr` is sent to, screened by the
+/// hooks of a CONNECT, preferring the family of the relay socket. `last`
+/// holds the last destination and its address, which skips the lookup and
+/// the hooks for datagrams to the same destination.
+async fn destination<H: Socks5Handler + Sync + ?Sized>(
+    handler: &H,
+    dest_addr: &SocksAddr,
+    is_ipv6: bool,
+    last: &mut Option<(SocksAddr, SocketAddr)>,
+) -> Result<SocketAddr, UdpDropReason> {
+    if let Some((last_addr, addr)) = last {
+        if last_addr == dest_addr {
+            return Ok(*addr);
+        }
+    }
+
+    // Hook errors deny the datagram, they are not kept across awaits
+    let allowed = matches!(handler.allow_port(dest_addr.port()).await, Ok(true));
+    if !allowed {
+        return Err(UdpDropReason::NotAllowed);
+    }
+
+    let addrs = match dest_addr {
+        SocksAddr::IPV4(addr) => vec![SocketAddr::V4(*addr)],
+        SocksAddr::IPV6(addr) => vec![SocketAddr::V6(*addr)],
+        SocksAddr::Domain(..) => time::timeout(RESOLVE_TIMEOUT, handler.resolve(dest_addr))
+            .await
+            .ok()
+            .and_then(Result::ok)
+            .filter(|addrs| !addrs.is_empty())
+            .ok_or(UdpDropReason::Unresolved)?,
+    };
+
+    let mut allowed_addrs = Vec::with_capacity(addrs.len());
+    for addr in addrs {
+        let allowed = matches!(handler.allow_resolved_ip(&addr.ip()).await, Ok(true));
+        if allowed {
+            allowed_addrs.push(addr);
+        }
+    }
+    let addr = allowed_addrs
+        .iter()
+        .find(|addr| addr.is_ipv6() == is_ipv6)
+        .or(allowed_addrs.first())
+        .copied()
+        .ok_or(UdpDropReason::NotAllowed)?;
+
+    *last = Some((dest_addr.clone(), addr));
+    Ok(addr)
+}
+
+/// Whether a receive error on the relay socket is about a single datagram,
+/// such as an ICMP port unreachable for an earlier one, rather than the
+/// socket itself
+fn is_datagram_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+    )
+}
+
+/// Reassembly queue of RFC 1928. FRAG holds the position of a fragment
+/// between 1 and 127, with the high-order bit set on the last one.
+/// Fragments must arrive in increasing positions within
+/// `REASSEMBLY_TIMEOUT`, otherwise the queue starts over.
+#[derive(Default)]
+struct Reassembly {
+    dest_addr: Option<SocksAddr>,
+    position: u8,
+    data: Vec<u8>,
+    started: Option<Instant>,
+}
+
+impl Reassembly {
+    fn reset(&mut self) {
+        self.dest_addr = None;
+        self.position = 0;
+        self.data.clear();
+        self.started = None;
+    }
+
+    /// Queues a fragment, returning the destination and the whole datagram
+    /// once the last fragment arrived
+    fn push(
+        &mut self,
+        frag: u8,
+        dest_addr: SocksAddr,
+        data: &[u8],
+    ) -> Option<(SocksAddr, Vec<u8>)> {
+        let position = frag & 0x7f;
+        if position == 0 {
+            return None;
+        }
+
+        let is_expired = self
+            .started
+            .is_some_and(|started| started.elapsed() > REASSEMBLY_TIMEOUT);
+        if position <= self.position || is_expired {
+            self.reset();
+        }
+
+        let dest_addr = self.dest_addr.get_or_insert(dest_addr).clone();
+        self.started.get_or_insert_with(Instant::now);
+        self.position = position;
+        self.data.extend(data);
+
+        if frag & 0x80 == 0 {
+            return None;
+        }
+
+        let data = std::mem::take(&mut self.data);
+        self.reset();
+
+        Some((dest_addr, data))
+    }
+}
+
+/// Encapsulates `data` from `src_addr` into `packet`, replacing its content
+/// without allocating once `packet` grew to the largest datagram
+fn encode_header(packet: &mut Vec<u8>, src_addr: SocketAddr, data: &[u8]) {
+    packet.clear();
+    packet.extend([0x00, 0x00, 0x00]);
+    match src_addr.ip() {
+        IpAddr::V4(ip) => {
+            packet.push(Socks5AddrType::IPV4.into());
+            packet.extend(ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            packet.push(Socks5AddrType::IPV6.into());
+            packet.extend(ip.octets());
+        }
+    }
+    packet.extend(src_addr.port().to_be_bytes());
+    packet.extend(data);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use async_trait::async_trait;
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::{addr::write_socks_addr, socks5::Socks5, test_util::tcp_pair};
+
+    /// Relays UDP, denying port 9 and failing every lookup
+    #[derive(Default)]
+    struct UdpHandler {
+        drops: Mutex<Vec<UdpDropReason>>,
+        stats: Mutex<Option<UdpStats>>,
+    }
+
+    #[async_trait]
+    impl Socks5Handler for UdpHandler {
+        type Error = SocksError;
+
+        async fn allow_port(&self, port: u16) -> Result<bool, Self::Error> {
+            Ok(port != 9)
+        }
+
+        async fn resolve(&self, _dest_addr: &SocksAddr) -> Result<Vec<SocketAddr>, Self::Error> {
+            Err(io::Error::from(io::ErrorKind::HostUnreachable).into())
+        }
+
+        fn udp_enabled(&self) -> bool {
+            true
+        }
+
+        fn on_udp_drop(&self, _src_addr: &SocketAddr, reason: UdpDropReason) {
+            self.drops.lock().unwrap().push(reason);
+        }
+
+        fn on_udp_complete(&self, _client_addr: &SocksAddr, stats: &UdpStats) {
+            *self.stats.lock().unwrap() = Some(*stats);
+        }
+    }
+
+    #[tokio::test]
+    async fn denied_and_unresolved_datagrams_are_dropped() {
+        let (mut client, mut server) = tcp_pair().await;
+        let handler = Arc::new(UdpHandler::default());
+        let mut socks5 = Socks5::<UdpHandler>::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            handler.clone(),
+        )
+        .with_strict(true);
+        let session = tokio::spawn(async move { socks5.negotiate(&mut server).await });
+
+        let client_udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let mut request = vec![0x05, 0x01, 0x00, 0x05, 0x03, 0x00];
+        write_socks_addr(&mut request, &client_udp.local_addr().unwrap().into())
+            .await
+            .unwrap();
+        client.write_all(&request).await.unwrap();
+        let mut reply = [0; 2 + 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..4], [0x05, 0x00, 0x05, 0x00]);
+        let relay_port = u16::from_be_bytes([reply[10], reply[11]]);
+        let relay_addr = SocketAddr::from(([127, 0, 0, 1], relay_port));
+
+        let denied = SocksAddr::from(SocketAddr::from(([127, 0, 0, 1], 9)));
+        let unresolved = SocksAddr::Domain("example.invalid".to_string(), 53);
+        let allowed = SocksAddr::from(target.local_addr().unwrap());
+        for dest_addr in [denied, unresolved, allowed] {
+            let datagram = Socks5UdpHeader::new(dest_addr).encode(b"ping").unwrap();
+            client_udp.send_to(&datagram, relay_addr).await.unwrap();
+        }
+
+        // Still relaying after the drops
+        let mut buf = [0; 16];
+        let size = target.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..size], b"ping");
+
+        drop(client);
+        session.await.unwrap().unwrap();
+        assert_eq!(
+            *handler.drops.lock().unwrap(),
+            [UdpDropReason::NotAllowed, UdpDropReason::Unresolved]
+        );
+        let stats = handler.stats.lock().unwrap().unwrap();
+        assert_eq!((stats.not_allowed, stats.unresolved), (1, 1));
+    }
+
+    #[test]
+    fn icmp_errors_do_not_end_the_association() {
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+        let other = io::Error::from(io::ErrorKind::InvalidInput);
+
+        assert!(is_datagram_error(&refused));
+        assert!(is_datagram_error(&reset));
+        assert!(!is_datagram_error(&other));
+    }
+
+    #[tokio::test]
+    async fn requests_on_the_control_connection_are_refused() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            UdpHandler::default(),
+        )
+        .with_strict(true);
+        let session = tokio::spawn(async move { socks5.negotiate(&mut server).await });
+
+        let associate = [0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        client.write_all(&associate).await.unwrap();
+        let mut reply = [0; 2 + 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..4], [0x05, 0x00, 0x05, 0x00]);
+
+        // A second request, split across writes
+        client.write_all(&associate[..2]).await.unwrap();
+        client.flush().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        client.write_all(&associate[2..]).await.unwrap();
+        let mut reply = [0; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [0x05, 0x01]);
+
+        // Anything else is refused too, and ends the association
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [0x05, 0x01]);
+        assert!(session.await.unwrap().is_err());
+    }
+
+    #[test]
+    fn encoded_headers_parse_back() {
+        let headers = [
+            Socks5UdpHeader::new(SocketAddr::from(([192, 0, 2, 1], 53)).into()),
+            Socks5UdpHeader {
+                frag: 2,
+                addr: SocksAddr::Domain("example.com".to_string(), 53),
+            },
+            Socks5UdpHeader::new(SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], 53)).into()),
+        ];
+
+        for header in headers {
+            let datagram = header.encode(b"data").unwrap();
+
+            let (parsed, offset) = Socks5UdpHeader::parse(&datagram).unwrap();
+            assert_eq!(parsed, header);
+            assert_eq!(&datagram[offset..], b"data");
+        }
+    }
+
+    #[test]
+    fn nonzero_rsv_is_invalid() {
+        let datagram = [0x00, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0, 53];
+
+        assert!(matches!(
+            Socks5UdpHeader::parse(&datagram),
+            Err(SocksError::InvalidReserved(0x0001))
+        ));
+    }
+
+    fn addr(port: u16) -> SocksAddr {
+        SocketAddr::from(([192, 0, 2, 1], port)).into()
+    }
+
+    #[test]
+    fn fragments_are_joined_at_the_end_marker() {
+        let mut reassembly = Reassembly::default();
+
+        assert_eq!(reassembly.push(1, addr(53), b"he"), None);
+        assert_eq!(reassembly.push(2, addr(53), b"ll"), None);
+        assert_eq!(
+            reassembly.push(0x83, addr(53), b"o"),
+            Some((addr(53), b"hello".to_vec()))
+        );
+        assert_eq!(
+            reassembly.push(0x81, addr(53), b"again"),
+            Some((addr(53), b"again".to_vec()))
+        );
+    }
+
+    #[test]
+    fn fragment_out_of_order_restarts_the_queue() {
+        let mut reassembly = Reassembly::default();
+
+        assert_eq!(reassembly.push(1, addr(53), b"stale"), None);
+        assert_eq!(reassembly.push(2, addr(53), b"stale"), None);
+        // Position 2 again starts a new datagram, to its own destination
+        assert_eq!(reassembly.push(2, addr(54), b"fre"), None);
+        assert_eq!(
+            reassembly.push(0x83, addr(53), b"sh"),
+            Some((addr(54), b"fresh".to_vec()))
+        );
+    }
+
+    #[test]
+    fn fragments_expire_after_the_reassembly_timeout() {
+        let mut reassembly = Reassembly::default();
+
+        assert_eq!(reassembly.push(1, addr(53), b"stale"), None);
+        reassembly.started =
+            Instant::now().checked_sub(REASSEMBLY_TIMEOUT + Duration::from_secs(1));
+        assert_eq!(reassembly.push(2, addr(53), b"fre"), None);
+        assert_eq!(
+            reassembly.push(0x83, addr(53), b"sh"),
+            Some((addr(53), b"fresh".to_vec()))
+        );
+    }
+
+    #[tokio::test]
+    async fn fragments_are_dropped_without_fragmentation_support() {
+        let (mut client, mut server) = tcp_pair().await;
+        let handler = Arc::new(UdpHandler::default());
+        let mut socks5 = Socks5::<UdpHandler>::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            handler.clone(),
+        )
+        .with_strict(true);
+        let session = tokio::spawn(async move { socks5.negotiate(&mut server).await });
+
+        let client_udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let mut request = vec![0x05, 0x01, 0x00, 0x05, 0x03, 0x00];
+        write_socks_addr(&mut request, &client_udp.local_addr().unwrap().into())
+            .await
+            .unwrap();
+        client.write_all(&request).await.unwrap();
+        let mut reply = [0; 2 + 10];
+        client.read_exact(&mut reply).await.unwrap();
+        let relay_port = u16::from_be_bytes([reply[10], reply[11]]);
+        let relay_addr = SocketAddr::from(([127, 0, 0, 1], relay_port));
+
+        let dest_addr = SocksAddr::from(target.local_addr().unwrap());
+        for (frag, data) in [(0x81, b"frag"), (0x00, b"ping")] {
+            let header = Socks5UdpHeader {
+                frag,
+                addr: dest_addr.clone(),
+            };
+            let datagram = header.encode(data).unwrap();
+            client_udp.send_to(&datagram, relay_addr).await.unwrap();
+        }
+
+        let mut buf = [0; 16];
+        let size = target.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..size], b"ping");
+
+        drop(client);
+        session.await.unwrap().unwrap();
+        assert_eq!(*handler.drops.lock().unwrap(), [UdpDropReason::Fragmented]);
+    }
+
+    #[tokio::test]
+    async fn responses_go_to_the_learned_client_address() {
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            UdpHandler::default(),
+        )
+        .with_strict(true);
+        let session = tokio::spawn(async move { socks5.negotiate(&mut server).await });
+
+        // The client does not know its UDP address yet
+        let associate = [0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        client.write_all(&associate).await.unwrap();
+        let mut reply = [0; 2 + 10];
+        client.read_exact(&mut reply).await.unwrap();
+        let relay_port = u16::from_be_bytes([reply[10], reply[11]]);
+        let relay_addr = SocketAddr::from(([127, 0, 0, 1], relay_port));
+
+        let client_udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+
+        let datagram = Socks5UdpHeader::new(target_addr.into())
+            .encode(b"ping")
+            .unwrap();
+        client_udp.send_to(&datagram, relay_addr).await.unwrap();
+        let mut buf = [0; 64];
+        let (size, src_addr) = target.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..size], b"ping");
+
+        target.send_to(b"pong", src_addr).await.unwrap();
+        let size = client_udp.recv(&mut buf).await.unwrap();
+        let (header, offset) = Socks5UdpHeader::parse(&buf[..size]).unwrap();
+        assert_eq!(header, Socks5UdpHeader::new(target_addr.into()));
+        assert_eq!(&buf[offset..size], b"pong");
+
+        drop(client);
+        session.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,133 @@
+use std::net::SocketAddr;
+
+use async_trait::async_trait;
+use tokio::net::TcpStream;
+
+use crate::{
+    addr::SocksAddr,
+    error::SocksError,
+    relay::relay,
+    socks4::{command::Socks4Command, reply::Socks4Reply, Socks4Handler},
+    stream::ClientStream,
+};
+
+use super::{
+    client,
+    command::{CommandDecision, Socks5Command},
+    reply::Socks5Reply,
+    reply_addr, Socks5Handler,
+};
+
+/// Handler that chains to an upstream SOCKS5 server: CONNECT requests, from
+/// SOCKS4 or SOCKS5 clients, are dialed through the upstream rather than
+/// directly, which makes the server one link of a proxy chain.
+///
+/// BIND and UDP ASSOCIATE cannot be chained and are refused.
+///
+/// It can serve clients on its own, with `Socks::from_stream`, or be
+/// composed into another handler, whose `connect` delegates to
+/// `Socks5Handler::connect` of an `UpstreamSocks5` it holds, for example
+/// after checking the destination against its own policy. Its errors are
+/// `SocksError`, which every handler error converts from. A failure reply of
+/// the upstream is passed on to the client.
+#[derive(Clone, Debug)]
+pub struct UpstreamSocks5 {
+    upstream_addr: SocketAddr,
+    credentials: Option<(String, String)>,
+}
+
+impl UpstreamSocks5 {
+    pub fn new(upstream_addr: SocketAddr) -> Self {
+        Self {
+            upstream_addr,
+            credentials: None,
+        }
+    }
+
+    /// Authenticate to the upstream with USERNAME/PASSWORD
+    pub fn with_credentials(mut self, username: &str, password: &str) -> Self {
+        self.credentials = Some((username.to_string(), password.to_string()));
+        self
+    }
+
+    /// Dials `dest_addr` through the upstream, returning the connection to
+    /// the upstream, which carries the tunnel, and the address the upstream
+    /// bound for it.
+    pub async fn dial(&self, dest_addr: &SocksAddr) -> Result<(TcpStream, SocketAddr), SocksError> {
+        let mut upstream = TcpStream::connect(self.upstream_addr).await?;
+
+        let credentials = self
+            .credentials
+            .as_ref()
+            .map(|(username, password)| (username.as_str(), password.as_str()));
+        let bind_addr = client::connect(&mut upstream, dest_addr, credentials).await?;
+
+        // A domain BND.ADDR cannot be written in replies, report the local
+        // end of the connection to the upstream instead
+        let bind_addr = match bind_addr {
+            SocksAddr::IPV4(addr) => SocketAddr::V4(addr),
+            SocksAddr::IPV6(addr) => SocketAddr::V6(addr),
+            SocksAddr::Domain(_, _) => upstream.local_addr()?,
+        };
+
+        Ok((upstream, bind_addr))
+    }
+}
+
+#[async_trait]
+impl Socks5Handler for UpstreamSocks5 {
+    type Error = SocksError;
+
+    async fn allow_command(&self, command: &Socks5Command) -> Result<CommandDecision, Self::Error> {
+        Ok((*command == Socks5Command::Connect).into())
+    }
+
+    async fn connect(
+        &self,
+        stream: &mut ClientStream<'_>,
+        dest_addr: &SocksAddr,
+    ) -> Result<(), Self::Error> {
+        let (mut upstream, bind_addr) = self.dial(dest_addr).await?;
+        Socks5Reply::Succeeded
+            .reply(stream, reply_addr(self, bind_addr))
+            .await?;
+        Socks5Handler::on_established(self, dest_addr, bind_addr);
+
+        let transfer = self.on_relay(stream, &mut upstream).await?;
+        Socks5Handler::on_transfer_complete(self, dest_addr, &transfer);
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Socks4Handler for UpstreamSocks5 {
+    type Error = SocksError;
+
+    async fn allow_command(&self, command: &Socks4Command) -> Result<bool, Self::Error> {
+        Ok(*command == Socks4Command::Connect)
+    }
+
+    async fn connect(
+        &self,
+        stream: &mut ClientStream<'_>,
+        dest_addr: &SocksAddr,
+    ) -> Result<(), Self::Error> {
+        let (mut upstream, bind_addr) = self.dial(dest_addr).await?;
+        Socks4Reply::Granted.reply(stream, bind_addr).await?;
+        Socks4Handler::on_established(self, dest_addr, bind_addr);
+
+        let transfer = relay(
+            stream,
+            &mut upstream,
+            Socks4Handler::half_close(self),
+            Socks4Handler::max_session_duration(self),
+            Socks4Handler::read_timeout(self),
+            Socks4Handler::write_timeout(self),
+        )
+        .await?;
+        Socks4Handler::on_transfer_complete(self, dest_addr, &transfer);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,470 @@
+use std::{
+    fmt,
+    ops::Deref,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{ready, Context, Poll},
+};
+
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+
+use crate::socks5::addr_type::Socks5AddrType;
+#[cfg(feature = "tap")]
+use crate::tap::Tap;
+
+/// Connection to the client, as the handlers serving a request get it.
+///
+/// Reads and writes go through to the `TcpStream`, which it derefs to for
+/// addresses and socket options, and the replies written through it are
+/// counted, so a handler failing after it answered the request does not
+/// get a second reply written after its own, whichever helper wrote it.
+/// Handshake frames held back by `Socks5Handler::coalesce_replies` go out
+/// ahead of the next bytes written to it, or when it is flushed. With the
+/// `tap` feature, the bytes read and written are copied into the tap of
+/// the session, if any.
+///
+/// `get_mut` reaches the `TcpStream` itself, such as for splice(2)
+/// relaying once the replies are sent, but writes through it are not
+/// counted, tapped, and may overtake held frames, which a flush sends
+/// first.
+///
+/// Sessions only wrap a `TcpStream`, the default of `S`. Other streams are
+/// for writing replies into memory, as the tests do, not for serving a
+/// session over TLS or another transport.
+pub struct ClientStream<'a, S = TcpStream> {
+    stream: &'a mut S,
+    counter: Option<ReplyCounter>,
+    /// Frames held back, written ahead of the next write
+    pending: Vec<u8>,
+    #[cfg(feature = "tap")]
+    tap: Option<Box<dyn Tap + Send + Sync>>,
+}
+
+impl<'a, S> ClientStream<'a, S> {
+    pub fn new(stream: &'a mut S) -> Self {
+        Self {
+            stream,
+            counter: None,
+            pending: Vec::new(),
+            #[cfg(feature = "tap")]
+            tap: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        self.stream
+    }
+
+    /// Starts counting the replies written from now on, until `expected`
+    /// of them went out, dropping the count of any previous request
+    pub(crate) fn count_replies(&mut self, framing: ReplyFraming, expected: u8) -> ReplyCount {
+        let count = ReplyCount::default();
+        self.counter = Some(ReplyCounter {
+            framing,
+            expected,
+            frame: Vec::new(),
+            count: count.clone(),
+        });
+
+        count
+    }
+
+    /// Whether a tap records the bytes going through the stream, which a
+    /// relay bypassing it with `get_mut` would miss
+    pub fn is_tapped(&self) -> bool {
+        #[cfg(feature = "tap")]
+        return self.tap.is_some();
+
+        #[cfg(not(feature = "tap"))]
+        false
+    }
+
+    #[cfg(feature = "tap")]
+    pub(crate) fn set_tap(&mut self, tap: Option<Box<dyn Tap + Send + Sync>>) {
+        self.tap = tap;
+    }
+
+    #[cfg(feature = "tap")]
+    pub(crate) fn take_tap(&mut self) -> Option<Box<dyn Tap + Send + Sync>> {
+        self.tap.take()
+    }
+
+    /// Holds `frame` back, to be sent in the same write as whatever is
+    /// written next
+    pub(crate) fn hold(&mut self, frame: &[u8]) {
+        self.pending.extend_from_slice(frame);
+    }
+
+    fn record_write(&mut self, data: &[u8]) {
+        if let Some(counter) = &mut self.counter {
+            if !counter.record(data) {
+                self.counter = None;
+            }
+        }
+    }
+}
+
+impl<S: fmt::Debug> fmt::Debug for ClientStream<'_, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientStream")
+            .field("stream", &self.stream)
+            .field("is_tapped", &self.is_tapped())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S> Deref for ClientStream<'_, S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        self.stream
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ClientStream<'_, S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+
+        let result = Pin::new(&mut *this.stream).poll_read(cx, buf);
+        #[cfg(feature = "tap")]
+        if let (Poll::Ready(Ok(())), Some(tap)) = (&result, &mut this.tap) {
+            tap.on_read(&buf.filled()[filled..]);
+        }
+        #[cfg(not(feature = "tap"))]
+        let _ = filled;
+
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> ClientStream<'_, S> {
+    /// Writes the held frames, with `buf` after them in the same write as
+    /// far as the stream takes it, returning how much of `buf` was written
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        while !self.pending.is_empty() {
+            let held = self.pending.len();
+            self.pending.extend_from_slice(buf);
+            let result = Pin::new(&mut *self.stream).poll_write(cx, &self.pending);
+            self.pending.truncate(held);
+            if let Poll::Ready(Ok(size)) = result {
+                self.tap_write(size, buf);
+            }
+
+            let size = match result {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Ok(0)),
+                Poll::Ready(Ok(size)) => size,
+                poll => return poll,
+            };
+            if size < held {
+                self.pending.drain(..size);
+                continue;
+            }
+
+            self.pending.clear();
+            if size > held {
+                return Poll::Ready(Ok(size - held));
+            }
+            // All held frames went out, but nothing of `buf` yet
+        }
+
+        let result = Pin::new(&mut *self.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(size)) = result {
+            self.tap_write(size, buf);
+        }
+
+        result
+    }
+
+    /// Copies into the tap the first `size` bytes of the held frames
+    /// followed by `buf`, which went out in a write
+    #[allow(unused_variables)]
+    fn tap_write(&mut self, size: usize, buf: &[u8]) {
+        #[cfg(feature = "tap")]
+        if let Some(tap) = &mut self.tap {
+            let held = size.min(self.pending.len());
+            for data in [&self.pending[..held], &buf[..size - held]] {
+                if !data.is_empty() {
+                    tap.on_write(data);
+                }
+            }
+        }
+    }
+
+    /// Writes the held frames on their own
+    fn poll_drain_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let size = ready!(Pin::new(&mut *self.stream).poll_write(cx, &self.pending))?;
+            if size == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.tap_write(size, &[]);
+            self.pending.drain(..size);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ClientStream<'_, S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        let result = this.poll_write_pending(cx, buf);
+        if let Poll::Ready(Ok(size)) = result {
+            this.record_write(&buf[..size]);
+        }
+
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain_pending(cx))?;
+
+        Pin::new(&mut *this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        // Held frames go out before the FIN
+        ready!(this.poll_drain_pending(cx))?;
+
+        Pin::new(&mut *this.stream).poll_shutdown(cx)
+    }
+}
+
+/// How replies are delimited in the bytes written to a client
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum ReplyFraming {
+    /// 8 bytes: VN, CD, DSTPORT and DSTIP
+    Socks4,
+    /// VER, REP, RSV and ATYP, then BND.ADDR and BND.PORT
+    Socks5,
+}
+
+impl ReplyFraming {
+    /// Length of the reply starting with `frame`, as far as its bytes tell,
+    /// or `None` if they are not a reply
+    fn frame_len(&self, frame: &[u8]) -> Option<usize> {
+        match self {
+            Self::Socks4 => Some(8),
+            Self::Socks5 if frame.len() < 4 => Some(4),
+            Self::Socks5 => {
+                let addr_type = Socks5AddrType::try_from(frame[3]).ok()?;
+                Some(addr_type.reply_len(frame.get(4).copied()))
+            }
+        }
+    }
+}
+
+/// Replies counted for a request
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Replies {
+    pub(crate) sent: u8,
+    /// Status octet, REP or CD, of the first reply
+    pub(crate) first: Option<u8>,
+    /// Status octet of the last reply
+    pub(crate) last: Option<u8>,
+}
+
+/// Replies counted on a `ClientStream`, readable while the handler holds
+/// the stream
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ReplyCount(Arc<Mutex<Replies>>);
+
+impl ReplyCount {
+    pub(crate) fn get(&self) -> Replies {
+        *self.lock()
+    }
+
+    pub(crate) fn set(&self, replies: Replies) {
+        *self.lock() = replies;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Replies> {
+        // The count stays consistent even if a holder of the lock panicked
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[derive(Debug)]
+struct ReplyCounter {
+    framing: ReplyFraming,
+    expected: u8,
+    /// Bytes of the reply being written
+    frame: Vec<u8>,
+    count: ReplyCount,
+}
+
+impl ReplyCounter {
+    /// Counts the replies completed by `data`, returning whether further
+    /// writes may still hold replies
+    fn record(&mut self, mut data: &[u8]) -> bool {
+        let mut replies = self.count.lock();
+
+        while !data.is_empty() {
+            if replies.sent >= self.expected {
+                return false;
+            }
+            let Some(len) = self.framing.frame_len(&self.frame) else {
+                return false;
+            };
+
+            let size = (len - self.frame.len()).min(data.len());
+            self.frame.extend_from_slice(&data[..size]);
+            data = &data[size..];
+
+            // The header may tell the frame is longer than known so far
+            if self.framing.frame_len(&self.frame) == Some(self.frame.len()) {
+                let status = self.frame[1];
+                replies.sent = replies.sent.saturating_add(1);
+                replies.first = replies.first.or(Some(status));
+                replies.last = Some(status);
+                self.frame.clear();
+            }
+        }
+
+        replies.sent < self.expected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::AsyncWriteExt;
+
+    /// Writer recording each write, taking at most `limit` bytes of it
+    struct Writes {
+        writes: Vec<Vec<u8>>,
+        limit: usize,
+    }
+
+    impl Writes {
+        fn new(limit: usize) -> Self {
+            Self {
+                writes: Vec::new(),
+                limit,
+            }
+        }
+    }
+
+    impl AsyncWrite for Writes {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            let size = buf.len().min(this.limit);
+            this.writes.push(buf[..size].to_vec());
+            Poll::Ready(Ok(size))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    const REPLY: [u8; 10] = [0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0, 80];
+
+    #[tokio::test]
+    async fn counts_replies_split_across_writes() {
+        let mut buf = Vec::new();
+        let mut stream = ClientStream::new(&mut buf);
+        let count = stream.count_replies(ReplyFraming::Socks5, 2);
+
+        let reply = [0x05, 0x00, 0x00, 0x03, 0x01, b'a', 0x00, 0x50];
+        stream.write_all(&reply[..3]).await.unwrap();
+        assert_eq!(count.get().sent, 0);
+        stream.write_all(&reply[3..]).await.unwrap();
+        assert_eq!(count.get().sent, 1);
+
+        let reply = [0x05, 0x04, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+        stream.write_all(&reply).await.unwrap();
+        let replies = count.get();
+        assert_eq!(replies.sent, 2);
+        assert_eq!(replies.first, Some(0x00));
+        assert_eq!(replies.last, Some(0x04));
+    }
+
+    #[tokio::test]
+    async fn stops_counting_after_the_expected_replies() {
+        let mut buf = Vec::new();
+        let mut stream = ClientStream::new(&mut buf);
+        let count = stream.count_replies(ReplyFraming::Socks5, 1);
+
+        let reply = [0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+        stream.write_all(&reply).await.unwrap();
+        // Relayed data that happens to look like a reply
+        let data = [0x05, 0x01, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+        stream.write_all(&data).await.unwrap();
+
+        let replies = count.get();
+        assert_eq!(replies.sent, 1);
+        assert_eq!(replies.last, Some(0x00));
+    }
+
+    #[tokio::test]
+    async fn held_frames_go_out_in_the_next_write() {
+        let mut writes = Writes::new(usize::MAX);
+        let mut stream = ClientStream::new(&mut writes);
+
+        stream.hold(&[0x05, 0x02]);
+        stream.hold(&[0x01, 0x00]);
+        stream.write_all(&REPLY).await.unwrap();
+
+        let mut expected = vec![0x05, 0x02, 0x01, 0x00];
+        expected.extend(REPLY);
+        assert_eq!(writes.writes, [expected]);
+    }
+
+    #[tokio::test]
+    async fn held_frames_survive_short_writes() {
+        let mut writes = Writes::new(3);
+        let mut stream = ClientStream::new(&mut writes);
+        let count = stream.count_replies(ReplyFraming::Socks5, 1);
+
+        stream.hold(&[0x05, 0x00]);
+        stream.write_all(&REPLY).await.unwrap();
+
+        let mut expected = vec![0x05, 0x00];
+        expected.extend(REPLY);
+        assert_eq!(writes.writes.concat(), expected);
+        // Only the bytes of the reply are counted, not the held frame
+        assert_eq!(count.get().sent, 1);
+    }
+
+    #[tokio::test]
+    async fn flush_writes_held_frames() {
+        let mut writes = Writes::new(usize::MAX);
+        let mut stream = ClientStream::new(&mut writes);
+
+        stream.hold(&[0x05, 0x00]);
+        stream.flush().await.unwrap();
+
+        assert_eq!(writes.writes, [vec![0x05, 0x00]]);
+    }
+}
@@ -0,0 +1,10 @@
+/// Sink the bytes a client exchanges with the server are copied into, such
+/// as a session recording to replay an interop bug from, see
+/// `Socks::with_tap`.
+pub trait Tap {
+    /// Bytes read from the client, empty once it reached EOF
+    fn on_read(&mut self, data: &[u8]);
+
+    /// Bytes written to the client
+    fn on_write(&mut self, data: &[u8]);
+}
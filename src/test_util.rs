@@ -0,0 +1,34 @@
+use std::{net::SocketAddr, time::Duration};
+
+use tokio::{
+    net::{TcpListener, TcpSocket, TcpStream},
+    time,
+};
+
+/// Both ends of a loopback TCP connection, the client first
+pub(crate) async fn tcp_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+
+    (client, server)
+}
+
+/// Listener whose accept queue is full, so dialing it hangs until the
+/// dialer gives up, and the connections filling the queue
+pub(crate) async fn unresponsive_listener() -> (TcpListener, Vec<TcpStream>) {
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+    let listener = socket.listen(1).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let mut streams = Vec::new();
+    while let Ok(stream) = time::timeout(Duration::from_millis(100), TcpStream::connect(addr)).await
+    {
+        streams.push(stream.unwrap());
+    }
+
+    (listener, streams)
+}
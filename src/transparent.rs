@@ -0,0 +1,134 @@
+use std::{
+    mem,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    os::fd::AsRawFd,
+};
+
+use tokio::{
+    io::{self, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+
+use crate::{
+    addr::SocksAddr,
+    dial::dial_from,
+    error::{SocksError, TimeoutPhase},
+    socks5::Socks5Handler,
+    stream::ClientStream,
+};
+
+/// Destination a connection was headed to before an iptables `REDIRECT`
+/// (or `DNAT`) rule sent it to this server, read with `SO_ORIGINAL_DST`.
+///
+/// Only redirected connections carry one. For any other connection, such
+/// as a regular SOCKS client, the server address itself is returned, or an
+/// error when the kernel does not track the connection.
+pub fn original_dst(stream: &TcpStream) -> io::Result<SocketAddr> {
+    let is_ipv4 = match stream.local_addr()? {
+        SocketAddr::V4(_) => true,
+        SocketAddr::V6(addr) => addr.ip().to_ipv4_mapped().is_some(),
+    };
+
+    if is_ipv4 {
+        let addr: libc::sockaddr_in = getsockopt(stream, libc::SOL_IP, libc::SO_ORIGINAL_DST)?;
+        let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+
+        Ok(SocketAddrV4::new(ip, u16::from_be(addr.sin_port)).into())
+    } else {
+        let addr: libc::sockaddr_in6 =
+            getsockopt(stream, libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST)?;
+        let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+
+        Ok(SocketAddrV6::new(
+            ip,
+            u16::from_be(addr.sin6_port),
+            addr.sin6_flowinfo,
+            addr.sin6_scope_id,
+        )
+        .into())
+    }
+}
+
+/// Serves a connection redirected to this server as a transparent proxy:
+/// no SOCKS negotiation takes place, the client is relayed as is to its
+/// `original_dst`.
+///
+/// The policy hooks of `handler` still apply. `allow_peer` screens the
+/// client, `allow_port` and `allow_resolved_ip` screen the destination,
+/// and a denial closes the connection since there is no reply to send. The
+/// destination is dialed from `outbound_bind_addr` within `connect_timeout`,
+/// passed to `wrap_remote`, and relayed with `on_relay` under a permit from
+/// `acquire_permit`, between `on_established` and `on_transfer_complete`.
+///
+/// Meant for connections that were redirected, not for SOCKS clients: a
+/// connection that was not redirected would be relayed to the server
+/// itself, so it fails with `SocksError::DestinationNotAllowed` instead.
+pub async fn serve_redirected<H>(handler: &H, stream: &mut TcpStream) -> Result<(), H::Error>
+where
+    H: Socks5Handler + Sync + ?Sized,
+{
+    let peer_addr = stream.peer_addr()?;
+    if !handler.allow_peer(&peer_addr).await? {
+        stream.shutdown().await?;
+        return Err(SocksError::PeerNotAllowed(peer_addr).into());
+    }
+
+    let original_dst = original_dst(stream)?;
+    let dest_addr = SocksAddr::from(original_dst);
+
+    if original_dst == stream.local_addr()?
+        || !handler.allow_port(original_dst.port()).await?
+        || !handler.allow_resolved_ip(&original_dst.ip()).await?
+    {
+        stream.shutdown().await?;
+        return Err(SocksError::DestinationNotAllowed(dest_addr).into());
+    }
+
+    let _permit = handler.acquire_permit().await?;
+
+    let addrs = [original_dst];
+    let attempt = dial_from(&addrs, handler.outbound_bind_addr(&dest_addr));
+    let remote = match handler.connect_timeout() {
+        Some(timeout) => {
+            time::timeout(timeout, attempt)
+                .await
+                .map_err(|_| SocksError::Timeout {
+                    phase: TimeoutPhase::Connect,
+                })??
+        }
+        None => attempt.await?,
+    };
+    let bind_addr = remote.local_addr()?;
+    let mut remote = handler.wrap_remote(remote, &dest_addr).await?;
+    handler.on_established(&dest_addr, bind_addr);
+
+    let transfer = handler
+        .on_relay(&mut ClientStream::new(stream), &mut remote)
+        .await?;
+    handler.on_transfer_complete(&dest_addr, &transfer);
+
+    Ok(())
+}
+
+fn getsockopt<T>(stream: &TcpStream, level: libc::c_int, name: libc::c_int) -> io::Result<T> {
+    let mut value = mem::MaybeUninit::<T>::zeroed();
+    let mut length = mem::size_of::<T>() as libc::socklen_t;
+
+    // The kernel writes at most `length` bytes into `value`
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            level,
+            name,
+            value.as_mut_ptr().cast(),
+            &mut length,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Both socket address structures are plain data, valid when zeroed
+    Ok(unsafe { value.assume_init() })
+}
@@ -0,0 +1,114 @@
+use std::path::Path;
+
+use crate::addr::SocksAddr;
+
+/// Path of the Unix socket a SOCKS5 CONNECT to `dest_addr` is routed to,
+/// see `Socks5Handler::unix_socket_prefix`.
+///
+/// A destination is a Unix socket when it is a domain starting with
+/// `prefix`, the path being what follows it: with the prefix `unix:`, a
+/// request for `unix:/run/app.sock` connects to `/run/app.sock`. The port
+/// of the request has no meaning for a Unix socket and is ignored. Since
+/// DST.ADDR holds at most 255 bytes, so does the domain, prefix included.
+/// Any other destination, and an empty path, is not a Unix socket.
+pub fn unix_socket_path<'a>(dest_addr: &'a SocksAddr, prefix: &str) -> Option<&'a Path> {
+    match dest_addr {
+        SocksAddr::Domain(domain, _) => domain
+            .strip_prefix(prefix)
+            .filter(|path| !path.is_empty())
+            .map(Path::new),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use async_trait::async_trait;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UnixListener,
+    };
+
+    use super::*;
+    use crate::{
+        error::SocksError,
+        socks5::{Socks5, Socks5Handler},
+        test_util::tcp_pair,
+    };
+
+    /// Routes domains starting with `unix:` to Unix sockets
+    struct UnixHandler;
+
+    #[async_trait]
+    impl Socks5Handler for UnixHandler {
+        type Error = SocksError;
+
+        fn unix_socket_prefix(&self) -> Option<&str> {
+            Some("unix:")
+        }
+    }
+
+    #[test]
+    fn prefixed_domains_are_unix_socket_paths() {
+        let addr = SocksAddr::Domain("unix:/run/app.sock".to_string(), 0);
+        assert_eq!(
+            unix_socket_path(&addr, "unix:"),
+            Some(Path::new("/run/app.sock"))
+        );
+
+        let addr = SocksAddr::Domain("example.com".to_string(), 80);
+        assert_eq!(unix_socket_path(&addr, "unix:"), None);
+
+        let addr = SocketAddr::from(([127, 0, 0, 1], 80)).into();
+        assert_eq!(unix_socket_path(&addr, "unix:"), None);
+    }
+
+    #[test]
+    fn empty_paths_are_not_unix_sockets() {
+        let addr = SocksAddr::Domain("unix:".to_string(), 0);
+        assert_eq!(unix_socket_path(&addr, "unix:"), None);
+    }
+
+    #[tokio::test]
+    async fn connect_reaches_a_unix_listener() {
+        let path = std::env::temp_dir().join(format!("rusocks-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let (mut client, mut server) = tcp_pair().await;
+        let mut socks5 = Socks5::new(
+            server.peer_addr().unwrap(),
+            server.local_addr().unwrap(),
+            UnixHandler,
+        )
+        .with_strict(true);
+        let session = tokio::spawn(async move { socks5.negotiate(&mut server).await });
+
+        let domain = format!("unix:{}", path.display());
+        let mut request = vec![0x05, 0x01, 0x00, 0x03, domain.len() as u8];
+        request.extend(domain.as_bytes());
+        request.extend(0u16.to_be_bytes());
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        client.write_all(&request).await.unwrap();
+
+        let mut reply = [0; 2 + 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(
+            reply,
+            [0x05, 0x00, 0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]
+        );
+
+        let (mut remote, _) = listener.accept().await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut data = [0; 4];
+        remote.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"ping");
+
+        drop(client);
+        drop(remote);
+        session.await.unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}